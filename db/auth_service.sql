@@ -0,0 +1,56 @@
+-- Registers the auth API as a service so its own management endpoints can be
+-- authorized through the same service-role-permission model as any other service.
+
+\set ON_ERROR_STOP on
+
+INSERT INTO auth.services (name, description)
+VALUES ('auth-api', 'Authentication and authorization API')
+ON CONFLICT (name) DO NOTHING;
+
+INSERT INTO auth.role (name)
+VALUES ('Admin')
+ON CONFLICT (name) DO NOTHING;
+
+INSERT INTO auth.permission (name)
+VALUES
+  ('users.read'),
+  ('users.write'),
+  ('users.impersonate'),
+  ('services.read'),
+  ('services.write'),
+  ('roles.read'),
+  ('roles.write'),
+  ('permissions.read'),
+  ('permissions.write'),
+  ('keys.read'),
+  ('keys.write')
+ON CONFLICT (name) DO NOTHING;
+
+INSERT INTO auth.service_roles (service_id, role_id)
+SELECT s.id, r.id
+FROM auth.services s
+JOIN auth.role r ON r.name = 'Admin'
+WHERE s.name = 'auth-api'
+ON CONFLICT (service_id, role_id) DO NOTHING;
+
+INSERT INTO auth.service_role_permission (service_role_id, permission_id)
+SELECT sr.id, p.id
+FROM auth.service_roles sr
+JOIN auth.services s ON s.id = sr.service_id
+JOIN auth.role r ON r.id = sr.role_id
+JOIN auth.permission p ON p.name IN (
+  'users.read',
+  'users.write',
+  'users.impersonate',
+  'services.read',
+  'services.write',
+  'roles.read',
+  'roles.write',
+  'permissions.read',
+  'permissions.write',
+  'keys.read',
+  'keys.write'
+)
+WHERE s.name = 'auth-api'
+  AND r.name = 'Admin'
+ON CONFLICT (service_role_id, permission_id) DO NOTHING;
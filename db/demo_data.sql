@@ -31,6 +31,7 @@ VALUES
 ON CONFLICT (name) DO NOTHING;
 
 -- People
+-- Passwords are Argon2id hashes of "<username>-hash" (e.g. adm1 / adm1-hash).
 INSERT INTO auth.person (
   username,
   password_hash,
@@ -40,12 +41,25 @@ INSERT INTO auth.person (
   document_number
 )
 VALUES
-  ('adm1', 'adm1-hash', 'Admin One', 'N', 'DNI', '00000001'),
-  ('usr1', 'usr1-hash', 'User One', 'N', 'DNI', '00000002'),
-  ('usr2', 'usr2-hash', 'User Two', 'N', 'DNI', '00000003'),
-  ('usr3', 'usr3-hash', 'User Three', 'N', 'DNI', '00000004'),
-  ('editor1', 'editor1-hash', 'Editor One', 'N', 'DNI', '00000005'),
-  ('viewer1', 'viewer1-hash', 'Viewer One', 'N', 'DNI', '00000006')
+  ('adm1', '$argon2id$v=19$m=19456,t=2,p=1$CojSvwI6bq5TMlujlqhZFQ$5M03Ifiki5nibYmT7s8t6hZhfvC0rp1Rsh34o+oPoes', 'Admin One', 'N', 'DNI', '00000001'),
+  ('usr1', '$argon2id$v=19$m=19456,t=2,p=1$4GqLQNmb5TpGehEqDmQ7wg$Ne4VLOxCqWVipwhxOw/4EhOhtF2dWAISRkqrtG9N0y4', 'User One', 'N', 'DNI', '00000002'),
+  ('usr2', '$argon2id$v=19$m=19456,t=2,p=1$ixDXTyP42aLHJ82jysGKrw$gK6t3Ym/aYjRkmJQ+R/K/fWyWSvbkVcIZ1TPFwdDkic', 'User Two', 'N', 'DNI', '00000003'),
+  ('usr3', '$argon2id$v=19$m=19456,t=2,p=1$sLGtJFlrloV9/25RfEkXbA$WLKdpr0IkyPINBuKwh9LA4CRm4qdFK6ee8swfkoaHcc', 'User Three', 'N', 'DNI', '00000004'),
+  ('editor1', '$argon2id$v=19$m=19456,t=2,p=1$ZsSBCKlrCGQ0548roMU6qA$vJzLBVK2fqgTrtrUg6J8xhzcolRX42ldY2B+pjnA2Z8', 'Editor One', 'N', 'DNI', '00000005'),
+  ('viewer1', '$argon2id$v=19$m=19456,t=2,p=1$OmwRuY2ARWt96vArHPJYmA$l6TCBersw9kp/ER600DgXGNgqxyvP690Hzn5XHirhIY', 'Viewer One', 'N', 'DNI', '00000006')
+ON CONFLICT (username) DO NOTHING;
+
+-- Account still holding a pre-hashing plaintext password; migrated on its first login.
+INSERT INTO auth.person (
+  username,
+  password_hash,
+  name,
+  person_type,
+  document_type,
+  document_number
+)
+VALUES
+  ('legacy1', 'legacy1-hash', 'Legacy One', 'N', 'DNI', '00000007')
 ON CONFLICT (username) DO NOTHING;
 
 -- Service roles
@@ -87,6 +101,7 @@ ON CONFLICT (service_role_id, permission_id) DO NOTHING;
 -- Person assignments to service roles
 WITH person_service_role_pairs (username, service_name, role_name) AS (
   VALUES
+    ('adm1', 'auth-api', 'Admin'),
     ('adm1', 'Service A', 'Admin'),
     ('usr1', 'Service A', 'User'),
     ('usr2', 'Service B', 'User'),
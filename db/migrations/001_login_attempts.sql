@@ -0,0 +1,18 @@
+-- Failed login counters used by the /auth/login lockout.
+
+\set ON_ERROR_STOP on
+
+CREATE TABLE IF NOT EXISTS auth.login_attempts (
+  subject TEXT PRIMARY KEY,
+  failures INTEGER NOT NULL DEFAULT 0,
+  last_failure_at BIGINT NOT NULL,
+  locked_until BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+DROP TRIGGER IF EXISTS trg_auth_login_attempts_audit ON auth.login_attempts;
+CREATE TRIGGER trg_auth_login_attempts_audit
+BEFORE INSERT OR UPDATE ON auth.login_attempts
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
@@ -0,0 +1,15 @@
+-- Replace raw bearer tokens in auth.tokens_cache with their SHA-256 digest.
+-- Live sessions keep working: the API hashes presented tokens before looking them up.
+-- Run once against an existing database, before deploying the matching API version:
+--   psql -U postgres -d auth_api -f db/migrations/002_hash_tokens_cache.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+ALTER TABLE auth.tokens_cache RENAME COLUMN token TO token_hash;
+
+UPDATE auth.tokens_cache
+SET token_hash = encode(sha256(convert_to(token_hash, 'UTF8')), 'hex');
+
+COMMIT;
@@ -0,0 +1,14 @@
+-- Table backing ACCESS_LOG_SINK=db.
+
+\set ON_ERROR_STOP on
+
+CREATE TABLE IF NOT EXISTS auth.access_log (
+  id BIGSERIAL PRIMARY KEY,
+  token_fingerprint TEXT NOT NULL,
+  user_id INTEGER,
+  endpoint TEXT NOT NULL,
+  ip TEXT NOT NULL,
+  ts BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_access_log_ts ON auth.access_log(ts);
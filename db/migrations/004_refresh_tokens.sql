@@ -0,0 +1,24 @@
+-- Refresh tokens with rotation and reuse detection.
+
+\set ON_ERROR_STOP on
+
+ALTER TABLE auth.tokens_cache ADD COLUMN IF NOT EXISTS family_id TEXT;
+CREATE INDEX IF NOT EXISTS idx_auth_tokens_family_id ON auth.tokens_cache(family_id);
+
+CREATE TABLE IF NOT EXISTS auth.refresh_tokens (
+  token_hash TEXT PRIMARY KEY,
+  family_id TEXT NOT NULL,
+  payload JSONB NOT NULL,
+  expires_at BIGINT NOT NULL,
+  used_at BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_refresh_tokens_family_id ON auth.refresh_tokens(family_id);
+
+DROP TRIGGER IF EXISTS trg_auth_refresh_tokens_audit ON auth.refresh_tokens;
+CREATE TRIGGER trg_auth_refresh_tokens_audit
+BEFORE INSERT OR UPDATE ON auth.refresh_tokens
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
@@ -0,0 +1,9 @@
+-- Absolute session lifetime (TOKEN_MAX_LIFETIME_SECONDS), measured from the original login.
+
+\set ON_ERROR_STOP on
+
+UPDATE auth.tokens_cache SET created_at = modified_at WHERE created_at IS NULL;
+
+ALTER TABLE auth.refresh_tokens ADD COLUMN IF NOT EXISTS session_started_at BIGINT;
+UPDATE auth.refresh_tokens SET session_started_at = created_at WHERE session_started_at IS NULL;
+ALTER TABLE auth.refresh_tokens ALTER COLUMN session_started_at SET NOT NULL;
@@ -0,0 +1,35 @@
+-- Session id, origin and last-seen time on tokens, for /auth/sessions.
+-- The refresh token family id becomes the public session id.
+
+\set ON_ERROR_STOP on
+
+DO $$
+BEGIN
+  IF EXISTS (
+    SELECT 1 FROM information_schema.columns
+    WHERE table_schema = 'auth' AND table_name = 'tokens_cache' AND column_name = 'family_id'
+  ) THEN
+    ALTER TABLE auth.tokens_cache RENAME COLUMN family_id TO session_id;
+    ALTER INDEX auth.idx_auth_tokens_family_id RENAME TO idx_auth_tokens_session_id;
+  END IF;
+  IF EXISTS (
+    SELECT 1 FROM information_schema.columns
+    WHERE table_schema = 'auth' AND table_name = 'refresh_tokens' AND column_name = 'family_id'
+  ) THEN
+    ALTER TABLE auth.refresh_tokens RENAME COLUMN family_id TO session_id;
+    ALTER INDEX auth.idx_auth_refresh_tokens_family_id RENAME TO idx_auth_refresh_tokens_session_id;
+  END IF;
+END $$;
+
+ALTER TABLE auth.tokens_cache ADD COLUMN IF NOT EXISTS ip TEXT;
+ALTER TABLE auth.tokens_cache ADD COLUMN IF NOT EXISTS user_agent TEXT;
+ALTER TABLE auth.tokens_cache ADD COLUMN IF NOT EXISTS last_seen_at BIGINT;
+
+-- Tokens issued before refresh tokens existed each count as their own session.
+UPDATE auth.tokens_cache SET session_id = substr(token_hash, 1, 32) WHERE session_id IS NULL;
+UPDATE auth.tokens_cache SET last_seen_at = modified_at WHERE last_seen_at IS NULL;
+ALTER TABLE auth.tokens_cache ALTER COLUMN session_id SET NOT NULL;
+ALTER TABLE auth.tokens_cache ALTER COLUMN last_seen_at SET NOT NULL;
+
+ALTER TABLE auth.refresh_tokens ADD COLUMN IF NOT EXISTS ip TEXT;
+ALTER TABLE auth.refresh_tokens ADD COLUMN IF NOT EXISTS user_agent TEXT;
@@ -0,0 +1,7 @@
+-- Per-service session limits and the service each session was opened for.
+
+\set ON_ERROR_STOP on
+
+ALTER TABLE auth.services ADD COLUMN IF NOT EXISTS max_sessions INTEGER;
+ALTER TABLE auth.tokens_cache ADD COLUMN IF NOT EXISTS service_id INTEGER;
+ALTER TABLE auth.refresh_tokens ADD COLUMN IF NOT EXISTS service_id INTEGER;
@@ -0,0 +1,21 @@
+-- API keys for backend services, and the calling service in the access log.
+
+\set ON_ERROR_STOP on
+
+CREATE TABLE IF NOT EXISTS auth.service_api_keys (
+  id SERIAL PRIMARY KEY,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
+  key_hash TEXT NOT NULL UNIQUE,
+  prefix TEXT NOT NULL,
+  last_used_at BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+DROP TRIGGER IF EXISTS trg_auth_service_api_keys_audit ON auth.service_api_keys;
+CREATE TRIGGER trg_auth_service_api_keys_audit
+BEFORE INSERT OR UPDATE ON auth.service_api_keys
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+ALTER TABLE auth.access_log ADD COLUMN IF NOT EXISTS service_id INTEGER;
@@ -0,0 +1,20 @@
+-- OAuth clients registered against services, for the client credentials grant.
+
+\set ON_ERROR_STOP on
+
+CREATE TABLE IF NOT EXISTS auth.oauth_clients (
+  id SERIAL PRIMARY KEY,
+  client_id TEXT NOT NULL UNIQUE,
+  secret_hash TEXT NOT NULL,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
+  name TEXT NOT NULL,
+  scopes TEXT[] NOT NULL DEFAULT '{}',
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+DROP TRIGGER IF EXISTS trg_auth_oauth_clients_audit ON auth.oauth_clients;
+CREATE TRIGGER trg_auth_oauth_clients_audit
+BEFORE INSERT OR UPDATE ON auth.oauth_clients
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
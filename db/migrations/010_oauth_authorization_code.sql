@@ -0,0 +1,41 @@
+-- Authorization code flow: public clients, registered redirect URIs and authorization codes.
+
+\set ON_ERROR_STOP on
+
+ALTER TABLE auth.oauth_clients ALTER COLUMN secret_hash DROP NOT NULL;
+ALTER TABLE auth.oauth_clients ADD COLUMN IF NOT EXISTS public BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE IF NOT EXISTS auth.oauth_redirect_uris (
+  id SERIAL PRIMARY KEY,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
+  redirect_uri TEXT NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE (service_id, redirect_uri)
+);
+
+CREATE TABLE IF NOT EXISTS auth.oauth_authorization_codes (
+  code_hash TEXT PRIMARY KEY,
+  client_id TEXT REFERENCES auth.oauth_clients(client_id) ON DELETE CASCADE NOT NULL,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  redirect_uri TEXT NOT NULL,
+  scope TEXT NOT NULL,
+  code_challenge TEXT NOT NULL,
+  expires_at BIGINT NOT NULL,
+  used_at BIGINT,
+  session_id TEXT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+DROP TRIGGER IF EXISTS trg_auth_oauth_redirect_uris_audit ON auth.oauth_redirect_uris;
+CREATE TRIGGER trg_auth_oauth_redirect_uris_audit
+BEFORE INSERT OR UPDATE ON auth.oauth_redirect_uris
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+DROP TRIGGER IF EXISTS trg_auth_oauth_authorization_codes_audit ON auth.oauth_authorization_codes;
+CREATE TRIGGER trg_auth_oauth_authorization_codes_audit
+BEFORE INSERT OR UPDATE ON auth.oauth_authorization_codes
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
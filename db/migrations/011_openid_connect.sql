@@ -0,0 +1,22 @@
+-- OpenID Connect: ID token signing keys and the nonce of authorization requests.
+-- Re-run db/auth_service.sql afterwards for the keys.read / keys.write permissions.
+
+\set ON_ERROR_STOP on
+
+CREATE TABLE IF NOT EXISTS auth.signing_keys (
+  kid TEXT PRIMARY KEY,
+  private_key BYTEA NOT NULL,
+  public_n TEXT NOT NULL,
+  public_e TEXT NOT NULL,
+  retired_at BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+DROP TRIGGER IF EXISTS trg_auth_signing_keys_audit ON auth.signing_keys;
+CREATE TRIGGER trg_auth_signing_keys_audit
+BEFORE INSERT OR UPDATE ON auth.signing_keys
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+ALTER TABLE auth.oauth_authorization_codes ADD COLUMN IF NOT EXISTS nonce TEXT;
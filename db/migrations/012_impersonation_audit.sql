@@ -0,0 +1,26 @@
+-- Audit trail of administrator impersonation.
+-- Re-run db/auth_service.sql afterwards for the users.impersonate permission.
+
+\set ON_ERROR_STOP on
+
+CREATE TABLE IF NOT EXISTS auth.impersonation_audit (
+  id BIGSERIAL PRIMARY KEY,
+  event TEXT NOT NULL CHECK (event IN ('start', 'stop')),
+  impersonator_id INTEGER REFERENCES auth.person(id) NOT NULL,
+  person_id INTEGER REFERENCES auth.person(id) NOT NULL,
+  session_id TEXT NOT NULL,
+  reason TEXT,
+  ip TEXT,
+  user_agent TEXT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_impersonation_audit_person_id ON auth.impersonation_audit(person_id);
+CREATE INDEX IF NOT EXISTS idx_auth_impersonation_audit_impersonator_id ON auth.impersonation_audit(impersonator_id);
+
+DROP TRIGGER IF EXISTS trg_auth_impersonation_audit_audit ON auth.impersonation_audit;
+CREATE TRIGGER trg_auth_impersonation_audit_audit
+BEFORE INSERT OR UPDATE ON auth.impersonation_audit
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
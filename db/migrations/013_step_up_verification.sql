@@ -0,0 +1,7 @@
+-- When each session last checked the password, for step-up re-authentication.
+-- Existing sessions start unverified and must call /auth/reauthenticate before sensitive operations.
+
+\set ON_ERROR_STOP on
+
+ALTER TABLE auth.tokens_cache ADD COLUMN IF NOT EXISTS verified_at BIGINT;
+ALTER TABLE auth.refresh_tokens ADD COLUMN IF NOT EXISTS verified_at BIGINT;
@@ -0,0 +1,36 @@
+-- TOTP second factor and pending two-step logins.
+
+\set ON_ERROR_STOP on
+
+CREATE TABLE IF NOT EXISTS auth.person_totp (
+  person_id INTEGER PRIMARY KEY REFERENCES auth.person(id) ON DELETE CASCADE,
+  secret_ciphertext BYTEA NOT NULL,
+  secret_nonce BYTEA NOT NULL,
+  confirmed_at BIGINT,
+  last_used_step BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE TABLE IF NOT EXISTS auth.login_challenges (
+  challenge_hash TEXT PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE,
+  expires_at BIGINT NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_login_challenges_expires_at ON auth.login_challenges(expires_at);
+
+DROP TRIGGER IF EXISTS trg_auth_person_totp_audit ON auth.person_totp;
+CREATE TRIGGER trg_auth_person_totp_audit
+BEFORE INSERT OR UPDATE ON auth.person_totp
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+DROP TRIGGER IF EXISTS trg_auth_login_challenges_audit ON auth.login_challenges;
+CREATE TRIGGER trg_auth_login_challenges_audit
+BEFORE INSERT OR UPDATE ON auth.login_challenges
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
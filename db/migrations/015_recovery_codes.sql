@@ -0,0 +1,38 @@
+-- TOTP recovery codes and per-person security events.
+
+\set ON_ERROR_STOP on
+
+CREATE TABLE IF NOT EXISTS auth.recovery_codes (
+  id BIGSERIAL PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  code_hash TEXT NOT NULL,
+  used_at BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE (person_id, code_hash)
+);
+
+CREATE TABLE IF NOT EXISTS auth.security_events (
+  id BIGSERIAL PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  event TEXT NOT NULL,
+  detail JSONB NOT NULL DEFAULT '{}'::JSONB,
+  ip TEXT,
+  user_agent TEXT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_security_events_person_id ON auth.security_events(person_id);
+
+DROP TRIGGER IF EXISTS trg_auth_recovery_codes_audit ON auth.recovery_codes;
+CREATE TRIGGER trg_auth_recovery_codes_audit
+BEFORE INSERT OR UPDATE ON auth.recovery_codes
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+DROP TRIGGER IF EXISTS trg_auth_security_events_audit ON auth.security_events;
+CREATE TRIGGER trg_auth_security_events_audit
+BEFORE INSERT OR UPDATE ON auth.security_events
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
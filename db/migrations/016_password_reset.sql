@@ -0,0 +1,28 @@
+-- Person emails and password reset tokens.
+-- create_person and update_person gain an email parameter: the old signatures are
+-- dropped here, re-run db/procedures.sql afterwards.
+
+\set ON_ERROR_STOP on
+
+ALTER TABLE auth.person ADD COLUMN IF NOT EXISTS email TEXT;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_auth_person_email ON auth.person(LOWER(email));
+
+DROP FUNCTION IF EXISTS auth.create_person(TEXT, TEXT, TEXT, auth.person_type, auth.document_type, TEXT);
+DROP PROCEDURE IF EXISTS auth.update_person(INT, TEXT, TEXT, TEXT);
+
+CREATE TABLE IF NOT EXISTS auth.password_reset_tokens (
+  token_hash TEXT PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  expires_at BIGINT NOT NULL,
+  used_at BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_password_reset_tokens_person_id ON auth.password_reset_tokens(person_id);
+
+DROP TRIGGER IF EXISTS trg_auth_password_reset_tokens_audit ON auth.password_reset_tokens;
+CREATE TRIGGER trg_auth_password_reset_tokens_audit
+BEFORE INSERT OR UPDATE ON auth.password_reset_tokens
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
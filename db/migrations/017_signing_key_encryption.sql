@@ -0,0 +1,6 @@
+-- Encrypt ID token signing keys at rest. Keys stored before this migration stay in plain
+-- form until the server starts and encrypts them with OIDC_KEY_ENCRYPTION_KEY.
+
+\set ON_ERROR_STOP on
+
+ALTER TABLE auth.signing_keys ADD COLUMN IF NOT EXISTS private_key_nonce BYTEA;
@@ -0,0 +1,7 @@
+-- At most one stop event per impersonation session, so the stop can be recorded from
+-- every place a session ends without duplicates.
+
+\set ON_ERROR_STOP on
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_auth_impersonation_audit_stop
+  ON auth.impersonation_audit(session_id) WHERE event = 'stop';
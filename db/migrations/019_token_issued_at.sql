@@ -0,0 +1,8 @@
+-- When each access token was issued, reported as `iat` by /oauth/introspect.
+-- created_at holds the start of the token's session; existing tokens fall back to it.
+
+\set ON_ERROR_STOP on
+
+ALTER TABLE auth.tokens_cache ADD COLUMN IF NOT EXISTS issued_at BIGINT;
+UPDATE auth.tokens_cache SET issued_at = created_at WHERE issued_at IS NULL;
+ALTER TABLE auth.tokens_cache ALTER COLUMN issued_at SET NOT NULL;
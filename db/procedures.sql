@@ -7,13 +7,14 @@ CREATE OR REPLACE FUNCTION auth.create_person(
     p_name TEXT,
     p_person_type auth.person_type,
     p_document_type auth.document_type,
-    p_document_number TEXT
+    p_document_number TEXT,
+    p_email TEXT DEFAULT NULL
 )
 RETURNS TABLE(id INT, username TEXT, name TEXT) AS $$
 BEGIN
     RETURN QUERY
-    INSERT INTO auth.person (username, password_hash, name, person_type, document_type, document_number)
-    VALUES (p_username, p_password_hash, p_name, p_person_type, p_document_type, p_document_number)
+    INSERT INTO auth.person (username, password_hash, name, person_type, document_type, document_number, email)
+    VALUES (p_username, p_password_hash, p_name, p_person_type, p_document_type, p_document_number, p_email)
     RETURNING auth.person.id, auth.person.username, auth.person.name;
 END;
 $$ LANGUAGE plpgsql;
@@ -43,14 +44,16 @@ CREATE OR REPLACE PROCEDURE auth.update_person(
     p_id INT,
     p_username TEXT,
     p_password_hash TEXT,
-    p_name TEXT
+    p_name TEXT,
+    p_email TEXT DEFAULT NULL
 ) AS $$
 BEGIN
     UPDATE auth.person
     SET
         username = COALESCE(p_username, username),
         password_hash = COALESCE(p_password_hash, password_hash),
-        name = COALESCE(p_name, name)
+        name = COALESCE(p_name, name),
+        email = COALESCE(p_email, email)
     WHERE id = p_id;
 END;
 $$ LANGUAGE plpgsql;
@@ -63,6 +66,19 @@ BEGIN
 END;
 $$ LANGUAGE plpgsql;
 
+-- Accounts whose password_hash is not yet an Argon2id PHC string (same rule as is_legacy_hash in src/password.rs)
+CREATE OR REPLACE FUNCTION auth.password_hash_report()
+RETURNS TABLE(legacy_count BIGINT, total_count BIGINT) AS $$
+BEGIN
+    RETURN QUERY
+    SELECT
+        COUNT(*) FILTER (WHERE p.password_hash NOT LIKE '$argon2id$%'),
+        COUNT(*)
+    FROM auth.person p
+    WHERE p.removed_at IS NULL;
+END;
+$$ LANGUAGE plpgsql;
+
 -- Role management
 CREATE OR REPLACE FUNCTION auth.create_role(p_name TEXT)
 RETURNS TABLE(id INT, name TEXT) AS $$
@@ -383,6 +399,23 @@ BEGIN
 END;
 $$ LANGUAGE plpgsql;
 
+CREATE OR REPLACE FUNCTION auth.list_person_permissions_in_service(p_person_id INT, p_service_id INT)
+RETURNS TABLE(id INT, name TEXT) AS $$
+BEGIN
+    RETURN QUERY
+    SELECT DISTINCT p.id, p.name
+    FROM auth.person_service_role psr
+    JOIN auth.service_roles sr
+      ON sr.service_id = psr.service_id
+     AND sr.role_id = psr.role_id
+    JOIN auth.service_role_permission srp ON srp.service_role_id = sr.id
+    JOIN auth.permission p ON srp.permission_id = p.id
+    WHERE psr.person_id = p_person_id
+      AND psr.service_id = p_service_id
+    ORDER BY p.id;
+END;
+$$ LANGUAGE plpgsql;
+
 CREATE OR REPLACE FUNCTION auth.list_services_of_person(p_person_id INT)
 RETURNS TABLE(id INT, name TEXT) AS $$
 BEGIN
@@ -14,6 +14,9 @@ CREATE DATABASE auth_api;
 \echo 'Loading stored procedures...'
 \ir procedures.sql
 
+\echo 'Registering auth API service...'
+\ir auth_service.sql
+
 \echo 'Loading demo data...'
 \ir demo_data.sql
 
@@ -16,12 +16,16 @@ CREATE TABLE auth.person (
   person_type auth.person_type NOT NULL DEFAULT 'N',
   document_type auth.document_type NOT NULL DEFAULT 'DNI',
   document_number TEXT NOT NULL,
+  email TEXT,
   created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
   updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
   removed_at BIGINT,
   UNIQUE (document_type, document_number)
 );
 
+-- Where password reset tokens are sent; compared case-insensitively
+CREATE UNIQUE INDEX idx_auth_person_email ON auth.person(LOWER(email));
+
 CREATE TABLE auth.role (
   id SERIAL PRIMARY KEY,
   name TEXT NOT NULL UNIQUE,
@@ -40,6 +44,7 @@ CREATE TABLE auth.services (
   id SERIAL PRIMARY KEY,
   name TEXT NOT NULL UNIQUE,
   description TEXT,
+  max_sessions INTEGER,
   created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
   updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
   status BOOLEAN NOT NULL DEFAULT TRUE
@@ -78,15 +83,207 @@ CREATE TABLE auth.person_service_role (
   UNIQUE (person_id, service_id, role_id)
 );
 
+-- token_hash is the hex SHA-256 of the bearer token; raw tokens are never stored
 CREATE TABLE auth.tokens_cache (
-  token TEXT PRIMARY KEY,
+  token_hash TEXT PRIMARY KEY,
   payload JSONB NOT NULL,
   modified_at BIGINT NOT NULL,
+  session_id TEXT NOT NULL,
+  ip TEXT,
+  user_agent TEXT,
+  service_id INTEGER,
+  last_seen_at BIGINT NOT NULL,
+  verified_at BIGINT,
+  issued_at BIGINT NOT NULL,
   created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
   updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
 );
 
 CREATE INDEX idx_auth_tokens_modified_at ON auth.tokens_cache(modified_at);
+CREATE INDEX idx_auth_tokens_session_id ON auth.tokens_cache(session_id);
+
+-- Single-use refresh tokens; every token issued from one login shares a session_id
+CREATE TABLE auth.refresh_tokens (
+  token_hash TEXT PRIMARY KEY,
+  session_id TEXT NOT NULL,
+  payload JSONB NOT NULL,
+  expires_at BIGINT NOT NULL,
+  used_at BIGINT,
+  session_started_at BIGINT NOT NULL,
+  ip TEXT,
+  user_agent TEXT,
+  service_id INTEGER,
+  verified_at BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE INDEX idx_auth_refresh_tokens_session_id ON auth.refresh_tokens(session_id);
+
+-- API keys of backend services; only the SHA-256 of the key is stored
+CREATE TABLE auth.service_api_keys (
+  id SERIAL PRIMARY KEY,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
+  key_hash TEXT NOT NULL UNIQUE,
+  prefix TEXT NOT NULL,
+  last_used_at BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+-- OAuth clients of a service; secrets are stored hashed, public clients have none
+CREATE TABLE auth.oauth_clients (
+  id SERIAL PRIMARY KEY,
+  client_id TEXT NOT NULL UNIQUE,
+  secret_hash TEXT,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
+  name TEXT NOT NULL,
+  scopes TEXT[] NOT NULL DEFAULT '{}',
+  public BOOLEAN NOT NULL DEFAULT FALSE,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+-- Redirect URIs the authorization code flow may send a service's users back to
+CREATE TABLE auth.oauth_redirect_uris (
+  id SERIAL PRIMARY KEY,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
+  redirect_uri TEXT NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE (service_id, redirect_uri)
+);
+
+-- Short-lived authorization codes (stored hashed), spent on first exchange
+CREATE TABLE auth.oauth_authorization_codes (
+  code_hash TEXT PRIMARY KEY,
+  client_id TEXT REFERENCES auth.oauth_clients(client_id) ON DELETE CASCADE NOT NULL,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  redirect_uri TEXT NOT NULL,
+  scope TEXT NOT NULL,
+  code_challenge TEXT NOT NULL,
+  expires_at BIGINT NOT NULL,
+  used_at BIGINT,
+  session_id TEXT,
+  nonce TEXT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+-- RS256 keys that sign OpenID Connect ID tokens; retired keys stay published until their tokens expire
+-- private_key is AES-256-GCM encrypted under private_key_nonce (NULL only for keys not yet encrypted)
+CREATE TABLE auth.signing_keys (
+  kid TEXT PRIMARY KEY,
+  private_key BYTEA NOT NULL,
+  private_key_nonce BYTEA,
+  public_n TEXT NOT NULL,
+  public_e TEXT NOT NULL,
+  retired_at BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+-- Every start and stop of an administrator impersonating a person; never updated or deleted
+CREATE TABLE auth.impersonation_audit (
+  id BIGSERIAL PRIMARY KEY,
+  event TEXT NOT NULL CHECK (event IN ('start', 'stop')),
+  impersonator_id INTEGER REFERENCES auth.person(id) NOT NULL,
+  person_id INTEGER REFERENCES auth.person(id) NOT NULL,
+  session_id TEXT NOT NULL,
+  reason TEXT,
+  ip TEXT,
+  user_agent TEXT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE INDEX idx_auth_impersonation_audit_person_id ON auth.impersonation_audit(person_id);
+CREATE INDEX idx_auth_impersonation_audit_impersonator_id ON auth.impersonation_audit(impersonator_id);
+CREATE UNIQUE INDEX idx_auth_impersonation_audit_stop ON auth.impersonation_audit(session_id) WHERE event = 'stop';
+
+-- TOTP second factor of a person; the secret is AES-256-GCM encrypted by the API.
+-- confirmed_at stays NULL until the first code is accepted.
+CREATE TABLE auth.person_totp (
+  person_id INTEGER PRIMARY KEY REFERENCES auth.person(id) ON DELETE CASCADE,
+  secret_ciphertext BYTEA NOT NULL,
+  secret_nonce BYTEA NOT NULL,
+  confirmed_at BIGINT,
+  last_used_step BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+-- Logins whose password was accepted and that wait for the second factor
+CREATE TABLE auth.login_challenges (
+  challenge_hash TEXT PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE,
+  expires_at BIGINT NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE INDEX idx_auth_login_challenges_expires_at ON auth.login_challenges(expires_at);
+
+-- Single-use codes standing in for a lost TOTP authenticator, stored as SHA-256
+CREATE TABLE auth.recovery_codes (
+  id BIGSERIAL PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  code_hash TEXT NOT NULL,
+  used_at BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE (person_id, code_hash)
+);
+
+-- Security history of a person (second factor changes, recovery code use, ...)
+CREATE TABLE auth.security_events (
+  id BIGSERIAL PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  event TEXT NOT NULL,
+  detail JSONB NOT NULL DEFAULT '{}'::JSONB,
+  ip TEXT,
+  user_agent TEXT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE INDEX idx_auth_security_events_person_id ON auth.security_events(person_id);
+
+-- Single-use tokens from /auth/password/forgot, stored as SHA-256
+CREATE TABLE auth.password_reset_tokens (
+  token_hash TEXT PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  expires_at BIGINT NOT NULL,
+  used_at BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE INDEX idx_auth_password_reset_tokens_person_id ON auth.password_reset_tokens(person_id);
+
+-- Access log rows when ACCESS_LOG_SINK=db; tokens are only recorded by fingerprint
+CREATE TABLE auth.access_log (
+  id BIGSERIAL PRIMARY KEY,
+  token_fingerprint TEXT NOT NULL,
+  user_id INTEGER,
+  service_id INTEGER,
+  endpoint TEXT NOT NULL,
+  ip TEXT NOT NULL,
+  ts BIGINT NOT NULL
+);
+
+CREATE INDEX idx_auth_access_log_ts ON auth.access_log(ts);
+
+-- Failed login counters, keyed by 'user:<username>', 'ip:<address>' or 'mfa:<person_id>'
+CREATE TABLE auth.login_attempts (
+  subject TEXT PRIMARY KEY,
+  failures INTEGER NOT NULL DEFAULT 0,
+  last_failure_at BIGINT NOT NULL,
+  locked_until BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
 
 CREATE OR REPLACE FUNCTION auth.set_epoch_audit_fields()
 RETURNS TRIGGER AS $$
@@ -149,3 +346,68 @@ CREATE TRIGGER trg_auth_tokens_cache_audit
 BEFORE INSERT OR UPDATE ON auth.tokens_cache
 FOR EACH ROW
 EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_login_attempts_audit
+BEFORE INSERT OR UPDATE ON auth.login_attempts
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_refresh_tokens_audit
+BEFORE INSERT OR UPDATE ON auth.refresh_tokens
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_service_api_keys_audit
+BEFORE INSERT OR UPDATE ON auth.service_api_keys
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_oauth_clients_audit
+BEFORE INSERT OR UPDATE ON auth.oauth_clients
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_oauth_redirect_uris_audit
+BEFORE INSERT OR UPDATE ON auth.oauth_redirect_uris
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_oauth_authorization_codes_audit
+BEFORE INSERT OR UPDATE ON auth.oauth_authorization_codes
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_signing_keys_audit
+BEFORE INSERT OR UPDATE ON auth.signing_keys
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_impersonation_audit_audit
+BEFORE INSERT OR UPDATE ON auth.impersonation_audit
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_person_totp_audit
+BEFORE INSERT OR UPDATE ON auth.person_totp
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_login_challenges_audit
+BEFORE INSERT OR UPDATE ON auth.login_challenges
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_recovery_codes_audit
+BEFORE INSERT OR UPDATE ON auth.recovery_codes
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_security_events_audit
+BEFORE INSERT OR UPDATE ON auth.security_events
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_password_reset_tokens_audit
+BEFORE INSERT OR UPDATE ON auth.password_reset_tokens
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
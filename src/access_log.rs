@@ -0,0 +1,307 @@
+use crate::auth::TokenManager;
+use crate::database::DB;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogSink {
+  Stdout,
+  File(PathBuf),
+  Database,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+  pub sink: LogSink,
+  pub retention_days: i64,
+}
+
+impl AccessLogConfig {
+  pub fn load() -> Self {
+    let sink = match env::var("ACCESS_LOG_SINK")
+      .unwrap_or_default()
+      .to_ascii_lowercase()
+      .as_str()
+    {
+      "file" => LogSink::File(PathBuf::from(
+        env::var("ACCESS_LOG_DIR").unwrap_or_else(|_| "logs".to_string()),
+      )),
+      "db" | "database" => LogSink::Database,
+      _ => LogSink::Stdout,
+    };
+    let retention_days = env::var("ACCESS_LOG_RETENTION_DAYS")
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .unwrap_or(30);
+    Self {
+      sink,
+      retention_days,
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessEntry {
+  pub token_fingerprint: String,
+  pub user_id: Option<i64>,
+  /// Service that made the request with its API key, if any.
+  pub service_id: Option<i32>,
+  pub endpoint: String,
+  pub ip: String,
+  pub ts: i64,
+}
+
+impl AccessEntry {
+  fn to_line(&self) -> String {
+    let user_id = self
+      .user_id
+      .map(|id| id.to_string())
+      .unwrap_or_else(|| "-".to_string());
+    let service_id = self
+      .service_id
+      .map(|id| id.to_string())
+      .unwrap_or_else(|| "-".to_string());
+    format!(
+      "[access] token_fp={} user_id={} service_id={} endpoint={} ts={} ip={}",
+      self.token_fingerprint, user_id, service_id, self.endpoint, self.ts, self.ip
+    )
+  }
+}
+
+/// Stable, non-reversible identifier for a token: a prefix of the digest kept in `auth.tokens_cache`.
+pub fn token_fingerprint(token: &str) -> String {
+  TokenManager::hash_token(token)[..16].to_string()
+}
+
+/// Civil date (YYYY-MM-DD, UTC) for an epoch timestamp, used to name daily log files.
+fn epoch_to_date(ts: i64) -> String {
+  let days = ts.div_euclid(86_400);
+  let z = days + 719_468;
+  let era = z.div_euclid(146_097);
+  let doe = z - era * 146_097;
+  let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + i64::from(month <= 2);
+  format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+async fn append_to_file(dir: &Path, entry: &AccessEntry) -> std::io::Result<()> {
+  tokio::fs::create_dir_all(dir).await?;
+  let path = dir.join(format!("access-{}.log", epoch_to_date(entry.ts)));
+  let mut file = tokio::fs::OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(path)
+    .await?;
+  file
+    .write_all(format!("{}\n", entry.to_line()).as_bytes())
+    .await?;
+  // tokio hands the write to a blocking task; wait for it before reporting success.
+  file.flush().await
+}
+
+async fn insert_into_db(entry: &AccessEntry) -> Result<(), sqlx::Error> {
+  let db = DB::shared().await?;
+  sqlx::query(
+    "INSERT INTO auth.access_log (token_fingerprint, user_id, service_id, endpoint, ip, ts) VALUES ($1, $2, $3, $4, $5, $6)",
+  )
+  .bind(&entry.token_fingerprint)
+  .bind(entry.user_id)
+  .bind(entry.service_id)
+  .bind(&entry.endpoint)
+  .bind(&entry.ip)
+  .bind(entry.ts)
+  .execute(db.pool())
+  .await?;
+  Ok(())
+}
+
+/// Write an entry to the configured sink. File and database writes run in the
+/// background so they never delay the response.
+pub fn record(entry: AccessEntry) {
+  match AccessLogConfig::load().sink {
+    LogSink::Stdout => println!("{}", entry.to_line()),
+    LogSink::File(dir) => {
+      tokio::spawn(async move {
+        if let Err(err) = append_to_file(&dir, &entry).await {
+          eprintln!("[access-log-error] {}", err);
+        }
+      });
+    }
+    LogSink::Database => {
+      tokio::spawn(async move {
+        if let Err(err) = insert_into_db(&entry).await {
+          eprintln!("[access-log-error] {}", err);
+        }
+      });
+    }
+  }
+}
+
+async fn cleanup_db(db: &DB, cutoff: i64) -> Result<u64, sqlx::Error> {
+  let rows = sqlx::query("DELETE FROM auth.access_log WHERE ts < $1")
+    .bind(cutoff)
+    .execute(db.pool())
+    .await?
+    .rows_affected();
+  Ok(rows)
+}
+
+async fn cleanup_files(dir: &Path, cutoff: i64) -> std::io::Result<u64> {
+  let cutoff_time = SystemTime::UNIX_EPOCH + Duration::from_secs(cutoff.max(0) as u64);
+  let mut removed = 0;
+  let mut entries = match tokio::fs::read_dir(dir).await {
+    Ok(entries) => entries,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+    Err(err) => return Err(err),
+  };
+  while let Some(file) = entries.next_entry().await? {
+    let name = file.file_name().to_string_lossy().to_string();
+    if !(name.starts_with("access-") && name.ends_with(".log")) {
+      continue;
+    }
+    let modified = file.metadata().await?.modified()?;
+    if modified < cutoff_time {
+      tokio::fs::remove_file(file.path()).await?;
+      removed += 1;
+    }
+  }
+  Ok(removed)
+}
+
+/// Remove entries older than the retention window from the file or database sink.
+pub async fn cleanup_expired(db: &DB, now: i64) -> Result<u64, Box<dyn std::error::Error>> {
+  let config = AccessLogConfig::load();
+  if config.retention_days <= 0 {
+    return Ok(0);
+  }
+  let cutoff = now - config.retention_days * 86_400;
+  match config.sink {
+    LogSink::Stdout => Ok(0),
+    LogSink::Database => Ok(cleanup_db(db, cutoff).await?),
+    LogSink::File(dir) => Ok(cleanup_files(&dir, cutoff).await?),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::UNIX_EPOCH;
+
+  const RAW_TOKEN: &str = "raw-access-log-test-token";
+
+  fn now_epoch() -> i64 {
+    SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs() as i64
+  }
+
+  fn entry(endpoint: &str, ts: i64) -> AccessEntry {
+    AccessEntry {
+      token_fingerprint: token_fingerprint(RAW_TOKEN),
+      user_id: Some(1),
+      service_id: None,
+      endpoint: endpoint.to_string(),
+      ip: "127.0.0.1".to_string(),
+      ts,
+    }
+  }
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let nanos = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_nanos();
+    env::temp_dir().join(format!("access-log-{}-{}", name, nanos))
+  }
+
+  #[test]
+  fn fingerprint_is_a_digest_prefix() {
+    let fingerprint = token_fingerprint(RAW_TOKEN);
+    assert_eq!(fingerprint.len(), 16);
+    assert!(TokenManager::hash_token(RAW_TOKEN).starts_with(&fingerprint));
+    assert!(!fingerprint.contains(RAW_TOKEN));
+  }
+
+  #[tokio::test]
+  async fn file_sink_writes_fingerprint_not_token() {
+    let dir = temp_dir("write");
+    let ts = 1_700_000_000;
+    append_to_file(&dir, &entry("/auth/profile", ts))
+      .await
+      .unwrap();
+
+    let content = std::fs::read_to_string(dir.join("access-2023-11-14.log")).unwrap();
+    assert!(content.contains(&format!("token_fp={}", token_fingerprint(RAW_TOKEN))));
+    assert!(content.contains("endpoint=/auth/profile"));
+    assert!(!content.contains(RAW_TOKEN));
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn file_cleanup_removes_files_past_retention() {
+    let dir = temp_dir("cleanup");
+    std::fs::create_dir_all(&dir).unwrap();
+    let now = now_epoch();
+    let old_time = SystemTime::now() - Duration::from_secs(40 * 86_400);
+    for name in ["access-old.log", "other.log"] {
+      let file = std::fs::File::create(dir.join(name)).unwrap();
+      file.set_modified(old_time).unwrap();
+    }
+    std::fs::File::create(dir.join("access-new.log")).unwrap();
+
+    let removed = cleanup_files(&dir, now - 30 * 86_400).await.unwrap();
+    assert_eq!(removed, 1);
+    assert!(!dir.join("access-old.log").exists());
+    assert!(dir.join("access-new.log").exists());
+    assert!(dir.join("other.log").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn database_sink_stores_fingerprint_and_cleanup_removes_old_rows() {
+    let db = DB::shared().await.expect("database connection");
+    let now = now_epoch();
+    let nanos = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_nanos();
+    let endpoint = format!("/access-log-test/{}", nanos);
+    insert_into_db(&entry(&endpoint, now)).await.unwrap();
+    insert_into_db(&entry(&endpoint, now - 31 * 86_400))
+      .await
+      .unwrap();
+
+    let raw = sqlx::query_scalar::<_, i64>(
+      "SELECT COUNT(*) FROM auth.access_log l WHERE l.endpoint = $1 AND strpos(l::text, $2) > 0",
+    )
+    .bind(&endpoint)
+    .bind(RAW_TOKEN)
+    .fetch_one(db.pool())
+    .await
+    .unwrap();
+    assert_eq!(raw, 0);
+
+    assert!(cleanup_db(&db, now - 30 * 86_400).await.unwrap() >= 1);
+    let remaining = sqlx::query_scalar::<_, i64>(
+      "SELECT ts FROM auth.access_log WHERE endpoint = $1 AND token_fingerprint = $2",
+    )
+    .bind(&endpoint)
+    .bind(token_fingerprint(RAW_TOKEN))
+    .fetch_all(db.pool())
+    .await
+    .unwrap();
+    assert_eq!(remaining, vec![now]);
+    sqlx::query("DELETE FROM auth.access_log WHERE endpoint = $1")
+      .bind(&endpoint)
+      .execute(db.pool())
+      .await
+      .unwrap();
+  }
+}
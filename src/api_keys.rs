@@ -0,0 +1,164 @@
+use crate::auth::TokenManager;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::Serialize;
+use sqlx::{PgConnection, Pool, Postgres};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Every key starts with this marker so leaked keys are easy to spot in logs and repos.
+const KEY_MARKER: &str = "eqk_";
+/// Characters of the key (after the marker) kept in clear to tell keys apart.
+const PREFIX_LEN: usize = 8;
+
+/// Key metadata shown to administrators; the key itself is only returned when issued.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ApiKeyInfo {
+  pub id: i32,
+  pub service_id: i32,
+  pub prefix: String,
+  pub created_at: i64,
+  pub last_used_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IssuedApiKey {
+  pub id: i32,
+  pub service_id: i32,
+  pub prefix: String,
+  pub api_key: String,
+}
+
+/// Service that authenticated with an API key.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ServiceCaller {
+  pub key_id: i32,
+  pub service_id: i32,
+}
+
+pub struct ApiKeyManager<'a> {
+  pool: &'a Pool<Postgres>,
+}
+
+impl<'a> ApiKeyManager<'a> {
+  pub fn new(pool: &'a Pool<Postgres>) -> Self {
+    Self { pool }
+  }
+
+  fn now_epoch() -> i64 {
+    SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs() as i64
+  }
+
+  fn generate_key() -> String {
+    let mut random = [0u8; 32];
+    OsRng.fill_bytes(&mut random);
+    let body: String = random.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}{}", KEY_MARKER, body)
+  }
+
+  async fn insert_key(
+    conn: &mut PgConnection,
+    service_id: i32,
+  ) -> Result<IssuedApiKey, sqlx::Error> {
+    let api_key = Self::generate_key();
+    let prefix = api_key[..KEY_MARKER.len() + PREFIX_LEN].to_string();
+    let id = sqlx::query_scalar::<_, i32>(
+      "INSERT INTO auth.service_api_keys (service_id, key_hash, prefix) VALUES ($1, $2, $3) RETURNING id",
+    )
+    .bind(service_id)
+    .bind(TokenManager::hash_token(&api_key))
+    .bind(&prefix)
+    .fetch_one(conn)
+    .await?;
+    Ok(IssuedApiKey {
+      id,
+      service_id,
+      prefix,
+      api_key,
+    })
+  }
+
+  /// Issue a new key for an active service; `None` when the service is unknown or disabled.
+  pub async fn issue(&self, service_id: i32) -> Result<Option<IssuedApiKey>, sqlx::Error> {
+    let mut conn = self.pool.acquire().await?;
+    let active = sqlx::query_scalar::<_, bool>(
+      "SELECT EXISTS (SELECT 1 FROM auth.services WHERE id = $1 AND status = TRUE)",
+    )
+    .bind(service_id)
+    .fetch_one(&mut *conn)
+    .await?;
+    if !active {
+      return Ok(None);
+    }
+    Self::insert_key(&mut conn, service_id).await.map(Some)
+  }
+
+  pub async fn list(&self, service_id: i32) -> Result<Vec<ApiKeyInfo>, sqlx::Error> {
+    sqlx::query_as::<_, ApiKeyInfo>(
+      "SELECT id, service_id, prefix, created_at, last_used_at
+       FROM auth.service_api_keys WHERE service_id = $1 ORDER BY id",
+    )
+    .bind(service_id)
+    .fetch_all(self.pool)
+    .await
+  }
+
+  pub async fn revoke(&self, service_id: i32, key_id: i32) -> Result<bool, sqlx::Error> {
+    let rows = sqlx::query("DELETE FROM auth.service_api_keys WHERE id = $1 AND service_id = $2")
+      .bind(key_id)
+      .bind(service_id)
+      .execute(self.pool)
+      .await?
+      .rows_affected();
+    Ok(rows > 0)
+  }
+
+  /// Replace a key with a new one in a single step; `None` when the key does not exist.
+  pub async fn rotate(
+    &self,
+    service_id: i32,
+    key_id: i32,
+  ) -> Result<Option<IssuedApiKey>, sqlx::Error> {
+    let mut tx = self.pool.begin().await?;
+    let rows = sqlx::query("DELETE FROM auth.service_api_keys WHERE id = $1 AND service_id = $2")
+      .bind(key_id)
+      .bind(service_id)
+      .execute(&mut *tx)
+      .await?
+      .rows_affected();
+    if rows == 0 {
+      return Ok(None);
+    }
+    let issued = Self::insert_key(&mut tx, service_id).await?;
+    tx.commit().await?;
+    Ok(Some(issued))
+  }
+
+  /// Resolve a presented key to its service. Keys of disabled services are rejected.
+  pub async fn authenticate(&self, api_key: &str) -> Result<Option<ServiceCaller>, sqlx::Error> {
+    let caller = sqlx::query_as::<_, ServiceCaller>(
+      "SELECT k.id AS key_id, s.id AS service_id
+       FROM auth.service_api_keys k
+       JOIN auth.services s ON s.id = k.service_id
+       WHERE k.key_hash = $1 AND s.status = TRUE",
+    )
+    .bind(TokenManager::hash_token(api_key))
+    .fetch_optional(self.pool)
+    .await?;
+    if let Some(caller) = &caller {
+      // last_used_at is informational; refresh it at most once a minute per key.
+      let now = Self::now_epoch();
+      sqlx::query(
+        "UPDATE auth.service_api_keys SET last_used_at = $2
+         WHERE id = $1 AND (last_used_at IS NULL OR last_used_at < $2 - 60)",
+      )
+      .bind(caller.key_id)
+      .bind(now)
+      .execute(self.pool)
+      .await?;
+    }
+    Ok(caller)
+  }
+}
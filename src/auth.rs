@@ -1,23 +1,87 @@
+use crate::impersonation::ImpersonationAudit;
 use rand::RngCore;
 use rand::rngs::OsRng;
 use serde::Serialize;
 use serde_json::Value;
 use sha2::{Digest, Sha256};
-use sqlx::{Pool, Postgres};
+use sqlx::{PgConnection, Pool, Postgres};
 use std::env;
+use std::fmt;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// How stale `last_seen_at` may get before a validation that does not renew the token
+/// writes it again.
+const LAST_SEEN_RESOLUTION_SECONDS: i64 = 60;
+
 #[derive(Debug, Clone, sqlx::FromRow)]
 pub struct TokenRecord {
-  pub token: String,
+  pub token_hash: String,
   pub payload: Value,
   pub modified_at: i64,
+  pub session_id: String,
+  pub created_at: i64,
+  /// When this access token was issued; `created_at` is the start of its session.
+  pub issued_at: i64,
+  /// When the person last proved who they are (login or re-authentication) in this session.
+  pub verified_at: Option<i64>,
+}
+
+/// Where a session was opened from; recorded with its tokens for the session list.
+#[derive(Debug, Clone, Default)]
+pub struct SessionContext {
+  pub ip: Option<String>,
+  pub user_agent: Option<String>,
+  /// Service the session was opened for; its `max_sessions` applies on top of the global limit.
+  pub service_id: Option<i32>,
+  /// Last password check; `None` for tokens issued without one.
+  pub verified_at: Option<i64>,
+}
+
+/// One login as shown to its owner. Never carries token values.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct SessionInfo {
+  pub session_id: String,
+  pub ip: Option<String>,
+  pub user_agent: Option<String>,
+  pub service_id: Option<i32>,
+  pub created_at: i64,
+  pub last_seen_at: i64,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+struct RefreshRecord {
+  session_id: String,
+  payload: Value,
+  expires_at: i64,
+  used_at: Option<i64>,
+  session_started_at: i64,
+  ip: Option<String>,
+  user_agent: Option<String>,
+  service_id: Option<i32>,
+  verified_at: Option<i64>,
+}
+
+/// What happens when a login would exceed a session limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionLimitPolicy {
+  /// Revoke the person's oldest sessions to make room.
+  EvictOldest,
+  /// Refuse the new login.
+  Reject,
 }
 
 #[derive(Debug, Clone)]
 pub struct TokenConfig {
   pub ttl_seconds: i64,
   pub renew_threshold_seconds: i64,
+  pub refresh_ttl_seconds: i64,
+  /// Hard cap on a session's age regardless of renewals; `0` disables it.
+  pub max_lifetime_seconds: i64,
+  /// Active sessions allowed per person across all services; `0` disables it.
+  pub max_sessions_per_person: i64,
+  pub session_limit_policy: SessionLimitPolicy,
+  /// How recent a password check must be for sensitive operations.
+  pub reauth_max_age_seconds: i64,
 }
 
 impl TokenConfig {
@@ -30,9 +94,38 @@ impl TokenConfig {
       .ok()
       .and_then(|v| v.parse::<i64>().ok())
       .unwrap_or(30);
+    let refresh_ttl_seconds = env::var("REFRESH_TOKEN_TTL_SECONDS")
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .unwrap_or(604800);
+    let max_lifetime_seconds = env::var("TOKEN_MAX_LIFETIME_SECONDS")
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .unwrap_or(86400);
+    let max_sessions_per_person = env::var("MAX_SESSIONS_PER_PERSON")
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .unwrap_or(0);
+    let session_limit_policy = match env::var("SESSION_LIMIT_POLICY")
+      .unwrap_or_default()
+      .to_ascii_lowercase()
+      .as_str()
+    {
+      "reject" => SessionLimitPolicy::Reject,
+      _ => SessionLimitPolicy::EvictOldest,
+    };
+    let reauth_max_age_seconds = env::var("REAUTH_MAX_AGE_SECONDS")
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .unwrap_or(300);
     Self {
       ttl_seconds,
       renew_threshold_seconds,
+      refresh_ttl_seconds,
+      max_lifetime_seconds,
+      max_sessions_per_person,
+      session_limit_policy,
+      reauth_max_age_seconds,
     }
   }
 }
@@ -47,6 +140,8 @@ pub struct TokenManager<'a> {
 pub enum TokenError {
   NotFound,
   Expired,
+  /// A refresh token was presented after it had already been exchanged.
+  Reused,
   Database(sqlx::Error),
 }
 
@@ -56,10 +151,47 @@ impl From<sqlx::Error> for TokenError {
   }
 }
 
+#[derive(Debug)]
+pub enum SessionError {
+  /// The person already holds `limit` sessions and the policy is to reject.
+  LimitReached {
+    limit: i64,
+  },
+  Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for SessionError {
+  fn from(err: sqlx::Error) -> Self {
+    SessionError::Database(err)
+  }
+}
+
+impl fmt::Display for SessionError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      SessionError::LimitReached { limit } => write!(f, "session limit of {} reached", limit),
+      SessionError::Database(err) => write!(f, "{}", err),
+    }
+  }
+}
+
 #[derive(Debug, Serialize)]
 pub struct TokenIssue {
   pub token: String,
   pub expires_at: i64,
+  pub session_id: String,
+}
+
+/// Access token plus the refresh token that can replace it.
+#[derive(Debug, Serialize)]
+pub struct SessionIssue {
+  pub token: String,
+  pub expires_at: i64,
+  pub refresh_token: String,
+  pub refresh_expires_at: i64,
+  pub max_expires_at: Option<i64>,
+  pub payload: Value,
+  pub session_id: String,
 }
 
 #[derive(Debug)]
@@ -67,6 +199,7 @@ pub struct TokenValidation {
   pub record: TokenRecord,
   pub renewed: bool,
   pub expires_at: i64,
+  pub max_expires_at: Option<i64>,
 }
 
 impl<'a> TokenManager<'a> {
@@ -92,33 +225,78 @@ impl<'a> TokenManager<'a> {
 
     let mut hasher = Sha256::new();
     hasher.update(secret.as_bytes());
-    hasher.update(&random);
+    hasher.update(random);
     hasher.update(now.to_be_bytes());
 
     let digest = hasher.finalize();
     format!("{:x}", digest)
   }
 
+  /// Only this digest is stored, so a copy of `auth.tokens_cache` cannot be replayed as sessions.
+  pub fn hash_token(token: &str) -> String {
+    let digest = Sha256::digest(token.as_bytes());
+    format!("{:x}", digest)
+  }
+
   async fn insert_token(
-    &self,
+    conn: &mut PgConnection,
     token: &str,
     payload: &Value,
     modified_at: i64,
+    session_id: &str,
+    created_at: i64,
+    context: &SessionContext,
   ) -> Result<(), sqlx::Error> {
-    sqlx::query("INSERT INTO auth.tokens_cache (token, payload, modified_at) VALUES ($1, $2, $3)")
-      .bind(token)
-      .bind(payload)
-      .bind(modified_at)
-      .execute(self.pool)
-      .await?;
+    sqlx::query(
+      "INSERT INTO auth.tokens_cache (token_hash, payload, modified_at, session_id, created_at, ip, user_agent, service_id, last_seen_at, verified_at, issued_at)
+       VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $3, $9, $3)",
+    )
+    .bind(Self::hash_token(token))
+    .bind(payload)
+    .bind(modified_at)
+    .bind(session_id)
+    .bind(created_at)
+    .bind(&context.ip)
+    .bind(&context.user_agent)
+    .bind(context.service_id)
+    .bind(context.verified_at)
+    .execute(conn)
+    .await?;
+    Ok(())
+  }
+
+  async fn insert_refresh_token(
+    conn: &mut PgConnection,
+    token: &str,
+    session_id: &str,
+    payload: &Value,
+    expires_at: i64,
+    session_started_at: i64,
+    context: &SessionContext,
+  ) -> Result<(), sqlx::Error> {
+    sqlx::query(
+      "INSERT INTO auth.refresh_tokens (token_hash, session_id, payload, expires_at, session_started_at, ip, user_agent, service_id, verified_at)
+       VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+    )
+    .bind(Self::hash_token(token))
+    .bind(session_id)
+    .bind(payload)
+    .bind(expires_at)
+    .bind(session_started_at)
+    .bind(&context.ip)
+    .bind(&context.user_agent)
+    .bind(context.service_id)
+    .bind(context.verified_at)
+    .execute(conn)
+    .await?;
     Ok(())
   }
 
   async fn fetch_token(&self, token: &str) -> Result<Option<TokenRecord>, sqlx::Error> {
     sqlx::query_as::<_, TokenRecord>(
-      "SELECT token, payload, modified_at FROM auth.tokens_cache WHERE token = $1",
+      "SELECT token_hash, payload, modified_at, session_id, created_at, issued_at, verified_at FROM auth.tokens_cache WHERE token_hash = $1",
     )
-    .bind(token)
+    .bind(Self::hash_token(token))
     .fetch_optional(self.pool)
     .await
   }
@@ -130,56 +308,502 @@ impl<'a> TokenManager<'a> {
     new_modified_at: i64,
   ) -> Result<Option<TokenRecord>, sqlx::Error> {
     sqlx::query_as::<_, TokenRecord>(
-      "UPDATE auth.tokens_cache SET modified_at = $1 WHERE token = $2 AND modified_at = $3 RETURNING token, payload, modified_at",
+      "UPDATE auth.tokens_cache SET modified_at = $1, last_seen_at = $1 WHERE token_hash = $2 AND modified_at = $3 RETURNING token_hash, payload, modified_at, session_id, created_at, issued_at, verified_at",
     )
     .bind(new_modified_at)
-    .bind(token)
+    .bind(Self::hash_token(token))
     .bind(previous_modified_at)
     .fetch_optional(self.pool)
     .await
   }
 
+  /// Record use of a token that was not renewed. Writes at most once a minute per token.
+  async fn mark_seen(&self, token: &str, now: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+      "UPDATE auth.tokens_cache SET last_seen_at = $1 WHERE token_hash = $2 AND last_seen_at <= $1 - $3",
+    )
+    .bind(now)
+    .bind(Self::hash_token(token))
+    .bind(LAST_SEEN_RESOLUTION_SECONDS)
+    .execute(self.pool)
+    .await?;
+    Ok(())
+  }
+
   fn compute_expires_at(&self, modified_at: i64) -> i64 {
     modified_at + self.config.ttl_seconds
   }
 
-  pub async fn issue_token(&self, payload: Value) -> Result<TokenIssue, sqlx::Error> {
-    let now = Self::now_epoch();
+  /// Absolute deadline for a session that started at `created_at`, if a cap is configured.
+  fn compute_max_expires_at(&self, created_at: i64) -> Option<i64> {
+    (self.config.max_lifetime_seconds > 0).then(|| created_at + self.config.max_lifetime_seconds)
+  }
+
+  fn past_max_lifetime(&self, created_at: i64, now: i64) -> bool {
+    self
+      .compute_max_expires_at(created_at)
+      .is_some_and(|deadline| now >= deadline)
+  }
+
+  fn new_token_value(now: i64) -> String {
     let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "local_secret".to_string());
-    let token = Self::generate_token_value(&secret, now);
-    self.insert_token(&token, &payload, now).await?;
+    Self::generate_token_value(&secret, now)
+  }
+
+  /// Public identifier for a session; random and unrelated to any token value.
+  fn new_session_id() -> String {
+    let mut random = [0u8; 16];
+    OsRng.fill_bytes(&mut random);
+    random.iter().map(|byte| format!("{:02x}", byte)).collect()
+  }
+
+  /// A single access token in a session of its own, without refresh token or session limits.
+  pub async fn issue_token(
+    &self,
+    payload: Value,
+    context: &SessionContext,
+  ) -> Result<TokenIssue, sqlx::Error> {
+    let now = Self::now_epoch();
+    let token = Self::new_token_value(now);
+    let session_id = Self::new_session_id();
+    let mut conn = self.pool.acquire().await?;
+    Self::insert_token(&mut conn, &token, &payload, now, &session_id, now, context).await?;
     Ok(TokenIssue {
       token,
       expires_at: self.compute_expires_at(now),
+      session_id,
     })
   }
 
+  async fn issue_in_session(
+    &self,
+    conn: &mut PgConnection,
+    payload: &Value,
+    session_id: &str,
+    session_started_at: i64,
+    context: &SessionContext,
+  ) -> Result<SessionIssue, sqlx::Error> {
+    let now = Self::now_epoch();
+    let token = Self::new_token_value(now);
+    let refresh_token = Self::new_token_value(now);
+    // A refresh token never outlives the session cap it was issued under.
+    let max_expires_at = self.compute_max_expires_at(session_started_at);
+    let mut refresh_expires_at = now + self.config.refresh_ttl_seconds;
+    if let Some(deadline) = max_expires_at {
+      refresh_expires_at = refresh_expires_at.min(deadline);
+    }
+    // Refreshed access tokens keep the original login time as created_at.
+    Self::insert_token(
+      conn,
+      &token,
+      payload,
+      now,
+      session_id,
+      session_started_at,
+      context,
+    )
+    .await?;
+    Self::insert_refresh_token(
+      conn,
+      &refresh_token,
+      session_id,
+      payload,
+      refresh_expires_at,
+      session_started_at,
+      context,
+    )
+    .await?;
+    Ok(SessionIssue {
+      token,
+      expires_at: self.compute_expires_at(now),
+      refresh_token,
+      refresh_expires_at,
+      max_expires_at,
+      payload: payload.clone(),
+      session_id: session_id.to_string(),
+    })
+  }
+
+  /// Start a new session: an access token and a refresh token sharing a fresh session id.
+  /// The session counts as verified only if `context.verified_at` says so.
+  pub async fn issue_session(
+    &self,
+    payload: Value,
+    context: &SessionContext,
+  ) -> Result<SessionIssue, SessionError> {
+    let now = Self::now_epoch();
+    let session_id = Self::new_session_id();
+    let mut tx = self.pool.begin().await?;
+    if let Some(user_id) = payload.get("user_id").and_then(Value::as_i64) {
+      // Concurrent logins of the same person queue here so none of them can overshoot a limit.
+      sqlx::query("SELECT pg_advisory_xact_lock(hashtext('auth.sessions'), $1)")
+        .bind(user_id as i32)
+        .execute(&mut *tx)
+        .await?;
+      if let Some(service_id) = context.service_id {
+        let service_limit = sqlx::query_scalar::<_, Option<i32>>(
+          "SELECT max_sessions FROM auth.services WHERE id = $1",
+        )
+        .bind(service_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .flatten();
+        if let Some(limit) = service_limit {
+          self
+            .enforce_session_limit(&mut tx, user_id, Some(service_id), i64::from(limit))
+            .await?;
+        }
+      }
+      self
+        .enforce_session_limit(&mut tx, user_id, None, self.config.max_sessions_per_person)
+        .await?;
+    }
+    let issue = self
+      .issue_in_session(&mut tx, &payload, &session_id, now, context)
+      .await?;
+    tx.commit().await?;
+    Ok(issue)
+  }
+
+  /// Make room for one more session of `user_id` (within `service_id`, if given),
+  /// evicting the oldest ones or refusing according to the configured policy.
+  async fn enforce_session_limit(
+    &self,
+    conn: &mut PgConnection,
+    user_id: i64,
+    service_id: Option<i32>,
+    limit: i64,
+  ) -> Result<(), SessionError> {
+    if limit <= 0 {
+      return Ok(());
+    }
+    let sessions = self.active_sessions(conn, user_id, service_id).await?;
+    let excess = sessions.len() as i64 - limit + 1;
+    if excess <= 0 {
+      return Ok(());
+    }
+    match self.config.session_limit_policy {
+      SessionLimitPolicy::Reject => Err(SessionError::LimitReached { limit }),
+      SessionLimitPolicy::EvictOldest => {
+        for session in sessions.iter().take(excess as usize) {
+          Self::delete_session(conn, &session.session_id).await?;
+        }
+        Ok(())
+      }
+    }
+  }
+
+  /// Exchange a refresh token for a new access/refresh pair in the same session.
+  /// Each refresh token works once and takes the access token issued with it along;
+  /// presenting a used one revokes the whole session.
+  pub async fn refresh_session(&self, refresh_token: &str) -> Result<SessionIssue, TokenError> {
+    let now = Self::now_epoch();
+    let token_hash = Self::hash_token(refresh_token);
+    // The claim and the new pair commit together, so a failed issue leaves the token usable.
+    let mut tx = self.pool.begin().await?;
+    let claimed = sqlx::query_as::<_, RefreshRecord>(
+      "UPDATE auth.refresh_tokens SET used_at = $1
+       WHERE token_hash = $2 AND used_at IS NULL AND expires_at > $1
+       RETURNING session_id, payload, expires_at, used_at, session_started_at, ip, user_agent, service_id, verified_at",
+    )
+    .bind(now)
+    .bind(&token_hash)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let record = match claimed {
+      Some(record) => record,
+      None => {
+        tx.rollback().await?;
+        let existing = sqlx::query_as::<_, RefreshRecord>(
+          "SELECT session_id, payload, expires_at, used_at, session_started_at, ip, user_agent, service_id, verified_at FROM auth.refresh_tokens WHERE token_hash = $1",
+        )
+        .bind(&token_hash)
+        .fetch_optional(self.pool)
+        .await?;
+        return match existing {
+          Some(record) if record.used_at.is_some() => {
+            self.revoke_session(&record.session_id).await?;
+            Err(TokenError::Reused)
+          }
+          Some(record) if record.expires_at <= now => Err(TokenError::Expired),
+          _ => Err(TokenError::NotFound),
+        };
+      }
+    };
+
+    if self.past_max_lifetime(record.session_started_at, now) {
+      tx.rollback().await?;
+      self.revoke_session(&record.session_id).await?;
+      return Err(TokenError::Expired);
+    }
+
+    // The access token issued with the old refresh token ends with it.
+    sqlx::query("DELETE FROM auth.tokens_cache WHERE session_id = $1")
+      .bind(&record.session_id)
+      .execute(&mut *tx)
+      .await?;
+    let issue = self
+      .issue_in_session(
+        &mut tx,
+        &record.payload,
+        &record.session_id,
+        record.session_started_at,
+        // The session keeps the origin, service and last verification it was opened with.
+        &SessionContext {
+          ip: record.ip,
+          user_agent: record.user_agent,
+          service_id: record.service_id,
+          verified_at: record.verified_at,
+        },
+      )
+      .await?;
+    tx.commit().await?;
+    Ok(issue)
+  }
+
+  /// Record a fresh password check on every token of a session; returns its time.
+  pub async fn mark_verified(&self, session_id: &str) -> Result<i64, sqlx::Error> {
+    let now = Self::now_epoch();
+    sqlx::query("UPDATE auth.tokens_cache SET verified_at = $1 WHERE session_id = $2")
+      .bind(now)
+      .bind(session_id)
+      .execute(self.pool)
+      .await?;
+    sqlx::query("UPDATE auth.refresh_tokens SET verified_at = $1 WHERE session_id = $2")
+      .bind(now)
+      .bind(session_id)
+      .execute(self.pool)
+      .await?;
+    Ok(now)
+  }
+
+  pub fn reauth_max_age(&self) -> i64 {
+    self.config.reauth_max_age_seconds
+  }
+
+  /// Whether the token's session checked the password within `REAUTH_MAX_AGE_SECONDS`.
+  pub fn recently_verified(&self, record: &TokenRecord) -> bool {
+    record.verified_at.is_some_and(|verified_at| {
+      Self::now_epoch() - verified_at <= self.config.reauth_max_age_seconds
+    })
+  }
+
+  /// Delete every access and refresh token that descends from the same login.
+  pub async fn revoke_session(&self, session_id: &str) -> Result<u64, sqlx::Error> {
+    let mut conn = self.pool.acquire().await?;
+    Self::delete_session(&mut conn, session_id).await
+  }
+
+  async fn delete_session(conn: &mut PgConnection, session_id: &str) -> Result<u64, sqlx::Error> {
+    let access = sqlx::query("DELETE FROM auth.tokens_cache WHERE session_id = $1")
+      .bind(session_id)
+      .execute(&mut *conn)
+      .await?
+      .rows_affected();
+    sqlx::query("DELETE FROM auth.refresh_tokens WHERE session_id = $1")
+      .bind(session_id)
+      .execute(&mut *conn)
+      .await?;
+    Ok(access)
+  }
+
+  /// Revoke one of `user_id`'s sessions; returns false when it is not theirs or does not exist.
+  pub async fn revoke_user_session(
+    &self,
+    user_id: i32,
+    session_id: &str,
+  ) -> Result<bool, sqlx::Error> {
+    let access = sqlx::query(
+      "DELETE FROM auth.tokens_cache WHERE session_id = $1 AND payload ->> 'user_id' = $2",
+    )
+    .bind(session_id)
+    .bind(user_id.to_string())
+    .execute(self.pool)
+    .await?
+    .rows_affected();
+    let refresh = sqlx::query(
+      "DELETE FROM auth.refresh_tokens WHERE session_id = $1 AND payload ->> 'user_id' = $2",
+    )
+    .bind(session_id)
+    .bind(user_id.to_string())
+    .execute(self.pool)
+    .await?
+    .rows_affected();
+    Ok(access + refresh > 0)
+  }
+
+  /// Sessions of `user_id` that can still be used, either through a live access
+  /// token or an unspent refresh token. Most recently seen first.
+  pub async fn list_sessions(&self, user_id: i32) -> Result<Vec<SessionInfo>, sqlx::Error> {
+    let mut conn = self.pool.acquire().await?;
+    let mut sessions = self
+      .active_sessions(&mut conn, i64::from(user_id), None)
+      .await?;
+    sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen_at));
+    Ok(sessions)
+  }
+
+  /// Sessions that can still be used, oldest first.
+  async fn active_sessions(
+    &self,
+    conn: &mut PgConnection,
+    user_id: i64,
+    service_id: Option<i32>,
+  ) -> Result<Vec<SessionInfo>, sqlx::Error> {
+    let now = Self::now_epoch();
+    sqlx::query_as::<_, SessionInfo>(
+      "SELECT session_id, ip, user_agent, service_id, created_at, last_seen_at FROM (
+         SELECT DISTINCT ON (session_id) session_id, ip, user_agent, service_id, created_at, last_seen_at
+         FROM (
+           SELECT session_id, ip, user_agent, service_id, created_at, last_seen_at
+           FROM auth.tokens_cache
+           WHERE payload ->> 'user_id' = $1 AND modified_at >= $2
+           UNION ALL
+           SELECT session_id, ip, user_agent, service_id, session_started_at, created_at
+           FROM auth.refresh_tokens
+           WHERE payload ->> 'user_id' = $1 AND used_at IS NULL AND expires_at > $3
+         ) live
+         WHERE $4::INTEGER IS NULL OR service_id = $4
+         ORDER BY session_id, last_seen_at DESC
+       ) sessions
+       ORDER BY created_at, session_id",
+    )
+    .bind(user_id.to_string())
+    .bind(now - self.config.ttl_seconds)
+    .bind(now)
+    .bind(service_id)
+    .fetch_all(conn)
+    .await
+  }
+
   pub async fn delete_token(&self, token: &str) -> Result<bool, sqlx::Error> {
-    let rows = sqlx::query("DELETE FROM auth.tokens_cache WHERE token = $1")
-      .bind(token)
+    let rows = sqlx::query("DELETE FROM auth.tokens_cache WHERE token_hash = $1")
+      .bind(Self::hash_token(token))
       .execute(self.pool)
       .await?
       .rows_affected();
     Ok(rows > 0)
   }
 
+  /// Whether `service_id` may introspect or revoke an access or refresh token: tokens
+  /// issued for that service (the `service_id` of a client_credentials payload, otherwise
+  /// the service its session was opened for), and tokens of sessions not tied to any
+  /// service whose person holds a role in it. False for unknown tokens.
+  pub async fn token_visible_to(&self, token: &str, service_id: i32) -> Result<bool, sqlx::Error> {
+    let visible = sqlx::query_scalar::<_, Option<bool>>(
+      "SELECT CASE
+         WHEN t.bound_service IS NOT NULL THEN t.bound_service = $2
+         ELSE EXISTS (
+           SELECT 1 FROM auth.person_service_role psr
+           WHERE psr.person_id = (t.payload->>'user_id')::INTEGER AND psr.service_id = $2
+         )
+       END
+       FROM (
+         SELECT COALESCE((payload->>'service_id')::INTEGER, service_id) AS bound_service, payload
+         FROM auth.tokens_cache WHERE token_hash = $1
+         UNION ALL
+         SELECT COALESCE((payload->>'service_id')::INTEGER, service_id), payload
+         FROM auth.refresh_tokens WHERE token_hash = $1
+         LIMIT 1
+       ) t",
+    )
+    .bind(Self::hash_token(token))
+    .bind(service_id)
+    .fetch_optional(self.pool)
+    .await?;
+    Ok(visible.flatten().unwrap_or(false))
+  }
+
+  /// Payload stored with a refresh token, whether or not it is still usable.
+  pub async fn refresh_token_payload(
+    &self,
+    refresh_token: &str,
+  ) -> Result<Option<Value>, sqlx::Error> {
+    sqlx::query_scalar::<_, Value>("SELECT payload FROM auth.refresh_tokens WHERE token_hash = $1")
+      .bind(Self::hash_token(refresh_token))
+      .fetch_optional(self.pool)
+      .await
+  }
+
+  /// Revoke the session a refresh token belongs to; false when the token is unknown.
+  pub async fn revoke_refresh_token(&self, refresh_token: &str) -> Result<bool, sqlx::Error> {
+    let session_id = sqlx::query_scalar::<_, String>(
+      "SELECT session_id FROM auth.refresh_tokens WHERE token_hash = $1",
+    )
+    .bind(Self::hash_token(refresh_token))
+    .fetch_optional(self.pool)
+    .await?;
+    match session_id {
+      Some(session_id) => {
+        self.revoke_session(&session_id).await?;
+        Ok(true)
+      }
+      None => Ok(false),
+    }
+  }
+
   pub async fn delete_tokens_for_user(&self, user_id: i32) -> Result<u64, sqlx::Error> {
     let rows = sqlx::query("DELETE FROM auth.tokens_cache WHERE payload ->> 'user_id' = $1")
       .bind(user_id.to_string())
       .execute(self.pool)
       .await?
       .rows_affected();
+    sqlx::query("DELETE FROM auth.refresh_tokens WHERE payload ->> 'user_id' = $1")
+      .bind(user_id.to_string())
+      .execute(self.pool)
+      .await?;
     Ok(rows)
   }
 
+  /// Revoke every session of `user_id` except `keep_session_id`; returns how many ended.
+  pub async fn delete_other_sessions_for_user(
+    &self,
+    user_id: i32,
+    keep_session_id: &str,
+  ) -> Result<i64, sqlx::Error> {
+    let sessions = sqlx::query_scalar::<_, i64>(
+      "WITH removed AS (
+         DELETE FROM auth.tokens_cache
+         WHERE payload ->> 'user_id' = $1 AND session_id <> $2
+         RETURNING session_id
+       )
+       SELECT COUNT(DISTINCT session_id) FROM removed",
+    )
+    .bind(user_id.to_string())
+    .bind(keep_session_id)
+    .fetch_one(self.pool)
+    .await?;
+    sqlx::query(
+      "DELETE FROM auth.refresh_tokens WHERE payload ->> 'user_id' = $1 AND session_id <> $2",
+    )
+    .bind(user_id.to_string())
+    .bind(keep_session_id)
+    .execute(self.pool)
+    .await?;
+    Ok(sessions)
+  }
+
   pub async fn cleanup_expired(&self) -> Result<u64, sqlx::Error> {
     let ttl = self.config.ttl_seconds.max(1);
     let cutoff = Self::now_epoch() - ttl;
-    let rows = sqlx::query("DELETE FROM auth.tokens_cache WHERE modified_at < $1")
+    let mut rows = sqlx::query("DELETE FROM auth.tokens_cache WHERE modified_at < $1")
       .bind(cutoff)
       .execute(self.pool)
       .await?
       .rows_affected();
+    if self.config.max_lifetime_seconds > 0 {
+      rows += sqlx::query("DELETE FROM auth.tokens_cache WHERE created_at < $1")
+        .bind(Self::now_epoch() - self.config.max_lifetime_seconds)
+        .execute(self.pool)
+        .await?
+        .rows_affected();
+    }
+    // Used refresh tokens are kept until they expire so reuse can still be detected.
+    sqlx::query("DELETE FROM auth.refresh_tokens WHERE expires_at < $1")
+      .bind(Self::now_epoch())
+      .execute(self.pool)
+      .await?;
     Ok(rows)
   }
 
@@ -191,6 +815,19 @@ impl<'a> TokenManager<'a> {
     now - modified_at >= self.config.renew_threshold_seconds
   }
 
+  /// Drop a token found expired. An impersonation session ending this way is recorded
+  /// as stopped.
+  async fn expire_token(&self, token: &str, record: &TokenRecord) {
+    let _ = self.delete_token(token).await;
+    if record.payload.get("impersonator_id").is_some()
+      && let Err(err) = ImpersonationAudit::new(self.pool)
+        .close_ended(Some(&record.session_id))
+        .await
+    {
+      eprintln!("[session-error] impersonation stop: {}", err);
+    }
+  }
+
   pub async fn validate_token(
     &self,
     token: &str,
@@ -201,8 +838,8 @@ impl<'a> TokenManager<'a> {
       None => return Err(TokenError::NotFound),
     };
     let now = Self::now_epoch();
-    if self.has_expired(record.modified_at, now) {
-      let _ = self.delete_token(token).await;
+    if self.has_expired(record.modified_at, now) || self.past_max_lifetime(record.created_at, now) {
+      self.expire_token(token, &record).await;
       return Err(TokenError::Expired);
     }
 
@@ -216,7 +853,7 @@ impl<'a> TokenManager<'a> {
         None => {
           if let Some(updated) = self.fetch_token(token).await? {
             if self.has_expired(updated.modified_at, now) {
-              let _ = self.delete_token(token).await;
+              self.expire_token(token, &updated).await;
               return Err(TokenError::Expired);
             }
             record = updated;
@@ -227,12 +864,18 @@ impl<'a> TokenManager<'a> {
       }
     }
 
+    if !renewed && let Err(err) = self.mark_seen(token, now).await {
+      eprintln!("[session-error] last_seen_at: {}", err);
+    }
+
     let expires_at = self.compute_expires_at(record.modified_at);
+    let max_expires_at = self.compute_max_expires_at(record.created_at);
 
     Ok(TokenValidation {
       record,
       renewed,
       expires_at,
+      max_expires_at,
     })
   }
 }
@@ -0,0 +1,203 @@
+use std::env;
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::num::NonZeroUsize;
+use std::thread;
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+  pub host: String,
+  pub port: u16,
+  pub workers: usize,
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+  key: &'static str,
+  value: String,
+  expected: &'static str,
+}
+
+impl ConfigError {
+  pub(crate) fn new(key: &'static str, value: String, expected: &'static str) -> Self {
+    Self {
+      key,
+      value,
+      expected,
+    }
+  }
+
+  /// A required setting that is unset or empty.
+  pub(crate) fn missing(key: &'static str, expected: &'static str) -> Self {
+    Self::new(key, String::new(), expected)
+  }
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.value.is_empty() {
+      return write!(f, "missing {}: expected {}", self.key, self.expected);
+    }
+    write!(
+      f,
+      "invalid {}={:?}: expected {}",
+      self.key, self.value, self.expected
+    )
+  }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Whether `APP_ENV=development`; only then may unset secrets fall back to a built-in one.
+pub fn development() -> bool {
+  env::var("APP_ENV").is_ok_and(|value| value.trim().eq_ignore_ascii_case("development"))
+}
+
+/// Passphrase of an encryption key: `key`, else `JWT_SECRET`. Outside development one of
+/// them must be set.
+pub(crate) fn secret(key: &'static str) -> Result<String, ConfigError> {
+  env::var(key)
+    .or_else(|_| env::var("JWT_SECRET"))
+    .ok()
+    .filter(|value| !value.is_empty())
+    .or_else(|| development().then(|| "local_secret".to_string()))
+    .ok_or_else(|| {
+      ConfigError::missing(key, "a secret (or JWT_SECRET), unless APP_ENV=development")
+    })
+}
+
+impl ServerConfig {
+  /// Read `SERVER_HOST`, `SERVER_PORT` and `SERVER_WORKERS`.
+  /// Unset values use defaults; values that are set but invalid are an error.
+  pub fn load() -> Result<Self, ConfigError> {
+    Self::from_vars(|key| env::var(key).ok())
+  }
+
+  fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+    let host = match var("SERVER_HOST") {
+      Some(value) => {
+        let trimmed = value.trim();
+        if trimmed.is_empty() || trimmed.contains(char::is_whitespace) {
+          return Err(ConfigError {
+            key: "SERVER_HOST",
+            value,
+            expected: "a hostname or IP address",
+          });
+        }
+        trimmed.to_string()
+      }
+      None => "127.0.0.1".to_string(),
+    };
+
+    let port = match var("SERVER_PORT") {
+      Some(value) => match value.trim().parse::<u16>() {
+        Ok(port) if port > 0 => port,
+        _ => {
+          return Err(ConfigError {
+            key: "SERVER_PORT",
+            value,
+            expected: "a port number between 1 and 65535",
+          });
+        }
+      },
+      None => 7878,
+    };
+
+    let workers = match var("SERVER_WORKERS") {
+      Some(value) => match value.trim().parse::<usize>() {
+        Ok(workers) if workers > 0 => workers,
+        _ => {
+          return Err(ConfigError {
+            key: "SERVER_WORKERS",
+            value,
+            expected: "a positive number of worker threads",
+          });
+        }
+      },
+      None => thread::available_parallelism()
+        .map(NonZeroUsize::get)
+        .unwrap_or(1),
+    };
+
+    Ok(Self {
+      host,
+      port,
+      workers,
+    })
+  }
+
+  pub fn bind_address(&self) -> String {
+    if self.host.parse::<Ipv6Addr>().is_ok() {
+      format!("[{}]:{}", self.host, self.port)
+    } else {
+      format!("{}:{}", self.host, self.port)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn load_with(vars: &[(&str, &str)]) -> Result<ServerConfig, ConfigError> {
+    ServerConfig::from_vars(|key| {
+      vars
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| value.to_string())
+    })
+  }
+
+  #[test]
+  fn defaults_when_unset() {
+    let config = load_with(&[]).unwrap();
+    assert_eq!(config.host, "127.0.0.1");
+    assert_eq!(config.port, 7878);
+    assert!(config.workers >= 1);
+    assert_eq!(config.bind_address(), "127.0.0.1:7878");
+  }
+
+  #[test]
+  fn valid_overrides() {
+    let config = load_with(&[
+      ("SERVER_HOST", " 0.0.0.0 "),
+      ("SERVER_PORT", "8080"),
+      ("SERVER_WORKERS", "3"),
+    ])
+    .unwrap();
+    assert_eq!(config.host, "0.0.0.0");
+    assert_eq!(config.port, 8080);
+    assert_eq!(config.workers, 3);
+    assert_eq!(config.bind_address(), "0.0.0.0:8080");
+  }
+
+  #[test]
+  fn ipv6_host_is_bracketed() {
+    let config = load_with(&[("SERVER_HOST", "::1"), ("SERVER_PORT", "9000")]).unwrap();
+    assert_eq!(config.bind_address(), "[::1]:9000");
+  }
+
+  #[test]
+  fn rejects_invalid_port() {
+    for value in ["0", "65536", "-1", "http", ""] {
+      let err = load_with(&[("SERVER_PORT", value)]).unwrap_err();
+      assert_eq!(err.key, "SERVER_PORT", "value {:?}", value);
+    }
+  }
+
+  #[test]
+  fn rejects_invalid_workers() {
+    for value in ["0", "-2", "many", ""] {
+      let err = load_with(&[("SERVER_WORKERS", value)]).unwrap_err();
+      assert_eq!(err.key, "SERVER_WORKERS", "value {:?}", value);
+    }
+  }
+
+  #[test]
+  fn rejects_invalid_host() {
+    for value in ["", "  ", "local host"] {
+      let err = load_with(&[("SERVER_HOST", value)]).unwrap_err();
+      assert_eq!(err.key, "SERVER_HOST", "value {:?}", value);
+    }
+  }
+}
@@ -1,5 +1,8 @@
 use sqlx::{Pool, Postgres, postgres::PgPoolOptions};
 use std::env;
+use tokio::sync::OnceCell;
+
+static SHARED: OnceCell<DB> = OnceCell::const_new();
 
 #[derive(Clone)]
 pub struct DB {
@@ -27,6 +30,12 @@ impl DB {
     Ok(Self { pool })
   }
 
+  /// Process-wide pool, created once and shared by every handler and background job.
+  /// Cloning a `DB` only clones the pool handle, not the connections.
+  pub async fn shared() -> Result<Self, sqlx::Error> {
+    SHARED.get_or_try_init(DB::new).await.cloned()
+  }
+
   pub fn pool(&self) -> &Pool<Postgres> {
     &self.pool
   }
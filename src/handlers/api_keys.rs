@@ -0,0 +1,125 @@
+use crate::api_keys::ApiKeyManager;
+use httpageboy::{Request, Response, StatusCode};
+
+use super::{error_response, require_permission};
+
+fn parse_service_id(req: &Request) -> Result<i32, Response> {
+  req
+    .params
+    .get("id")
+    .and_then(|s| s.parse().ok())
+    .ok_or_else(|| error_response(StatusCode::BadRequest, "Invalid service ID"))
+}
+
+fn parse_key_id(req: &Request) -> Result<i32, Response> {
+  req
+    .params
+    .get("key_id")
+    .and_then(|s| s.parse().ok())
+    .ok_or_else(|| error_response(StatusCode::BadRequest, "Invalid API key ID"))
+}
+
+pub async fn list_api_keys(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "services.read").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let service_id = match parse_service_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match ApiKeyManager::new(db.pool()).list(service_id).await {
+    Ok(keys) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&keys).unwrap(),
+    },
+    Err(err) => {
+      eprintln!("[handler-error] list_api_keys: {}", err);
+      error_response(StatusCode::InternalServerError, "Failed to fetch API keys")
+    }
+  }
+}
+
+pub async fn issue_api_key(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "services.write").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let service_id = match parse_service_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match ApiKeyManager::new(db.pool()).issue(service_id).await {
+    Ok(Some(issued)) => Response {
+      status: StatusCode::Created.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&issued).unwrap(),
+    },
+    Ok(None) => error_response(StatusCode::NotFound, "Service not found"),
+    Err(err) => {
+      eprintln!("[handler-error] issue_api_key: {}", err);
+      error_response(StatusCode::InternalServerError, "Failed to issue API key")
+    }
+  }
+}
+
+pub async fn rotate_api_key(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "services.write").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let service_id = match parse_service_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  let key_id = match parse_key_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match ApiKeyManager::new(db.pool())
+    .rotate(service_id, key_id)
+    .await
+  {
+    Ok(Some(issued)) => Response {
+      status: StatusCode::Created.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&issued).unwrap(),
+    },
+    Ok(None) => error_response(StatusCode::NotFound, "API key not found"),
+    Err(err) => {
+      eprintln!("[handler-error] rotate_api_key: {}", err);
+      error_response(StatusCode::InternalServerError, "Failed to rotate API key")
+    }
+  }
+}
+
+pub async fn revoke_api_key(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "services.write").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let service_id = match parse_service_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  let key_id = match parse_key_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match ApiKeyManager::new(db.pool())
+    .revoke(service_id, key_id)
+    .await
+  {
+    Ok(true) => Response {
+      status: StatusCode::NoContent.to_string(),
+      content_type: "application/json".to_string(),
+      content: Vec::new(),
+    },
+    Ok(false) => error_response(StatusCode::NotFound, "API key not found"),
+    Err(err) => {
+      eprintln!("[handler-error] revoke_api_key: {}", err);
+      error_response(StatusCode::InternalServerError, "Failed to revoke API key")
+    }
+  }
+}
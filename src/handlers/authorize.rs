@@ -0,0 +1,364 @@
+use crate::database::DB;
+use crate::oauth::{AuthorizationCode, ConsentRequest, OAuthClient, OAuthClientManager};
+use httpageboy::{Request, Response, StatusCode};
+
+use super::get_db_connection;
+use super::oauth::{form_decode, granted_scopes, parse_form};
+use super::totp::verify_second_factor;
+use super::users::verify_login;
+
+/// Parameters of an authorization request (RFC 6749 §4.1.1, RFC 7636 §4.3), read from
+/// the query string of `GET /oauth/authorize` or the form posted back by the login page.
+struct AuthorizeParams {
+  response_type: String,
+  client_id: String,
+  redirect_uri: String,
+  scope: String,
+  state: Option<String>,
+  code_challenge: String,
+  code_challenge_method: String,
+  nonce: Option<String>,
+}
+
+impl AuthorizeParams {
+  fn consent_request(&self) -> ConsentRequest<'_> {
+    ConsentRequest {
+      client_id: &self.client_id,
+      redirect_uri: &self.redirect_uri,
+      code_challenge: &self.code_challenge,
+    }
+  }
+
+  fn from_lookup(get: impl Fn(&str) -> Option<String>) -> Self {
+    let field = |name: &str| get(name).unwrap_or_default();
+    Self {
+      response_type: field("response_type"),
+      client_id: field("client_id"),
+      redirect_uri: field("redirect_uri"),
+      scope: field("scope"),
+      state: get("state").filter(|state| !state.is_empty()),
+      code_challenge: field("code_challenge"),
+      code_challenge_method: field("code_challenge_method"),
+      nonce: get("nonce").filter(|nonce| !nonce.is_empty()),
+    }
+  }
+}
+
+/// Why an authorization request cannot go ahead.
+enum AuthorizeError {
+  /// The client or redirect URI cannot be trusted, so the error is shown to the user
+  /// instead of being sent to the redirect URI (RFC 6749 §4.1.2.1).
+  Page(StatusCode, &'static str),
+  Redirect {
+    error: &'static str,
+    description: &'static str,
+  },
+}
+
+fn escape_html(value: &str) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for c in value.chars() {
+    match c {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '"' => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&#39;"),
+      _ => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+fn html_response(status: StatusCode, title: &str, body: &str) -> Response {
+  Response {
+    status: status.to_string(),
+    content_type: "text/html; charset=utf-8".to_string(),
+    content: format!(
+      "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width\"><title>{}</title></head>\n<body style=\"font-family:sans-serif;max-width:24em;margin:3em auto\">\n{}\n</body></html>\n",
+      escape_html(title),
+      body
+    )
+    .into_bytes(),
+  }
+}
+
+fn error_page(status: StatusCode, message: &str) -> Response {
+  html_response(
+    status,
+    "Authorization error",
+    &format!(
+      "<h1>Authorization error</h1>\n<p>{}</p>",
+      escape_html(message)
+    ),
+  )
+}
+
+/// Send the browser back to the client. The server cannot set a `Location` header, so
+/// the redirect is done by the page itself.
+fn redirect_page(redirect_uri: &str, params: &[(&str, &str)], state: Option<&str>) -> Response {
+  let mut query = form_urlencoded::Serializer::new(String::new());
+  for (name, value) in params {
+    query.append_pair(name, value);
+  }
+  if let Some(state) = state {
+    query.append_pair("state", state);
+  }
+  let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+  let target = escape_html(&format!("{}{}{}", redirect_uri, separator, query.finish()));
+  Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "text/html; charset=utf-8".to_string(),
+    content: format!(
+      "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"0;url={0}\"><title>Redirecting</title></head>\n<body><p><a href=\"{0}\">Continue</a></p></body></html>\n",
+      target
+    )
+    .into_bytes(),
+  }
+}
+
+fn redirect_error(params: &AuthorizeParams, error: &str, description: &str) -> Response {
+  redirect_page(
+    &params.redirect_uri,
+    &[("error", error), ("error_description", description)],
+    params.state.as_deref(),
+  )
+}
+
+/// The code challenge is the base64url SHA-256 of a 43-128 character verifier.
+fn is_valid_code_challenge(challenge: &str) -> bool {
+  challenge.len() == 43
+    && challenge
+      .bytes()
+      .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_'))
+}
+
+async fn validate_request(
+  db: &DB,
+  params: &AuthorizeParams,
+) -> Result<(OAuthClient, String), AuthorizeError> {
+  let clients = OAuthClientManager::new(db.pool());
+  let client = match clients.find(&params.client_id).await {
+    Ok(Some(client)) => client,
+    Ok(None) => {
+      return Err(AuthorizeError::Page(
+        StatusCode::BadRequest,
+        "Unknown client",
+      ));
+    }
+    Err(err) => {
+      eprintln!("[handler-error] oauth_authorize: {}", err);
+      return Err(AuthorizeError::Page(
+        StatusCode::InternalServerError,
+        "Failed to load client",
+      ));
+    }
+  };
+  match clients
+    .is_redirect_registered(client.service_id, &params.redirect_uri)
+    .await
+  {
+    Ok(true) => {}
+    Ok(false) => {
+      return Err(AuthorizeError::Page(
+        StatusCode::BadRequest,
+        "Redirect URI is not registered for this client",
+      ));
+    }
+    Err(err) => {
+      eprintln!("[handler-error] oauth_authorize: {}", err);
+      return Err(AuthorizeError::Page(
+        StatusCode::InternalServerError,
+        "Failed to check redirect URI",
+      ));
+    }
+  }
+
+  if params.response_type != "code" {
+    return Err(AuthorizeError::Redirect {
+      error: "unsupported_response_type",
+      description: "Only response_type=code is supported",
+    });
+  }
+  if params.code_challenge_method != "S256" || !is_valid_code_challenge(&params.code_challenge) {
+    return Err(AuthorizeError::Redirect {
+      error: "invalid_request",
+      description: "A PKCE code_challenge with code_challenge_method=S256 is required",
+    });
+  }
+  let scope = match granted_scopes(&client, Some(&params.scope)) {
+    Some(scopes) => scopes.join(" "),
+    None => {
+      return Err(AuthorizeError::Redirect {
+        error: "invalid_scope",
+        description: "Requested scope exceeds the client's scopes",
+      });
+    }
+  };
+  Ok((client, scope))
+}
+
+/// The login form. Every rendering carries a fresh consent token, so a form posted to
+/// `/oauth/authorize` must match a request this server recently showed a page for.
+fn consent_page(
+  status: StatusCode,
+  db: &DB,
+  params: &AuthorizeParams,
+  client: &OAuthClient,
+  scope: &str,
+  error: Option<&str>,
+) -> Response {
+  let consent_token = OAuthClientManager::new(db.pool()).consent_token(&params.consent_request());
+  let hidden = [
+    ("response_type", params.response_type.as_str()),
+    ("client_id", params.client_id.as_str()),
+    ("redirect_uri", params.redirect_uri.as_str()),
+    ("scope", scope),
+    ("state", params.state.as_deref().unwrap_or_default()),
+    ("code_challenge", params.code_challenge.as_str()),
+    (
+      "code_challenge_method",
+      params.code_challenge_method.as_str(),
+    ),
+    ("nonce", params.nonce.as_deref().unwrap_or_default()),
+    ("consent_token", consent_token.as_str()),
+  ]
+  .iter()
+  .map(|(name, value)| {
+    format!(
+      "<input type=\"hidden\" name=\"{}\" value=\"{}\">",
+      name,
+      escape_html(value)
+    )
+  })
+  .collect::<Vec<_>>()
+  .join("\n");
+  let scopes = if scope.is_empty() {
+    "<p>It asks for no extra permissions.</p>".to_string()
+  } else {
+    format!(
+      "<p>It asks for:</p>\n<ul>{}</ul>",
+      scope
+        .split(' ')
+        .map(|item| format!("<li>{}</li>", escape_html(item)))
+        .collect::<String>()
+    )
+  };
+  let error = error
+    .map(|message| {
+      format!(
+        "<p role=\"alert\" style=\"color:#b00\">{}</p>\n",
+        escape_html(message)
+      )
+    })
+    .unwrap_or_default();
+  html_response(
+    status,
+    "Sign in",
+    &format!(
+      "<h1>Sign in</h1>\n<p><strong>{}</strong> wants to use your account.</p>\n{}\n{}<form method=\"post\" action=\"/oauth/authorize\">\n{}\n<p><label>Username<br><input name=\"username\" autocomplete=\"username\" required></label></p>\n<p><label>Password<br><input name=\"password\" type=\"password\" autocomplete=\"current-password\" required></label></p>\n<p><label>Authenticator code (if enabled)<br><input name=\"code\" inputmode=\"numeric\" autocomplete=\"one-time-code\"></label></p>\n<p><button name=\"decision\" value=\"allow\">Allow</button> <button name=\"decision\" value=\"deny\" formnovalidate>Deny</button></p>\n</form>",
+      escape_html(&client.name),
+      scopes,
+      error,
+      hidden
+    ),
+  )
+}
+
+pub async fn oauth_authorize_page(req: &Request) -> Response {
+  let params =
+    AuthorizeParams::from_lookup(|name| req.params.get(name).map(|value| form_decode(value)));
+  let db = match get_db_connection().await {
+    Ok(db) => db,
+    Err(_) => return error_page(StatusCode::InternalServerError, "Service unavailable"),
+  };
+  match validate_request(&db, &params).await {
+    Ok((client, scope)) => consent_page(StatusCode::Ok, &db, &params, &client, &scope, None),
+    Err(AuthorizeError::Page(status, message)) => error_page(status, message),
+    Err(AuthorizeError::Redirect { error, description }) => {
+      redirect_error(&params, error, description)
+    }
+  }
+}
+
+pub async fn oauth_authorize(req: &Request) -> Response {
+  let form = parse_form(&req.body);
+  let params = AuthorizeParams::from_lookup(|name| form.get(name).cloned());
+  let db = match get_db_connection().await {
+    Ok(db) => db,
+    Err(_) => return error_page(StatusCode::InternalServerError, "Service unavailable"),
+  };
+  let (client, scope) = match validate_request(&db, &params).await {
+    Ok(validated) => validated,
+    Err(AuthorizeError::Page(status, message)) => return error_page(status, message),
+    Err(AuthorizeError::Redirect { error, description }) => {
+      return redirect_error(&params, error, description);
+    }
+  };
+  let consent_token = form
+    .get("consent_token")
+    .map(String::as_str)
+    .unwrap_or_default();
+  if !OAuthClientManager::new(db.pool())
+    .verify_consent_token(consent_token, &params.consent_request())
+  {
+    return error_page(
+      StatusCode::Forbidden,
+      "This sign-in form has expired or was not issued by this server. Please start again.",
+    );
+  }
+  if form.get("decision").map(String::as_str) != Some("allow") {
+    return redirect_error(&params, "access_denied", "The user denied the request");
+  }
+
+  let username = form.get("username").map(String::as_str).unwrap_or_default();
+  let password = form.get("password").map(String::as_str).unwrap_or_default();
+  let user = match verify_login(&db, req, username, password).await {
+    Ok(user) => user,
+    Err(failure) => {
+      return consent_page(
+        failure.status(),
+        &db,
+        &params,
+        &client,
+        &scope,
+        Some(failure.message()),
+      );
+    }
+  };
+  let code = form.get("code").map(String::as_str);
+  if let Err(failure) = verify_second_factor(&db, req, user.id, code).await {
+    return consent_page(
+      failure.status(),
+      &db,
+      &params,
+      &client,
+      &scope,
+      Some(failure.message()),
+    );
+  }
+
+  let grant = AuthorizationCode {
+    client_id: client.client_id.clone(),
+    person_id: user.id,
+    redirect_uri: params.redirect_uri.clone(),
+    scope,
+    code_challenge: params.code_challenge.clone(),
+    nonce: params.nonce.clone(),
+  };
+  match OAuthClientManager::new(db.pool()).create_code(&grant).await {
+    Ok(code) => redirect_page(
+      &params.redirect_uri,
+      &[("code", code.as_str())],
+      params.state.as_deref(),
+    ),
+    Err(err) => {
+      eprintln!("[handler-error] oauth_authorize: {}", err);
+      redirect_error(
+        &params,
+        "server_error",
+        "Failed to issue authorization code",
+      )
+    }
+  }
+}
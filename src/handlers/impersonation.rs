@@ -0,0 +1,224 @@
+use crate::auth::{SessionContext, TokenManager};
+use crate::database::DB;
+use crate::impersonation::{ImpersonationAudit, ImpersonationRecord};
+use httpageboy::{Request, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use super::{
+  error_response, extract_ip, extract_user_agent, log_access, require_permission,
+  with_auth_no_renew,
+};
+
+/// Claim naming the administrator behind an impersonation token.
+pub(super) const IMPERSONATOR_CLAIM: &str = "impersonator_id";
+
+pub(super) fn impersonator_id(payload: &Value) -> Option<i32> {
+  payload
+    .get(IMPERSONATOR_CLAIM)
+    .and_then(|value| value.as_i64())
+    .map(|id| id as i32)
+}
+
+/// Record the end of the impersonation session a token belongs to. Failing to record
+/// it is logged but does not undo the revocation.
+pub(super) async fn record_impersonation_stop(
+  db: &DB,
+  req: &Request,
+  payload: &Value,
+  session_id: &str,
+) {
+  let (Some(impersonator_id), Some(person_id)) = (
+    impersonator_id(payload),
+    payload.get("user_id").and_then(|value| value.as_i64()),
+  ) else {
+    return;
+  };
+  let ip = extract_ip(req);
+  let user_agent = extract_user_agent(req);
+  let record = ImpersonationRecord {
+    impersonator_id,
+    person_id: person_id as i32,
+    session_id,
+    reason: None,
+    ip: Some(ip.as_str()).filter(|ip| *ip != "unknown"),
+    user_agent: user_agent.as_deref(),
+  };
+  if let Err(err) = ImpersonationAudit::new(db.pool())
+    .record_stop(&record)
+    .await
+  {
+    eprintln!("[handler-error] record_impersonation_stop: {}", err);
+  }
+}
+
+/// Record the stop of impersonation sessions that ended because their tokens were
+/// revoked along with others: `session_id` only, or every session left without a token.
+pub(super) async fn close_ended_impersonations(db: &DB, session_id: Option<&str>) {
+  if let Err(err) = ImpersonationAudit::new(db.pool())
+    .close_ended(session_id)
+    .await
+  {
+    eprintln!("[handler-error] close_ended_impersonations: {}", err);
+  }
+}
+
+#[derive(Deserialize)]
+pub struct ImpersonatePayload {
+  reason: String,
+}
+
+pub async fn start_impersonation(req: &Request) -> Response {
+  let (db, validation, _) = match require_permission(req, "users.impersonate").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let person_id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return error_response(StatusCode::BadRequest, "Invalid user ID"),
+  };
+  let payload: ImpersonatePayload = match serde_json::from_str(&req.body) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "Invalid request body"),
+  };
+  let reason = payload.reason.trim();
+  if reason.is_empty() {
+    return error_response(StatusCode::BadRequest, "A reason is required");
+  }
+  let admin = &validation.record.payload;
+  let admin_id = match admin.get("user_id").and_then(|value| value.as_i64()) {
+    Some(id) => id as i32,
+    None => return error_response(StatusCode::Forbidden, "Token has no user"),
+  };
+  if admin_id == person_id {
+    return error_response(StatusCode::BadRequest, "Cannot impersonate yourself");
+  }
+
+  let person = match sqlx::query_as::<_, (String, String)>(
+    "SELECT username, name FROM auth.person WHERE id = $1 AND removed_at IS NULL",
+  )
+  .bind(person_id)
+  .fetch_optional(db.pool())
+  .await
+  {
+    Ok(Some(person)) => person,
+    Ok(None) => return error_response(StatusCode::NotFound, "User not found"),
+    Err(err) => {
+      eprintln!("[handler-error] start_impersonation: {}", err);
+      return error_response(StatusCode::InternalServerError, "Failed to fetch user");
+    }
+  };
+  // `act` follows RFC 8693 §4.1; `impersonator_id` is the flat form for simple checks.
+  let token_payload = json!({
+    "user_id": person_id,
+    "username": person.0,
+    "name": person.1,
+    "act": {
+      "sub": admin_id.to_string(),
+      "user_id": admin_id,
+      "username": admin.get("username"),
+    },
+    IMPERSONATOR_CLAIM: admin_id,
+  });
+
+  let context = SessionContext {
+    ip: Some(extract_ip(req)).filter(|ip| ip != "unknown"),
+    user_agent: extract_user_agent(req),
+    service_id: None,
+    verified_at: None,
+  };
+  let manager = TokenManager::new(db.pool());
+  let issued = match manager.issue_token(token_payload.clone(), &context).await {
+    Ok(issue) => issue,
+    Err(err) => {
+      eprintln!("[handler-error] start_impersonation: {}", err);
+      return error_response(StatusCode::InternalServerError, "Failed to issue token");
+    }
+  };
+  let record = ImpersonationRecord {
+    impersonator_id: admin_id,
+    person_id,
+    session_id: &issued.session_id,
+    reason: Some(reason),
+    ip: context.ip.as_deref(),
+    user_agent: context.user_agent.as_deref(),
+  };
+  // No impersonation without its audit record.
+  if let Err(err) = ImpersonationAudit::new(db.pool())
+    .record_start(&record)
+    .await
+  {
+    eprintln!("[handler-error] start_impersonation: {}", err);
+    let _ = manager.revoke_session(&issued.session_id).await;
+    return error_response(
+      StatusCode::InternalServerError,
+      "Failed to record impersonation",
+    );
+  }
+  log_access(&issued.token, &token_payload, req, None);
+
+  Response {
+    status: StatusCode::Created.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({
+      "token": issued.token,
+      "expires_at": issued.expires_at,
+      "session_id": issued.session_id,
+      "payload": token_payload,
+    })
+    .to_string()
+    .into_bytes(),
+  }
+}
+
+pub async fn stop_impersonation(req: &Request) -> Response {
+  with_auth_no_renew(req, |_req, db, validation, _token| async move {
+    let payload = &validation.record.payload;
+    if impersonator_id(payload).is_none() {
+      return error_response(StatusCode::BadRequest, "Not an impersonation token");
+    }
+    let session_id = &validation.record.session_id;
+    if let Err(err) = TokenManager::new(db.pool())
+      .revoke_session(session_id)
+      .await
+    {
+      eprintln!("[handler-error] stop_impersonation: {}", err);
+      return error_response(StatusCode::InternalServerError, "Failed to revoke token");
+    }
+    record_impersonation_stop(&db, req, payload, session_id).await;
+    Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: json!({ "status": "stopped" }).to_string().into_bytes(),
+    }
+  })
+  .await
+}
+
+pub async fn list_impersonations(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "users.read").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let person_id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return error_response(StatusCode::BadRequest, "Invalid user ID"),
+  };
+  match ImpersonationAudit::new(db.pool())
+    .list_for_person(person_id)
+    .await
+  {
+    Ok(events) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&events).unwrap(),
+    },
+    Err(err) => {
+      eprintln!("[handler-error] list_impersonations: {}", err);
+      error_response(
+        StatusCode::InternalServerError,
+        "Failed to fetch impersonation audit",
+      )
+    }
+  }
+}
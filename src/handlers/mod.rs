@@ -1,7 +1,9 @@
+use crate::access_log::{self, AccessEntry, token_fingerprint};
+use crate::api_keys::{ApiKeyManager, ServiceCaller};
 use crate::auth::{TokenError, TokenManager, TokenValidation};
 use crate::database::DB;
 use httpageboy::{Request, Response, StatusCode};
-use serde_json::json;
+use serde_json::{Value, json};
 use std::future::Future;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -14,12 +16,23 @@ pub(super) fn error_response(status_code: StatusCode, message: &str) -> Response
   }
 }
 
-fn extract_token(req: &Request) -> Option<String> {
-  req
-    .headers
-    .iter()
-    .find(|(key, _)| key.eq_ignore_ascii_case("token"))
-    .map(|(_, value)| value.trim().to_string())
+/// Token from the `token` header, or from `Authorization: Bearer` as OAuth clients send it.
+pub(super) fn extract_token(req: &Request) -> Option<String> {
+  let header = |name: &str| {
+    req
+      .headers
+      .iter()
+      .find(|(key, _)| key.eq_ignore_ascii_case(name))
+      .map(|(_, value)| value.trim())
+  };
+  header("token")
+    .or_else(|| {
+      header("authorization")
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim())
+    })
+    .map(str::to_string)
     .filter(|value| !value.is_empty())
 }
 
@@ -27,39 +40,60 @@ pub(super) fn unauthorized_response(message: &str) -> Response {
   error_response(StatusCode::Unauthorized, message)
 }
 
-fn current_epoch() -> i64 {
+pub(super) fn current_epoch() -> i64 {
   SystemTime::now()
     .duration_since(UNIX_EPOCH)
     .unwrap_or_default()
     .as_secs() as i64
 }
 
-fn extract_ip(req: &Request) -> String {
+pub(super) fn extract_ip(req: &Request) -> String {
   for header in ["x-forwarded-for", "x-real-ip", "remote-addr"] {
     if let Some((_, value)) = req
       .headers
       .iter()
       .find(|(key, _)| key.eq_ignore_ascii_case(header))
+      && let Some(first) = value.split(',').next()
     {
-      if let Some(first) = value.split(',').next() {
-        let trimmed = first.trim();
-        if !trimmed.is_empty() {
-          return trimmed.to_string();
-        }
+      let trimmed = first.trim();
+      if !trimmed.is_empty() {
+        return trimmed.to_string();
       }
     }
   }
   "unknown".to_string()
 }
 
-pub(super) fn log_access(token: &str, req: &Request) {
-  let endpoint = req.path.as_str();
-  let ip = extract_ip(req);
-  let timestamp = current_epoch();
-  println!(
-    "[access] token={} endpoint={} ts={} ip={}",
-    token, endpoint, timestamp, ip
-  );
+pub(super) fn extract_user_agent(req: &Request) -> Option<String> {
+  req
+    .headers
+    .iter()
+    .find(|(key, _)| key.eq_ignore_ascii_case("user-agent"))
+    .map(|(_, value)| value.trim().to_string())
+    .filter(|value| !value.is_empty())
+}
+
+pub(super) fn log_access(token: &str, payload: &Value, req: &Request, service_id: Option<i32>) {
+  access_log::record(AccessEntry {
+    token_fingerprint: token_fingerprint(token),
+    user_id: payload.get("user_id").and_then(|value| value.as_i64()),
+    service_id,
+    endpoint: req.path.clone(),
+    ip: extract_ip(req),
+    ts: current_epoch(),
+  });
+}
+
+/// Access log entry for a request a service made with its API key and no token.
+pub(super) fn log_service_access(req: &Request, caller: &ServiceCaller, user_id: Option<i64>) {
+  access_log::record(AccessEntry {
+    token_fingerprint: "-".to_string(),
+    user_id,
+    service_id: Some(caller.service_id),
+    endpoint: req.path.clone(),
+    ip: extract_ip(req),
+    ts: current_epoch(),
+  });
 }
 
 async fn require_token(
@@ -70,23 +104,32 @@ async fn require_token(
     Some(value) => value,
     None => return Err(unauthorized_response("Missing token header")),
   };
-  let db = match DB::new().await {
-    Ok(db) => db,
-    Err(_) => {
-      return Err(error_response(
-        StatusCode::InternalServerError,
-        "Failed to connect to database",
-      ));
-    }
-  };
+  authenticate_token(req, token, renew, None).await
+}
+
+/// Validate a token obtained from somewhere other than the `token` header.
+/// `caller` is the service checking the token on someone's behalf, for the access log.
+pub(super) async fn authenticate_token(
+  req: &Request,
+  token: String,
+  renew: bool,
+  caller: Option<&ServiceCaller>,
+) -> Result<(DB, TokenValidation, String), Response> {
+  let db = get_db_connection().await?;
   let manager = TokenManager::new(db.pool());
   match manager.validate_token(&token, renew).await {
     Ok(validation) => {
-      log_access(&token, req);
+      log_access(
+        &token,
+        &validation.record.payload,
+        req,
+        caller.map(|caller| caller.service_id),
+      );
       Ok((db, validation, token))
     }
     Err(TokenError::NotFound) => Err(unauthorized_response("Invalid token")),
     Err(TokenError::Expired) => Err(unauthorized_response("Expired token")),
+    Err(TokenError::Reused) => Err(unauthorized_response("Invalid token")),
     Err(TokenError::Database(_)) => Err(error_response(
       StatusCode::InternalServerError,
       "Failed to validate token",
@@ -94,14 +137,125 @@ async fn require_token(
   }
 }
 
+pub(super) fn extract_api_key(req: &Request) -> Option<String> {
+  req
+    .headers
+    .iter()
+    .find(|(key, _)| key.eq_ignore_ascii_case("x-api-key"))
+    .map(|(_, value)| value.trim().to_string())
+    .filter(|value| !value.is_empty())
+}
+
+/// Resolve the `x-api-key` header of a backend service. `Ok(None)` when no key is
+/// presented; an unknown key, or one of a disabled service, is rejected.
+pub(super) async fn authenticate_service(req: &Request) -> Result<Option<ServiceCaller>, Response> {
+  let api_key = match extract_api_key(req) {
+    Some(value) => value,
+    None => return Ok(None),
+  };
+  let db = get_db_connection().await?;
+  match ApiKeyManager::new(db.pool()).authenticate(&api_key).await {
+    Ok(Some(caller)) => Ok(Some(caller)),
+    Ok(None) => Err(unauthorized_response("Invalid API key")),
+    Err(err) => {
+      eprintln!("[handler-error] authenticate_service: {}", err);
+      Err(error_response(
+        StatusCode::InternalServerError,
+        "Failed to validate API key",
+      ))
+    }
+  }
+}
+
 pub(super) async fn require_token_without_renew(
   req: &Request,
 ) -> Result<(DB, TokenValidation, String), Response> {
   require_token(req, false).await
 }
 
+/// Name of this API's own row in `auth.services`; management permissions are checked against it.
+pub(super) const AUTH_SERVICE_NAME: &str = "auth-api";
+
+fn forbidden_response(permission: &str) -> Response {
+  error_response(
+    StatusCode::Forbidden,
+    &format!("Missing permission: {}", permission),
+  )
+}
+
+pub(super) async fn require_permission(
+  req: &Request,
+  permission: &str,
+) -> Result<(DB, TokenValidation, String), Response> {
+  let (db, validation, token) = require_token_without_renew(req).await?;
+  let user_id = match validation
+    .record
+    .payload
+    .get("user_id")
+    .and_then(|value| value.as_i64())
+  {
+    Some(id) => id as i32,
+    None => return Err(forbidden_response(permission)),
+  };
+  // Impersonation tokens act as the person but never with management rights.
+  if impersonation::impersonator_id(&validation.record.payload).is_some() {
+    return Err(error_response(
+      StatusCode::Forbidden,
+      "Not allowed while impersonating",
+    ));
+  }
+  // Tokens issued to an OAuth client are limited to their granted scope.
+  if oauth::payload_client_id(&validation.record.payload).is_some() {
+    return Err(error_response(
+      StatusCode::Forbidden,
+      "Not allowed with a client token",
+    ));
+  }
+  match sqlx::query_scalar::<_, bool>(
+    "SELECT auth.check_person_permission_in_service($1, s.id, $3)
+     FROM auth.services s
+     WHERE s.name = $2 AND s.status = TRUE",
+  )
+  .bind(user_id)
+  .bind(AUTH_SERVICE_NAME)
+  .bind(permission)
+  .fetch_optional(db.pool())
+  .await
+  {
+    Ok(Some(true)) => Ok((db, validation, token)),
+    Ok(_) => Err(forbidden_response(permission)),
+    Err(_) => Err(error_response(
+      StatusCode::InternalServerError,
+      "Failed to check permission",
+    )),
+  }
+}
+
+/// Sensitive operations need a password check within `REAUTH_MAX_AGE_SECONDS`, however long
+/// the token itself has been renewed. The client answers by calling `/auth/reauthenticate`.
+pub(super) fn require_recent_verification(
+  db: &DB,
+  validation: &TokenValidation,
+) -> Result<(), Response> {
+  let manager = TokenManager::new(db.pool());
+  if manager.recently_verified(&validation.record) {
+    return Ok(());
+  }
+  Err(Response {
+    status: StatusCode::Unauthorized.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({
+      "error": "Recent authentication required",
+      "code": "reauthentication_required",
+      "max_age": manager.reauth_max_age(),
+    })
+    .to_string()
+    .into_bytes(),
+  })
+}
+
 pub(super) async fn get_db_connection() -> Result<DB, Response> {
-  match DB::new().await {
+  match DB::shared().await {
     Ok(db) => Ok(db),
     Err(_) => Err(error_response(
       StatusCode::InternalServerError,
@@ -129,14 +283,32 @@ where
   with_auth(req, false, action).await
 }
 
+mod api_keys;
+mod authorize;
+mod impersonation;
+mod oauth;
+mod oidc;
+mod password_reset;
 mod permissions;
 mod relations;
 mod roles;
+mod security_events;
 mod services;
+mod sessions;
+mod totp;
 mod users;
 
+pub use api_keys::*;
+pub use authorize::*;
+pub use impersonation::*;
+pub use oauth::*;
+pub use oidc::*;
+pub use password_reset::*;
 pub use permissions::*;
 pub use relations::*;
 pub use roles::*;
+pub use security_events::*;
 pub use services::*;
+pub use sessions::*;
+pub use totp::*;
 pub use users::*;
@@ -0,0 +1,809 @@
+use crate::api_keys::ApiKeyManager;
+use crate::auth::{SessionContext, SessionError, TokenError, TokenManager};
+use crate::database::DB;
+use crate::oauth::{
+  OAuthClient, OAuthClientManager, is_valid_redirect_uri, is_valid_scope, verify_pkce,
+};
+use crate::oidc::{IdTokenRequest, SigningKeys};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use httpageboy::{Request, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+
+use super::impersonation::close_ended_impersonations;
+use super::{
+  error_response, extract_api_key, extract_ip, extract_user_agent, get_db_connection, log_access,
+  require_permission,
+};
+
+fn parse_service_id(req: &Request) -> Result<i32, Response> {
+  req
+    .params
+    .get("id")
+    .and_then(|s| s.parse().ok())
+    .ok_or_else(|| error_response(StatusCode::BadRequest, "Invalid service ID"))
+}
+
+fn parse_client_id(req: &Request) -> Result<String, Response> {
+  req
+    .params
+    .get("client_id")
+    .filter(|id| !id.is_empty())
+    .cloned()
+    .ok_or_else(|| error_response(StatusCode::BadRequest, "Invalid client ID"))
+}
+
+#[derive(Deserialize)]
+pub struct RegisterClientPayload {
+  name: String,
+  #[serde(default)]
+  scopes: Vec<String>,
+  /// Browser and mobile apps that cannot keep a secret.
+  #[serde(default)]
+  public: bool,
+}
+
+pub async fn list_oauth_clients(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "services.read").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let service_id = match parse_service_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match OAuthClientManager::new(db.pool()).list(service_id).await {
+    Ok(clients) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&clients).unwrap(),
+    },
+    Err(err) => {
+      eprintln!("[handler-error] list_oauth_clients: {}", err);
+      error_response(
+        StatusCode::InternalServerError,
+        "Failed to fetch OAuth clients",
+      )
+    }
+  }
+}
+
+pub async fn register_oauth_client(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "services.write").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let service_id = match parse_service_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  let payload: RegisterClientPayload = match serde_json::from_str(&req.body) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "Invalid request body"),
+  };
+  let name = payload.name.trim();
+  if name.is_empty() {
+    return error_response(StatusCode::BadRequest, "Invalid client name");
+  }
+  if !payload.scopes.iter().all(|scope| is_valid_scope(scope)) {
+    return error_response(StatusCode::BadRequest, "Invalid scope");
+  }
+  let mut scopes = payload.scopes;
+  scopes.sort();
+  scopes.dedup();
+  match OAuthClientManager::new(db.pool())
+    .register(service_id, name, &scopes, payload.public)
+    .await
+  {
+    Ok(Some(issued)) => Response {
+      status: StatusCode::Created.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&issued).unwrap(),
+    },
+    Ok(None) => error_response(StatusCode::NotFound, "Service not found"),
+    Err(err) => {
+      eprintln!("[handler-error] register_oauth_client: {}", err);
+      error_response(
+        StatusCode::InternalServerError,
+        "Failed to register OAuth client",
+      )
+    }
+  }
+}
+
+pub async fn rotate_oauth_client_secret(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "services.write").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let service_id = match parse_service_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  let client_id = match parse_client_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match OAuthClientManager::new(db.pool())
+    .rotate_secret(service_id, &client_id)
+    .await
+  {
+    Ok(Some(issued)) => Response {
+      status: StatusCode::Created.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&issued).unwrap(),
+    },
+    Ok(None) => error_response(StatusCode::NotFound, "OAuth client not found"),
+    Err(err) => {
+      eprintln!("[handler-error] rotate_oauth_client_secret: {}", err);
+      error_response(
+        StatusCode::InternalServerError,
+        "Failed to rotate client secret",
+      )
+    }
+  }
+}
+
+pub async fn delete_oauth_client(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "services.write").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let service_id = match parse_service_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  let client_id = match parse_client_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match OAuthClientManager::new(db.pool())
+    .delete(service_id, &client_id)
+    .await
+  {
+    Ok(true) => Response {
+      status: StatusCode::NoContent.to_string(),
+      content_type: "application/json".to_string(),
+      content: Vec::new(),
+    },
+    Ok(false) => error_response(StatusCode::NotFound, "OAuth client not found"),
+    Err(err) => {
+      eprintln!("[handler-error] delete_oauth_client: {}", err);
+      error_response(
+        StatusCode::InternalServerError,
+        "Failed to delete OAuth client",
+      )
+    }
+  }
+}
+
+#[derive(Deserialize)]
+pub struct RedirectUriPayload {
+  redirect_uri: String,
+}
+
+pub async fn list_redirect_uris(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "services.read").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let service_id = match parse_service_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match OAuthClientManager::new(db.pool())
+    .list_redirect_uris(service_id)
+    .await
+  {
+    Ok(uris) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&uris).unwrap(),
+    },
+    Err(err) => {
+      eprintln!("[handler-error] list_redirect_uris: {}", err);
+      error_response(
+        StatusCode::InternalServerError,
+        "Failed to fetch redirect URIs",
+      )
+    }
+  }
+}
+
+pub async fn add_redirect_uri(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "services.write").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let service_id = match parse_service_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  let payload: RedirectUriPayload = match serde_json::from_str(&req.body) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "Invalid request body"),
+  };
+  if !is_valid_redirect_uri(&payload.redirect_uri) {
+    return error_response(StatusCode::BadRequest, "Invalid redirect URI");
+  }
+  match OAuthClientManager::new(db.pool())
+    .add_redirect_uri(service_id, &payload.redirect_uri)
+    .await
+  {
+    Ok(Some(uri)) => Response {
+      status: StatusCode::Created.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&uri).unwrap(),
+    },
+    Ok(None) => error_response(StatusCode::NotFound, "Service not found"),
+    Err(err) => {
+      eprintln!("[handler-error] add_redirect_uri: {}", err);
+      error_response(
+        StatusCode::InternalServerError,
+        "Failed to add redirect URI",
+      )
+    }
+  }
+}
+
+pub async fn remove_redirect_uri(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "services.write").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let service_id = match parse_service_id(req) {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  let uri_id: i32 = match req.params.get("uri_id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return error_response(StatusCode::BadRequest, "Invalid redirect URI ID"),
+  };
+  match OAuthClientManager::new(db.pool())
+    .remove_redirect_uri(service_id, uri_id)
+    .await
+  {
+    Ok(true) => Response {
+      status: StatusCode::NoContent.to_string(),
+      content_type: "application/json".to_string(),
+      content: Vec::new(),
+    },
+    Ok(false) => error_response(StatusCode::NotFound, "Redirect URI not found"),
+    Err(err) => {
+      eprintln!("[handler-error] remove_redirect_uri: {}", err);
+      error_response(
+        StatusCode::InternalServerError,
+        "Failed to remove redirect URI",
+      )
+    }
+  }
+}
+
+/// Error body of the token endpoint, using the codes of RFC 6749 §5.2.
+pub(super) fn oauth_error(status: StatusCode, error: &str, description: &str) -> Response {
+  Response {
+    status: status.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({ "error": error, "error_description": description })
+      .to_string()
+      .into_bytes(),
+  }
+}
+
+pub(super) fn parse_form(body: &str) -> HashMap<String, String> {
+  form_urlencoded::parse(body.as_bytes())
+    .into_owned()
+    .collect()
+}
+
+/// Decode one `application/x-www-form-urlencoded` value (`+` and percent escapes).
+pub(super) fn form_decode(value: &str) -> String {
+  form_urlencoded::parse(format!("v={}", value).as_bytes())
+    .next()
+    .map(|(_, v)| v.into_owned())
+    .unwrap_or_default()
+}
+
+/// Client id and secret from `Authorization: Basic` (RFC 6749 §2.3.1) or, failing that,
+/// from `client_id`/`client_secret` in the form body. Public clients send no secret.
+pub(super) fn client_credentials(
+  req: &Request,
+  form: &HashMap<String, String>,
+) -> Option<(String, Option<String>)> {
+  let basic = req
+    .headers
+    .iter()
+    .find(|(key, _)| key.eq_ignore_ascii_case("authorization"))
+    .and_then(|(_, value)| {
+      let (scheme, encoded) = value.trim().split_once(' ')?;
+      if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+      }
+      let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+      let (id, secret) = decoded.split_once(':')?;
+      Some((form_decode(id), Some(form_decode(secret))))
+    });
+  basic.or_else(|| {
+    Some((
+      form.get("client_id").filter(|id| !id.is_empty())?.clone(),
+      form.get("client_secret").cloned(),
+    ))
+  })
+}
+
+/// Scope that asks for an ID token; every client may request it.
+pub(super) const OPENID_SCOPE: &str = "openid";
+
+/// Scopes to grant for a space-separated `requested` list; an omitted scope grants
+/// everything the client was registered with. `None` when a scope is not the client's.
+pub(super) fn granted_scopes(client: &OAuthClient, requested: Option<&str>) -> Option<Vec<String>> {
+  match requested {
+    Some(requested) if !requested.trim().is_empty() => {
+      let mut requested: Vec<String> = requested.split_whitespace().map(String::from).collect();
+      requested.sort();
+      requested.dedup();
+      requested
+        .iter()
+        .all(|scope| scope == OPENID_SCOPE || client.scopes.contains(scope))
+        .then_some(requested)
+    }
+    _ => Some(client.scopes.clone()),
+  }
+}
+
+fn invalid_client(description: &str) -> Response {
+  oauth_error(StatusCode::Unauthorized, "invalid_client", description)
+}
+
+fn invalid_grant(description: &str) -> Response {
+  oauth_error(StatusCode::BadRequest, "invalid_grant", description)
+}
+
+fn server_error(context: &str, err: impl std::fmt::Display, description: &str) -> Response {
+  eprintln!("[handler-error] {}: {}", context, err);
+  oauth_error(StatusCode::InternalServerError, "server_error", description)
+}
+
+pub async fn oauth_token(req: &Request) -> Response {
+  let form = parse_form(&req.body);
+  let grant_type = match form.get("grant_type") {
+    Some(value) if !value.is_empty() => value.clone(),
+    _ => {
+      return oauth_error(
+        StatusCode::BadRequest,
+        "invalid_request",
+        "Missing grant_type",
+      );
+    }
+  };
+  let (client_id, client_secret) = match client_credentials(req, &form) {
+    Some(credentials) => credentials,
+    None => return invalid_client("Missing client credentials"),
+  };
+  let db = match get_db_connection().await {
+    Ok(db) => db,
+    Err(response) => return response,
+  };
+  let clients = OAuthClientManager::new(db.pool());
+  // Confidential clients must present their secret; public clients are identified by id
+  // and are held to PKCE instead.
+  let client = match &client_secret {
+    Some(secret) => clients.authenticate(&client_id, secret).await,
+    None => clients
+      .find(&client_id)
+      .await
+      .map(|client| client.filter(|client| client.public)),
+  };
+  let client = match client {
+    Ok(Some(client)) => client,
+    Ok(None) => return invalid_client("Client authentication failed"),
+    Err(err) => return server_error("oauth_token", err, "Failed to authenticate client"),
+  };
+
+  match grant_type.as_str() {
+    "client_credentials" => client_credentials_grant(&db, &client, &form).await,
+    "authorization_code" => authorization_code_grant(req, &db, &client, &form).await,
+    "refresh_token" => refresh_token_grant(req, &db, &client, &form).await,
+    _ => oauth_error(
+      StatusCode::BadRequest,
+      "unsupported_grant_type",
+      "Supported grant types are client_credentials, authorization_code and refresh_token",
+    ),
+  }
+}
+
+async fn client_credentials_grant(
+  db: &DB,
+  client: &OAuthClient,
+  form: &HashMap<String, String>,
+) -> Response {
+  if client.public {
+    return oauth_error(
+      StatusCode::BadRequest,
+      "unauthorized_client",
+      "Public clients cannot use client_credentials",
+    );
+  }
+  let scope = match granted_scopes(client, form.get("scope").map(String::as_str)) {
+    Some(scopes) => scopes.join(" "),
+    None => {
+      return oauth_error(
+        StatusCode::BadRequest,
+        "invalid_scope",
+        "Requested scope exceeds the client's scopes",
+      );
+    }
+  };
+
+  let manager = TokenManager::new(db.pool());
+  let payload = json!({
+    "client_id": client.client_id,
+    "service_id": client.service_id,
+    "scope": scope,
+    "grant_type": "client_credentials",
+  });
+  match manager
+    .issue_token(payload, &SessionContext::default())
+    .await
+  {
+    Ok(issue) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: json!({
+        "access_token": issue.token,
+        "token_type": "Bearer",
+        "expires_in": manager.ttl(),
+        "scope": scope,
+      })
+      .to_string()
+      .into_bytes(),
+    },
+    Err(err) => server_error("oauth_token", err, "Failed to issue token"),
+  }
+}
+
+async fn authorization_code_grant(
+  req: &Request,
+  db: &DB,
+  client: &OAuthClient,
+  form: &HashMap<String, String>,
+) -> Response {
+  let field = |name: &str| form.get(name).filter(|value| !value.is_empty());
+  let (code, redirect_uri, code_verifier) =
+    match (field("code"), field("redirect_uri"), field("code_verifier")) {
+      (Some(code), Some(redirect_uri), Some(verifier)) => (code, redirect_uri, verifier),
+      _ => {
+        return oauth_error(
+          StatusCode::BadRequest,
+          "invalid_request",
+          "code, redirect_uri and code_verifier are required",
+        );
+      }
+    };
+
+  let clients = OAuthClientManager::new(db.pool());
+  let grant = match clients.redeem_code(code).await {
+    Ok(grant) => grant,
+    Err(TokenError::NotFound) => return invalid_grant("Invalid authorization code"),
+    Err(TokenError::Expired) => return invalid_grant("Expired authorization code"),
+    Err(TokenError::Reused) => return invalid_grant("Authorization code reuse detected"),
+    Err(TokenError::Database(err)) => {
+      return server_error("oauth_token", err, "Failed to redeem authorization code");
+    }
+  };
+  if grant.client_id != client.client_id {
+    return invalid_grant("Authorization code was issued to another client");
+  }
+  if &grant.redirect_uri != redirect_uri {
+    return invalid_grant("redirect_uri does not match the authorization request");
+  }
+  if !verify_pkce(code_verifier, &grant.code_challenge) {
+    return invalid_grant("PKCE verification failed");
+  }
+
+  let person = match sqlx::query_as::<_, (String, String)>(
+    "SELECT username, name FROM auth.person WHERE id = $1 AND removed_at IS NULL",
+  )
+  .bind(grant.person_id)
+  .fetch_optional(db.pool())
+  .await
+  {
+    Ok(Some(person)) => person,
+    Ok(None) => return invalid_grant("User no longer exists"),
+    Err(err) => return server_error("oauth_token", err, "Failed to load user"),
+  };
+  let payload = json!({
+    "user_id": grant.person_id,
+    "username": person.0,
+    "name": person.1,
+    "client_id": client.client_id,
+    "scope": grant.scope,
+  });
+
+  let manager = TokenManager::new(db.pool());
+  let context = SessionContext {
+    ip: Some(extract_ip(req)).filter(|ip| ip != "unknown"),
+    user_agent: extract_user_agent(req),
+    service_id: Some(client.service_id),
+    verified_at: None,
+  };
+  let issued = match manager.issue_session(payload.clone(), &context).await {
+    Ok(issue) => issue,
+    Err(SessionError::LimitReached { .. }) => return invalid_grant("Session limit reached"),
+    Err(err) => return server_error("oauth_token", err, "Failed to issue token"),
+  };
+  if let Err(err) = clients.attach_session(code, &issued.session_id).await {
+    eprintln!("[handler-error] oauth_token: {}", err);
+  }
+
+  let mut body = json!({
+    "access_token": issued.token,
+    "token_type": "Bearer",
+    "expires_in": manager.ttl(),
+    "refresh_token": issued.refresh_token,
+    "scope": grant.scope,
+  });
+  if grant.scope.split(' ').any(|scope| scope == OPENID_SCOPE) {
+    let request = IdTokenRequest {
+      audience: &client.client_id,
+      session_id: &issued.session_id,
+      nonce: grant.nonce.as_deref(),
+    };
+    match SigningKeys::new(db.pool())
+      .issue_id_token(&payload, &request)
+      .await
+    {
+      Ok(id_token) => body["id_token"] = json!(id_token),
+      Err(err) => {
+        let _ = manager.revoke_session(&issued.session_id).await;
+        return server_error("oauth_token", err, "Failed to sign ID token");
+      }
+    }
+  }
+  log_access(&issued.token, &payload, req, None);
+
+  Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "application/json".to_string(),
+    content: body.to_string().into_bytes(),
+  }
+}
+
+/// Client that a token payload was issued to, if any.
+pub(super) fn payload_client_id(payload: &Value) -> Option<&str> {
+  payload.get("client_id").and_then(Value::as_str)
+}
+
+/// RFC 6749 §6. The refresh token must have been issued to the authenticated client; it
+/// is rotated like on `/auth/refresh` and keeps the scope it was granted with.
+async fn refresh_token_grant(
+  req: &Request,
+  db: &DB,
+  client: &OAuthClient,
+  form: &HashMap<String, String>,
+) -> Response {
+  let refresh_token = match form.get("refresh_token").filter(|value| !value.is_empty()) {
+    Some(token) => token,
+    None => {
+      return oauth_error(
+        StatusCode::BadRequest,
+        "invalid_request",
+        "refresh_token is required",
+      );
+    }
+  };
+
+  let manager = TokenManager::new(db.pool());
+  let granted_scope = match manager.refresh_token_payload(refresh_token).await {
+    Ok(Some(payload)) if payload_client_id(&payload) == Some(client.client_id.as_str()) => payload
+      .get("scope")
+      .and_then(Value::as_str)
+      .unwrap_or_default()
+      .to_string(),
+    // Tokens of other clients are reported like unknown ones and are left untouched.
+    Ok(_) => return invalid_grant("Invalid refresh token"),
+    Err(err) => return server_error("oauth_token", err, "Failed to load refresh token"),
+  };
+  if let Some(scope) = form.get("scope").filter(|value| !value.is_empty()) {
+    let granted: Vec<&str> = granted_scope.split(' ').collect();
+    if scope
+      .split(' ')
+      .any(|requested| !granted.contains(&requested))
+    {
+      return oauth_error(
+        StatusCode::BadRequest,
+        "invalid_scope",
+        "Requested scope exceeds the original grant",
+      );
+    }
+  }
+
+  let issued = match manager.refresh_session(refresh_token).await {
+    Ok(issue) => issue,
+    Err(TokenError::NotFound) => return invalid_grant("Invalid refresh token"),
+    Err(TokenError::Expired) => return invalid_grant("Expired refresh token"),
+    Err(TokenError::Reused) => return invalid_grant("Refresh token reuse detected"),
+    Err(TokenError::Database(err)) => {
+      return server_error("oauth_token", err, "Failed to refresh token");
+    }
+  };
+  log_access(&issued.token, &issued.payload, req, None);
+
+  Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({
+      "access_token": issued.token,
+      "token_type": "Bearer",
+      "expires_in": manager.ttl(),
+      "refresh_token": issued.refresh_token,
+      "scope": granted_scope,
+    })
+    .to_string()
+    .into_bytes(),
+  }
+}
+
+/// Service calling `/oauth/introspect` or `/oauth/revoke`: a backend with its `x-api-key`
+/// or a confidential OAuth client. Returns the caller's service id.
+async fn authenticate_caller(
+  req: &Request,
+  db: &DB,
+  form: &HashMap<String, String>,
+) -> Result<i32, Response> {
+  if let Some(api_key) = extract_api_key(req) {
+    return match ApiKeyManager::new(db.pool()).authenticate(&api_key).await {
+      Ok(Some(caller)) => Ok(caller.service_id),
+      Ok(None) => Err(invalid_client("Invalid API key")),
+      Err(err) => Err(server_error(
+        "authenticate_caller",
+        err,
+        "Failed to validate API key",
+      )),
+    };
+  }
+  let (client_id, client_secret) = match client_credentials(req, form) {
+    Some((client_id, Some(secret))) => (client_id, secret),
+    _ => return Err(invalid_client("Missing client credentials")),
+  };
+  match OAuthClientManager::new(db.pool())
+    .authenticate(&client_id, &client_secret)
+    .await
+  {
+    Ok(Some(client)) => Ok(client.service_id),
+    Ok(None) => Err(invalid_client("Client authentication failed")),
+    Err(err) => Err(server_error(
+      "authenticate_caller",
+      err,
+      "Failed to authenticate client",
+    )),
+  }
+}
+
+fn required_token(form: &HashMap<String, String>) -> Result<&str, Response> {
+  form
+    .get("token")
+    .map(String::as_str)
+    .filter(|token| !token.is_empty())
+    .ok_or_else(|| oauth_error(StatusCode::BadRequest, "invalid_request", "Missing token"))
+}
+
+/// RFC 7662 token introspection. Read-only: unlike `/check-token` it never renews the
+/// token. Tokens of another service, and login tokens of people without a role in the
+/// caller's service, are reported as inactive.
+pub async fn oauth_introspect(req: &Request) -> Response {
+  let form = parse_form(&req.body);
+  let db = match get_db_connection().await {
+    Ok(db) => db,
+    Err(response) => return response,
+  };
+  let service_id = match authenticate_caller(req, &db, &form).await {
+    Ok(service_id) => service_id,
+    Err(response) => return response,
+  };
+  let token = match required_token(&form) {
+    Ok(token) => token,
+    Err(response) => return response,
+  };
+
+  let inactive = || Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({ "active": false }).to_string().into_bytes(),
+  };
+  let manager = TokenManager::new(db.pool());
+  match manager.token_visible_to(token, service_id).await {
+    Ok(true) => {}
+    Ok(false) => return inactive(),
+    Err(err) => return server_error("oauth_introspect", err, "Failed to validate token"),
+  }
+  let validation = match manager.validate_token(token, false).await {
+    Ok(validation) => validation,
+    // Unknown, expired and revoked tokens all look the same to the caller.
+    Err(TokenError::Database(err)) => {
+      return server_error("oauth_introspect", err, "Failed to validate token");
+    }
+    Err(_) => return inactive(),
+  };
+  log_access(token, &validation.record.payload, req, Some(service_id));
+
+  let payload = &validation.record.payload;
+  let exp = validation
+    .max_expires_at
+    .map_or(validation.expires_at, |max| max.min(validation.expires_at));
+  let mut body = json!({
+    "active": true,
+    "token_type": "Bearer",
+    "exp": exp,
+    "iat": validation.record.issued_at,
+    "sid": validation.record.session_id,
+  });
+  // The subject is the person, or the client itself for client_credentials tokens.
+  match payload.get("user_id").and_then(|value| value.as_i64()) {
+    Some(user_id) => {
+      body["sub"] = json!(user_id.to_string());
+      body["user_id"] = json!(user_id);
+    }
+    None => body["sub"] = payload.get("client_id").cloned().unwrap_or_default(),
+  }
+  if let Some(verified_at) = validation.record.verified_at {
+    body["auth_time"] = json!(verified_at);
+  }
+  for claim in ["username", "client_id", "scope", "act"] {
+    if let Some(value) = payload.get(claim) {
+      body[claim] = value.clone();
+    }
+  }
+
+  Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "application/json".to_string(),
+    content: body.to_string().into_bytes(),
+  }
+}
+
+/// RFC 7009 token revocation. An access token is deleted on its own; a refresh token
+/// ends the session it belongs to. Unknown tokens and tokens the caller may not
+/// introspect are answered the same way and left alone.
+pub async fn oauth_revoke(req: &Request) -> Response {
+  let form = parse_form(&req.body);
+  let db = match get_db_connection().await {
+    Ok(db) => db,
+    Err(response) => return response,
+  };
+  let service_id = match authenticate_caller(req, &db, &form).await {
+    Ok(service_id) => service_id,
+    Err(response) => return response,
+  };
+  let token = match required_token(&form) {
+    Ok(token) => token,
+    Err(response) => return response,
+  };
+
+  let done = || Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "application/json".to_string(),
+    content: Vec::new(),
+  };
+  let manager = TokenManager::new(db.pool());
+  match manager.token_visible_to(token, service_id).await {
+    Ok(true) => {}
+    Ok(false) => return done(),
+    Err(err) => return server_error("oauth_revoke", err, "Failed to revoke token"),
+  }
+  let revoked = match manager.delete_token(token).await {
+    Ok(true) => Ok(true),
+    Ok(false) => manager.revoke_refresh_token(token).await,
+    Err(err) => Err(err),
+  };
+  match revoked {
+    Ok(true) => {
+      close_ended_impersonations(&db, None).await;
+      done()
+    }
+    Ok(false) => done(),
+    Err(err) => server_error("oauth_revoke", err, "Failed to revoke token"),
+  }
+}
@@ -0,0 +1,119 @@
+use crate::oidc::{OidcConfig, SigningKeys};
+use httpageboy::{Request, Response, StatusCode};
+use serde_json::json;
+
+use super::{error_response, get_db_connection, require_permission, with_auth};
+
+fn json_response(body: serde_json::Value) -> Response {
+  Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "application/json".to_string(),
+    content: body.to_string().into_bytes(),
+  }
+}
+
+pub async fn openid_configuration(_req: &Request) -> Response {
+  let issuer = match OidcConfig::load() {
+    Ok(config) => config.issuer,
+    Err(err) => {
+      eprintln!("[handler-error] openid_configuration: {}", err);
+      return error_response(StatusCode::InternalServerError, "OIDC is not configured");
+    }
+  };
+  json_response(json!({
+    "issuer": issuer,
+    "authorization_endpoint": format!("{}/oauth/authorize", issuer),
+    "token_endpoint": format!("{}/oauth/token", issuer),
+    "userinfo_endpoint": format!("{}/userinfo", issuer),
+    "introspection_endpoint": format!("{}/oauth/introspect", issuer),
+    "revocation_endpoint": format!("{}/oauth/revoke", issuer),
+    "jwks_uri": format!("{}/.well-known/jwks.json", issuer),
+    "response_types_supported": ["code"],
+    "grant_types_supported": ["authorization_code", "client_credentials"],
+    "subject_types_supported": ["public"],
+    "id_token_signing_alg_values_supported": ["RS256"],
+    "scopes_supported": ["openid"],
+    "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+    "code_challenge_methods_supported": ["S256"],
+    "claims_supported": ["sub", "iss", "aud", "exp", "iat", "sid", "nonce", "user_id", "preferred_username", "name"],
+  }))
+}
+
+pub async fn jwks(_req: &Request) -> Response {
+  let db = match get_db_connection().await {
+    Ok(db) => db,
+    Err(response) => return response,
+  };
+  match SigningKeys::new(db.pool()).jwks().await {
+    Ok(keys) => json_response(keys),
+    Err(err) => {
+      eprintln!("[handler-error] jwks: {}", err);
+      error_response(
+        StatusCode::InternalServerError,
+        "Failed to load signing keys",
+      )
+    }
+  }
+}
+
+/// OIDC UserInfo (Core §5.3): the person behind the access token, from the payload
+/// login stored with it.
+pub async fn userinfo(req: &Request) -> Response {
+  with_auth(req, true, |_req, _db, validation, _token| async move {
+    let payload = &validation.record.payload;
+    let user_id = match payload.get("user_id").and_then(|value| value.as_i64()) {
+      Some(id) => id,
+      None => return error_response(StatusCode::Forbidden, "Token has no user"),
+    };
+    json_response(json!({
+      "sub": user_id.to_string(),
+      "user_id": user_id,
+      "username": payload.get("username"),
+      "preferred_username": payload.get("username"),
+      "name": payload.get("name"),
+    }))
+  })
+  .await
+}
+
+pub async fn list_signing_keys(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "keys.read").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  match SigningKeys::new(db.pool()).list().await {
+    Ok(keys) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&keys).unwrap(),
+    },
+    Err(err) => {
+      eprintln!("[handler-error] list_signing_keys: {}", err);
+      error_response(
+        StatusCode::InternalServerError,
+        "Failed to fetch signing keys",
+      )
+    }
+  }
+}
+
+pub async fn rotate_signing_key(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "keys.write").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  match SigningKeys::new(db.pool()).rotate().await {
+    Ok(key) => Response {
+      status: StatusCode::Created.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&key).unwrap(),
+    },
+    Err(err) => {
+      eprintln!("[handler-error] rotate_signing_key: {}", err);
+      error_response(
+        StatusCode::InternalServerError,
+        "Failed to rotate signing key",
+      )
+    }
+  }
+}
@@ -0,0 +1,199 @@
+use crate::auth::TokenManager;
+use crate::login_guard::{LoginGuard, LoginSubject};
+use crate::notifier::{self, Notification};
+use crate::password::hash_password;
+use crate::password_reset::PasswordResets;
+use crate::security_events::{PASSWORD_RESET, PASSWORD_RESET_REQUESTED};
+use httpageboy::{Request, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::impersonation::close_ended_impersonations;
+use super::security_events::record_security_event;
+use super::users::check_person_password_policy;
+use super::{error_response, get_db_connection};
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordPayload {
+  username: Option<String>,
+  email: Option<String>,
+}
+
+fn reset_message(to: String, token: &str, url: Option<&str>, ttl_seconds: i64) -> Notification {
+  let link = url
+    .map(|url| format!("\n\nOr open {}{}", url, token))
+    .unwrap_or_default();
+  Notification {
+    to,
+    subject: "Password reset".to_string(),
+    body: format!(
+      "Someone asked to reset the password of your account.\n\nYour password reset code is {}{}\n\nIt expires in {} minutes and works once. If it was not you, ignore this message.",
+      token,
+      link,
+      ttl_seconds / 60
+    ),
+  }
+}
+
+/// Send a reset token to the person's email. The answer is the same whether or not the
+/// account exists. A username match wins over an email match, and a person gets at most
+/// one token per `PASSWORD_RESET_COOLDOWN_SECONDS`.
+pub async fn forgot_password(req: &Request) -> Response {
+  let payload: ForgotPasswordPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "Invalid request body"),
+  };
+  if payload.username.is_none() && payload.email.is_none() {
+    return error_response(StatusCode::BadRequest, "Invalid request body");
+  }
+  let db = match get_db_connection().await {
+    Ok(db) => db,
+    Err(response) => return response,
+  };
+
+  let person = sqlx::query_as::<_, (i32, Option<String>)>(
+    "SELECT id, email FROM auth.person
+     WHERE removed_at IS NULL AND (username = $1 OR LOWER(email) = LOWER($2))
+     ORDER BY username = $1 DESC NULLS LAST
+     LIMIT 1",
+  )
+  .bind(&payload.username)
+  .bind(&payload.email)
+  .fetch_optional(db.pool())
+  .await;
+  match person {
+    Ok(Some((person_id, Some(email)))) => {
+      let resets = PasswordResets::new(db.pool());
+      match resets.issue(person_id).await {
+        Ok(Some((token, _))) => {
+          let config = resets.config();
+          let message = reset_message(email, &token, config.url.as_deref(), config.ttl_seconds);
+          tokio::spawn(async move {
+            if let Err(err) = notifier::send(&message).await {
+              eprintln!("[notifier-error] person_id={} {}", person_id, err);
+            }
+          });
+          record_security_event(&db, req, person_id, PASSWORD_RESET_REQUESTED, json!({})).await;
+        }
+        // Still cooling down from the previous token: nothing is sent.
+        Ok(None) => {}
+        Err(err) => {
+          eprintln!("[handler-error] forgot_password: {}", err);
+          return error_response(
+            StatusCode::InternalServerError,
+            "Failed to create reset token",
+          );
+        }
+      }
+    }
+    // Unknown accounts and accounts without an email get the same answer.
+    Ok(_) => {}
+    Err(err) => {
+      eprintln!("[handler-error] forgot_password: {}", err);
+      return error_response(StatusCode::InternalServerError, "Failed to fetch user");
+    }
+  }
+
+  Response {
+    status: StatusCode::Accepted.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({ "status": "requested" }).to_string().into_bytes(),
+  }
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordPayload {
+  token: String,
+  password: String,
+}
+
+/// Set a new password with a token from `/auth/password/forgot`. Every session of the
+/// person ends, and their login lockout is cleared.
+pub async fn reset_password(req: &Request) -> Response {
+  let payload: ResetPasswordPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "Invalid request body"),
+  };
+  let db = match get_db_connection().await {
+    Ok(db) => db,
+    Err(response) => return response,
+  };
+
+  // The policy is checked before the token is spent, so a rejected password can be retried.
+  let resets = PasswordResets::new(db.pool());
+  match resets.find(&payload.token).await {
+    Ok(Some(person_id)) => {
+      if let Err(response) =
+        check_person_password_policy(&db, person_id, None, &payload.password).await
+      {
+        return response;
+      }
+    }
+    Ok(None) => return error_response(StatusCode::BadRequest, "Invalid or expired reset token"),
+    Err(err) => {
+      eprintln!("[handler-error] reset_password: {}", err);
+      return error_response(
+        StatusCode::InternalServerError,
+        "Failed to check reset token",
+      );
+    }
+  }
+  let person_id = match resets.consume(&payload.token).await {
+    Ok(Some(person_id)) => person_id,
+    Ok(None) => return error_response(StatusCode::BadRequest, "Invalid or expired reset token"),
+    Err(err) => {
+      eprintln!("[handler-error] reset_password: {}", err);
+      return error_response(
+        StatusCode::InternalServerError,
+        "Failed to check reset token",
+      );
+    }
+  };
+  let password_hash = match hash_password(&payload.password).await {
+    Ok(hash) => hash,
+    Err(err) => {
+      eprintln!("[handler-error] reset_password: {}", err);
+      return error_response(StatusCode::InternalServerError, "Failed to hash password");
+    }
+  };
+  let username = match sqlx::query_scalar::<_, String>(
+    "UPDATE auth.person SET password_hash = $1 WHERE id = $2 AND removed_at IS NULL RETURNING username",
+  )
+  .bind(password_hash)
+  .bind(person_id)
+  .fetch_optional(db.pool())
+  .await
+  {
+    Ok(Some(username)) => username,
+    Ok(None) => return error_response(StatusCode::BadRequest, "Invalid or expired reset token"),
+    Err(err) => {
+      eprintln!("[handler-error] reset_password: {}", err);
+      return error_response(StatusCode::InternalServerError, "Failed to update password");
+    }
+  };
+
+  if let Err(err) = TokenManager::new(db.pool())
+    .delete_tokens_for_user(person_id)
+    .await
+  {
+    eprintln!("[handler-error] reset_password: {}", err);
+    return error_response(
+      StatusCode::InternalServerError,
+      "Failed to remove user tokens",
+    );
+  }
+  close_ended_impersonations(&db, None).await;
+  let guard = LoginGuard::new(db.pool());
+  if let Err(err) = guard.reset(&[LoginSubject::Username(username)]).await {
+    eprintln!("[login-guard-error] {}", err);
+  }
+  record_security_event(&db, req, person_id, PASSWORD_RESET, json!({})).await;
+
+  Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({ "status": "password_reset" })
+      .to_string()
+      .into_bytes(),
+  }
+}
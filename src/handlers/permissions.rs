@@ -3,7 +3,7 @@ use httpageboy::{Request, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use super::{error_response, require_token_without_renew};
+use super::{error_response, require_permission};
 
 #[derive(Serialize, sqlx::FromRow)]
 pub struct Permission {
@@ -17,7 +17,7 @@ pub struct CreatePermissionPayload {
 }
 
 pub async fn create_permission(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "permissions.write").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -46,7 +46,7 @@ pub async fn create_permission(req: &Request) -> Response {
 }
 
 pub async fn list_permissions(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "permissions.read").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -72,7 +72,7 @@ pub struct UpdatePermissionPayload {
 }
 
 pub async fn update_permission(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "permissions.write").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -106,7 +106,7 @@ pub async fn update_permission(req: &Request) -> Response {
 }
 
 pub async fn delete_permission(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "permissions.write").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -168,7 +168,7 @@ async fn ensure_service_role_exists(
 }
 
 pub async fn assign_permission_to_role(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "roles.write").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -200,7 +200,7 @@ pub async fn assign_permission_to_role(req: &Request) -> Response {
 }
 
 pub async fn remove_permission_from_role(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "roles.write").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -228,7 +228,7 @@ pub async fn remove_permission_from_role(req: &Request) -> Response {
 }
 
 pub async fn list_role_permissions(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "roles.read").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
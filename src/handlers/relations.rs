@@ -4,7 +4,10 @@ use serde_json::json;
 
 use super::roles::Role;
 use super::users::User;
-use super::{error_response, require_token_without_renew};
+use super::{
+  authenticate_service, error_response, get_db_connection, log_service_access, require_permission,
+  require_token_without_renew,
+};
 
 #[derive(Deserialize)]
 pub struct ServiceRolePayload {
@@ -13,7 +16,7 @@ pub struct ServiceRolePayload {
 }
 
 pub async fn assign_role_to_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "roles.write").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -40,7 +43,7 @@ pub async fn assign_role_to_service(req: &Request) -> Response {
 }
 
 pub async fn remove_role_from_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "roles.write").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -67,7 +70,7 @@ pub async fn remove_role_from_service(req: &Request) -> Response {
 }
 
 pub async fn list_service_roles(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "roles.read").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -100,7 +103,7 @@ pub struct PersonServiceRolePayload {
 }
 
 pub async fn assign_role_to_person_in_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "roles.write").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -128,7 +131,7 @@ pub async fn assign_role_to_person_in_service(req: &Request) -> Response {
 }
 
 pub async fn remove_role_from_person_in_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "roles.write").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -156,7 +159,7 @@ pub async fn remove_role_from_person_in_service(req: &Request) -> Response {
 }
 
 pub async fn list_person_roles_in_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "roles.read").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -187,7 +190,7 @@ pub async fn list_person_roles_in_service(req: &Request) -> Response {
 }
 
 pub async fn list_persons_with_role_in_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "roles.read").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -264,14 +267,31 @@ fn parse_check_permission_payload(req: &Request) -> Result<CheckPermissionPayloa
 }
 
 pub async fn check_person_permission_in_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
-    Ok(tuple) => tuple,
+  // Backend services authenticate with their API key; anyone else needs a token.
+  let caller = match authenticate_service(req).await {
+    Ok(caller) => caller,
     Err(response) => return response,
   };
+  let db = match &caller {
+    Some(_) => match get_db_connection().await {
+      Ok(db) => db,
+      Err(response) => return response,
+    },
+    None => match require_token_without_renew(req).await {
+      Ok((db, _, _)) => db,
+      Err(response) => return response,
+    },
+  };
   let payload = match parse_check_permission_payload(req) {
     Ok(payload) => payload,
     Err(response) => return response,
   };
+  if let Some(caller) = &caller {
+    if caller.service_id != payload.service_id {
+      return error_response(StatusCode::Forbidden, "API key does not belong to service");
+    }
+    log_service_access(req, caller, Some(i64::from(payload.person_id)));
+  }
   match sqlx::query_scalar::<_, bool>(
     "SELECT * FROM auth.check_person_permission_in_service($1, $2, $3)",
   )
@@ -0,0 +1,77 @@
+use crate::database::DB;
+use crate::security_events::{SecurityEventRecord, SecurityEvents};
+use httpageboy::{Request, Response, StatusCode};
+use serde_json::Value;
+
+use super::users::session_person_id;
+use super::{
+  error_response, extract_ip, extract_user_agent, require_permission, with_auth_no_renew,
+};
+
+/// Add an event to the person's security history. Failures are logged and do not undo
+/// the change being recorded.
+pub(super) async fn record_security_event(
+  db: &DB,
+  req: &Request,
+  person_id: i32,
+  event: &str,
+  detail: Value,
+) {
+  let ip = Some(extract_ip(req)).filter(|ip| ip != "unknown");
+  let user_agent = extract_user_agent(req);
+  let record = SecurityEventRecord {
+    person_id,
+    event,
+    detail,
+    ip: ip.as_deref(),
+    user_agent: user_agent.as_deref(),
+  };
+  if let Err(err) = SecurityEvents::new(db.pool()).record(&record).await {
+    eprintln!(
+      "[security-event-error] person_id={} {}: {}",
+      person_id, event, err
+    );
+  }
+}
+
+async fn security_events_response(db: &DB, person_id: i32) -> Response {
+  match SecurityEvents::new(db.pool())
+    .list_for_person(person_id)
+    .await
+  {
+    Ok(events) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&events).unwrap(),
+    },
+    Err(err) => {
+      eprintln!("[handler-error] list_security_events: {}", err);
+      error_response(
+        StatusCode::InternalServerError,
+        "Failed to fetch security events",
+      )
+    }
+  }
+}
+
+pub async fn list_own_security_events(req: &Request) -> Response {
+  with_auth_no_renew(req, |_req, db, validation, _token| async move {
+    match session_person_id(&validation) {
+      Ok(person_id) => security_events_response(&db, person_id).await,
+      Err(response) => response,
+    }
+  })
+  .await
+}
+
+pub async fn list_security_events(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "users.read").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let person_id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return error_response(StatusCode::BadRequest, "Invalid user ID"),
+  };
+  security_events_response(&db, person_id).await
+}
@@ -2,7 +2,7 @@ use httpageboy::{Request, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use super::{error_response, require_token_without_renew};
+use super::{error_response, require_permission};
 
 #[derive(Serialize, sqlx::FromRow)]
 pub struct Service {
@@ -18,7 +18,7 @@ pub struct CreateServicePayload {
 }
 
 pub async fn create_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "services.write").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -42,7 +42,7 @@ pub async fn create_service(req: &Request) -> Response {
 }
 
 pub async fn list_services(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "services.read").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -63,10 +63,12 @@ pub async fn list_services(req: &Request) -> Response {
 pub struct UpdateServicePayload {
   name: Option<String>,
   description: Option<String>,
+  /// Sessions a person may hold in this service; `0` removes the limit.
+  max_sessions: Option<i32>,
 }
 
 pub async fn update_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "services.write").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -78,6 +80,20 @@ pub async fn update_service(req: &Request) -> Response {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "Invalid request body"),
   };
+  if let Some(max_sessions) = payload.max_sessions {
+    if max_sessions < 0 {
+      return error_response(StatusCode::BadRequest, "Invalid max_sessions");
+    }
+    if sqlx::query("UPDATE auth.services SET max_sessions = NULLIF($2, 0) WHERE id = $1")
+      .bind(id)
+      .bind(max_sessions)
+      .execute(db.pool())
+      .await
+      .is_err()
+    {
+      return error_response(StatusCode::InternalServerError, "Failed to update service");
+    }
+  }
   match sqlx::query("CALL auth.update_service($1, $2, $3)")
     .bind(id)
     .bind(payload.name)
@@ -95,7 +111,7 @@ pub async fn update_service(req: &Request) -> Response {
 }
 
 pub async fn delete_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "services.write").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -118,7 +134,7 @@ pub async fn delete_service(req: &Request) -> Response {
 }
 
 pub async fn list_services_of_person(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "services.read").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
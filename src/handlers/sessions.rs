@@ -0,0 +1,103 @@
+use crate::auth::TokenManager;
+use httpageboy::{Request, Response, StatusCode};
+use serde_json::json;
+
+use super::impersonation::close_ended_impersonations;
+use super::users::session_person_id;
+use super::{error_response, require_permission, with_auth, with_auth_no_renew};
+
+pub async fn list_sessions(req: &Request) -> Response {
+  with_auth(req, true, |_req, db, validation, _token| async move {
+    // An impersonation token must not see or end the person's real sessions.
+    let user_id = match session_person_id(&validation) {
+      Ok(id) => id,
+      Err(response) => return response,
+    };
+    let manager = TokenManager::new(db.pool());
+    match manager.list_sessions(user_id).await {
+      Ok(sessions) => {
+        let sessions: Vec<_> = sessions
+          .into_iter()
+          .map(|session| {
+            let current = session.session_id == validation.record.session_id;
+            let mut value = json!(session);
+            value["current"] = json!(current);
+            value
+          })
+          .collect();
+        Response {
+          status: StatusCode::Ok.to_string(),
+          content_type: "application/json".to_string(),
+          content: json!(sessions).to_string().into_bytes(),
+        }
+      }
+      Err(err) => {
+        eprintln!("[handler-error] list_sessions: {}", err);
+        error_response(StatusCode::InternalServerError, "Failed to list sessions")
+      }
+    }
+  })
+  .await
+}
+
+pub async fn revoke_session(req: &Request) -> Response {
+  let session_id = match req.params.get("id").filter(|id| !id.is_empty()) {
+    Some(id) => id.clone(),
+    None => return error_response(StatusCode::BadRequest, "Invalid session ID"),
+  };
+  with_auth_no_renew(req, |_req, db, validation, _token| async move {
+    // An impersonation token must not see or end the person's real sessions.
+    let user_id = match session_person_id(&validation) {
+      Ok(id) => id,
+      Err(response) => return response,
+    };
+    let manager = TokenManager::new(db.pool());
+    match manager.revoke_user_session(user_id, &session_id).await {
+      Ok(true) => {
+        close_ended_impersonations(&db, Some(&session_id)).await;
+        Response {
+          status: StatusCode::Ok.to_string(),
+          content_type: "application/json".to_string(),
+          content: json!({ "status": "revoked", "session_id": session_id })
+            .to_string()
+            .into_bytes(),
+        }
+      }
+      Ok(false) => error_response(StatusCode::NotFound, "Session not found"),
+      Err(err) => {
+        eprintln!("[handler-error] revoke_session: {}", err);
+        error_response(StatusCode::InternalServerError, "Failed to revoke session")
+      }
+    }
+  })
+  .await
+}
+
+pub async fn revoke_user_sessions(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "users.write").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return error_response(StatusCode::BadRequest, "Invalid user ID"),
+  };
+  let manager = TokenManager::new(db.pool());
+  match manager.delete_tokens_for_user(id).await {
+    Ok(_) => {
+      close_ended_impersonations(&db, None).await;
+      Response {
+        status: StatusCode::NoContent.to_string(),
+        content_type: "application/json".to_string(),
+        content: Vec::new(),
+      }
+    }
+    Err(err) => {
+      eprintln!("[handler-error] revoke_user_sessions: {}", err);
+      error_response(
+        StatusCode::InternalServerError,
+        "Failed to remove user tokens",
+      )
+    }
+  }
+}
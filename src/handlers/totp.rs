@@ -0,0 +1,416 @@
+use crate::database::DB;
+use crate::login_challenge::LoginChallenges;
+use crate::login_guard::{LoginGuard, LoginSubject};
+use crate::recovery_codes::RecoveryCodes;
+use crate::security_events::{
+  RECOVERY_CODE_USED, RECOVERY_CODES_GENERATED, TOTP_DISABLED, TOTP_ENABLED, TOTP_RESET,
+};
+use crate::totp::{TotpError, TotpManager};
+use httpageboy::{Request, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::security_events::record_security_event;
+use super::users::{
+  LoginFailure, complete_login, find_auth_user, session_person_id, with_client_ip,
+};
+use super::{
+  error_response, get_db_connection, require_permission, require_recent_verification,
+  unauthorized_response, with_auth_no_renew,
+};
+
+/// What a person presents at the second login step.
+pub(super) enum SecondFactor<'a> {
+  Totp(&'a str),
+  /// One of the single-use codes for a lost authenticator.
+  RecoveryCode(&'a str),
+}
+
+/// Check a second factor of `person_id`. Wrong codes count towards the person's
+/// second-factor lockout in [`LoginGuard`], separately from password failures.
+async fn check_second_factor(
+  db: &DB,
+  req: &Request,
+  person_id: i32,
+  factor: SecondFactor<'_>,
+) -> Result<(), LoginFailure> {
+  let guard = LoginGuard::new(db.pool());
+  let subjects = with_client_ip(LoginSubject::SecondFactor(person_id), req);
+  match guard.retry_after(&subjects).await {
+    Ok(Some(retry_after)) => return Err(LoginFailure::Locked { retry_after }),
+    Ok(None) => {}
+    Err(_) => return Err(LoginFailure::Internal("Failed to check login attempts")),
+  }
+
+  let accepted = match factor {
+    SecondFactor::Totp(code) => match TotpManager::new(db.pool()).verify(person_id, code).await {
+      Ok(valid) => Ok(valid),
+      Err(TotpError::NotEnrolled) => Ok(false),
+      Err(err) => Err(err.to_string()),
+    },
+    SecondFactor::RecoveryCode(code) => {
+      match RecoveryCodes::new(db.pool()).redeem(person_id, code).await {
+        Ok(Some(remaining)) => {
+          record_security_event(
+            db,
+            req,
+            person_id,
+            RECOVERY_CODE_USED,
+            json!({ "remaining": remaining }),
+          )
+          .await;
+          Ok(true)
+        }
+        Ok(None) => Ok(false),
+        Err(err) => Err(err.to_string()),
+      }
+    }
+  };
+  match accepted {
+    Ok(true) => {
+      if let Err(err) = guard.reset(&subjects).await {
+        eprintln!("[login-guard-error] {}", err);
+      }
+      Ok(())
+    }
+    Ok(false) => {
+      if let Err(err) = guard.record_failure(&subjects).await {
+        eprintln!("[login-guard-error] {}", err);
+      }
+      Err(LoginFailure::InvalidCode)
+    }
+    Err(err) => {
+      eprintln!("[handler-error] check_second_factor: {}", err);
+      Err(LoginFailure::Internal("Failed to verify code"))
+    }
+  }
+}
+
+/// Second step for logins that do not go through a challenge (the OAuth consent form,
+/// re-authentication). Passes when the person has no TOTP enabled.
+pub(super) async fn verify_second_factor(
+  db: &DB,
+  req: &Request,
+  person_id: i32,
+  code: Option<&str>,
+) -> Result<(), LoginFailure> {
+  match TotpManager::new(db.pool()).is_enabled(person_id).await {
+    Ok(false) => return Ok(()),
+    Ok(true) => {}
+    Err(err) => {
+      eprintln!("[handler-error] verify_second_factor: {}", err);
+      return Err(LoginFailure::Internal("Failed to check second factor"));
+    }
+  }
+  match code.map(str::trim).filter(|code| !code.is_empty()) {
+    Some(code) => check_second_factor(db, req, person_id, SecondFactor::Totp(code)).await,
+    None => Err(LoginFailure::CodeRequired),
+  }
+}
+
+#[derive(Deserialize)]
+pub struct LoginVerifyPayload {
+  challenge_id: String,
+  code: Option<String>,
+  recovery_code: Option<String>,
+}
+
+/// Second login step: trade the challenge from `/auth/login` and a TOTP or recovery code
+/// for a session.
+pub async fn login_verify(req: &Request) -> Response {
+  let payload: LoginVerifyPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "Invalid request body"),
+  };
+  let factor = match (&payload.code, &payload.recovery_code) {
+    (Some(code), None) => SecondFactor::Totp(code),
+    (None, Some(code)) => SecondFactor::RecoveryCode(code),
+    _ => return error_response(StatusCode::BadRequest, "Invalid request body"),
+  };
+  let db = match get_db_connection().await {
+    Ok(db) => db,
+    Err(response) => return response,
+  };
+
+  let challenges = LoginChallenges::new(db.pool());
+  let challenge = match challenges.find(&payload.challenge_id).await {
+    Ok(Some(challenge)) => challenge,
+    Ok(None) => return unauthorized_response("Invalid or expired challenge"),
+    Err(err) => {
+      eprintln!("[handler-error] login_verify: {}", err);
+      return error_response(
+        StatusCode::InternalServerError,
+        "Failed to load login challenge",
+      );
+    }
+  };
+  if let Err(failure) = check_second_factor(&db, req, challenge.person_id, factor).await {
+    return failure.into_response();
+  }
+  // Completing removes the challenge, so it opens one session at most.
+  let challenge = match challenges.complete(&payload.challenge_id).await {
+    Ok(Some(challenge)) => challenge,
+    Ok(None) => return unauthorized_response("Invalid or expired challenge"),
+    Err(err) => {
+      eprintln!("[handler-error] login_verify: {}", err);
+      return error_response(
+        StatusCode::InternalServerError,
+        "Failed to load login challenge",
+      );
+    }
+  };
+
+  let user = match find_auth_user(&db, challenge.person_id).await {
+    Ok(Some(user)) => user,
+    Ok(None) => return unauthorized_response("Invalid credentials"),
+    Err(err) => {
+      eprintln!("[handler-error] login_verify: {}", err);
+      return error_response(
+        StatusCode::InternalServerError,
+        "Failed to query user credentials",
+      );
+    }
+  };
+  complete_login(&db, req, &user, challenge.service_id).await
+}
+
+/// Start TOTP enrollment for the caller. The secret is returned once, as is and as an
+/// `otpauth://` URI for QR codes; it is enabled by `/auth/totp/confirm`.
+pub async fn enroll_totp(req: &Request) -> Response {
+  with_auth_no_renew(req, |_req, db, validation, _token| async move {
+    let person_id = match session_person_id(&validation) {
+      Ok(id) => id,
+      Err(response) => return response,
+    };
+    if let Err(response) = require_recent_verification(&db, &validation) {
+      return response;
+    }
+    let username = validation
+      .record
+      .payload
+      .get("username")
+      .and_then(|value| value.as_str())
+      .unwrap_or_default();
+    match TotpManager::new(db.pool())
+      .begin_enrollment(person_id, username)
+      .await
+    {
+      Ok(enrollment) => Response {
+        status: StatusCode::Created.to_string(),
+        content_type: "application/json".to_string(),
+        content: serde_json::to_vec(&enrollment).unwrap(),
+      },
+      Err(TotpError::AlreadyEnabled) => {
+        error_response(StatusCode::Conflict, "TOTP already enabled")
+      }
+      Err(err) => {
+        eprintln!("[handler-error] enroll_totp: {}", err);
+        error_response(
+          StatusCode::InternalServerError,
+          "Failed to start TOTP enrollment",
+        )
+      }
+    }
+  })
+  .await
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmTotpPayload {
+  code: String,
+}
+
+pub async fn confirm_totp(req: &Request) -> Response {
+  let payload: ConfirmTotpPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "Invalid request body"),
+  };
+  with_auth_no_renew(req, |_req, db, validation, _token| async move {
+    let person_id = match session_person_id(&validation) {
+      Ok(id) => id,
+      Err(response) => return response,
+    };
+    match TotpManager::new(db.pool())
+      .confirm(person_id, &payload.code)
+      .await
+    {
+      Ok(true) => {
+        record_security_event(&db, req, person_id, TOTP_ENABLED, json!({})).await;
+        match issue_recovery_codes(&db, req, person_id).await {
+          Ok(codes) => Response {
+            status: StatusCode::Ok.to_string(),
+            content_type: "application/json".to_string(),
+            content: json!({ "status": "enabled", "recovery_codes": codes })
+              .to_string()
+              .into_bytes(),
+          },
+          Err(response) => response,
+        }
+      }
+      Ok(false) => unauthorized_response("Invalid verification code"),
+      Err(TotpError::NotEnrolled) => {
+        error_response(StatusCode::BadRequest, "No pending TOTP enrollment")
+      }
+      Err(err) => {
+        eprintln!("[handler-error] confirm_totp: {}", err);
+        error_response(StatusCode::InternalServerError, "Failed to confirm TOTP")
+      }
+    }
+  })
+  .await
+}
+
+/// Replace the person's recovery codes, recording it in their security events.
+async fn issue_recovery_codes(
+  db: &DB,
+  req: &Request,
+  person_id: i32,
+) -> Result<Vec<String>, Response> {
+  match RecoveryCodes::new(db.pool()).regenerate(person_id).await {
+    Ok(codes) => {
+      record_security_event(
+        db,
+        req,
+        person_id,
+        RECOVERY_CODES_GENERATED,
+        json!({ "count": codes.len() }),
+      )
+      .await;
+      Ok(codes)
+    }
+    Err(err) => {
+      eprintln!("[handler-error] issue_recovery_codes: {}", err);
+      Err(error_response(
+        StatusCode::InternalServerError,
+        "Failed to generate recovery codes",
+      ))
+    }
+  }
+}
+
+async fn remove_totp(
+  db: &DB,
+  req: &Request,
+  person_id: i32,
+  event: &str,
+  detail: serde_json::Value,
+) -> Response {
+  match TotpManager::new(db.pool()).remove(person_id).await {
+    Ok(true) => {
+      if let Err(err) = RecoveryCodes::new(db.pool()).clear(person_id).await {
+        eprintln!("[handler-error] remove_totp: {}", err);
+      }
+      // A fresh enrollment starts without the old code-guessing counter.
+      let guard = LoginGuard::new(db.pool());
+      if let Err(err) = guard.reset(&[LoginSubject::SecondFactor(person_id)]).await {
+        eprintln!("[login-guard-error] {}", err);
+      }
+      record_security_event(db, req, person_id, event, detail).await;
+      Response {
+        status: StatusCode::NoContent.to_string(),
+        content_type: "application/json".to_string(),
+        content: Vec::new(),
+      }
+    }
+    Ok(false) => error_response(StatusCode::NotFound, "TOTP not enrolled"),
+    Err(err) => {
+      eprintln!("[handler-error] remove_totp: {}", err);
+      error_response(StatusCode::InternalServerError, "Failed to remove TOTP")
+    }
+  }
+}
+
+pub async fn disable_totp(req: &Request) -> Response {
+  with_auth_no_renew(req, |_req, db, validation, _token| async move {
+    let person_id = match session_person_id(&validation) {
+      Ok(id) => id,
+      Err(response) => return response,
+    };
+    if let Err(response) = require_recent_verification(&db, &validation) {
+      return response;
+    }
+    remove_totp(&db, req, person_id, TOTP_DISABLED, json!({})).await
+  })
+  .await
+}
+
+/// Administrator reset for a person who lost their authenticator.
+pub async fn reset_totp(req: &Request) -> Response {
+  let (db, validation, _) = match require_permission(req, "users.write").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  if let Err(response) = require_recent_verification(&db, &validation) {
+    return response;
+  }
+  let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return error_response(StatusCode::BadRequest, "Invalid user ID"),
+  };
+  let admin_id = validation
+    .record
+    .payload
+    .get("user_id")
+    .and_then(|value| value.as_i64());
+  remove_totp(&db, req, id, TOTP_RESET, json!({ "by": admin_id })).await
+}
+
+/// Replace the caller's recovery codes, e.g. after using several of them.
+pub async fn regenerate_recovery_codes(req: &Request) -> Response {
+  with_auth_no_renew(req, |_req, db, validation, _token| async move {
+    let person_id = match session_person_id(&validation) {
+      Ok(id) => id,
+      Err(response) => return response,
+    };
+    if let Err(response) = require_recent_verification(&db, &validation) {
+      return response;
+    }
+    match TotpManager::new(db.pool()).is_enabled(person_id).await {
+      Ok(true) => {}
+      Ok(false) => return error_response(StatusCode::Conflict, "TOTP not enabled"),
+      Err(err) => {
+        eprintln!("[handler-error] regenerate_recovery_codes: {}", err);
+        return error_response(
+          StatusCode::InternalServerError,
+          "Failed to check second factor",
+        );
+      }
+    }
+    match issue_recovery_codes(&db, req, person_id).await {
+      Ok(codes) => Response {
+        status: StatusCode::Created.to_string(),
+        content_type: "application/json".to_string(),
+        content: json!({ "recovery_codes": codes }).to_string().into_bytes(),
+      },
+      Err(response) => response,
+    }
+  })
+  .await
+}
+
+/// How many of the caller's recovery codes are left.
+pub async fn recovery_codes_status(req: &Request) -> Response {
+  with_auth_no_renew(req, |_req, db, validation, _token| async move {
+    let person_id = match session_person_id(&validation) {
+      Ok(id) => id,
+      Err(response) => return response,
+    };
+    match RecoveryCodes::new(db.pool()).counts(person_id).await {
+      Ok((remaining, used)) => Response {
+        status: StatusCode::Ok.to_string(),
+        content_type: "application/json".to_string(),
+        content: json!({ "remaining": remaining, "used": used })
+          .to_string()
+          .into_bytes(),
+      },
+      Err(err) => {
+        eprintln!("[handler-error] recovery_codes_status: {}", err);
+        error_response(
+          StatusCode::InternalServerError,
+          "Failed to fetch recovery codes",
+        )
+      }
+    }
+  })
+  .await
+}
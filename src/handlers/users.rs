@@ -1,11 +1,30 @@
-use crate::auth::TokenManager;
+use crate::auth::{SessionContext, SessionError, TokenError, TokenManager, TokenValidation};
+use crate::database::DB;
+use crate::login_challenge::{LoginChallenge, LoginChallenges};
+use crate::login_guard::{LoginGuard, LoginSubject};
+use crate::oidc::{IdTokenRequest, SigningKeys};
+use crate::password::{
+  hash_password, is_legacy_hash, verify_dummy_password, verify_legacy_password, verify_password,
+};
+use crate::password_policy::{PasswordOwner, PasswordPolicy};
+use crate::security_events::PASSWORD_CHANGED;
+use crate::totp::TotpManager;
 use httpageboy::{Request, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
+use super::impersonation::{
+  close_ended_impersonations, impersonator_id, record_impersonation_stop,
+};
+use super::oauth::payload_client_id;
+use super::permissions::Permission;
+use super::roles::Role;
+use super::security_events::record_security_event;
+use super::totp::verify_second_factor;
 use super::{
-  error_response, get_db_connection, log_access, require_token_without_renew,
-  unauthorized_response, with_auth, with_auth_no_renew,
+  authenticate_service, authenticate_token, current_epoch, error_response, extract_ip,
+  extract_token, extract_user_agent, get_db_connection, log_access, require_permission,
+  require_recent_verification, unauthorized_response, with_auth, with_auth_no_renew,
 };
 
 // Basic endpoints
@@ -21,58 +40,373 @@ pub async fn home(_req: &Request) -> Response {
 pub struct LoginPayload {
   username: String,
   password: String,
+  service_id: Option<i32>,
 }
 
 #[derive(sqlx::FromRow)]
-struct AuthUser {
-  id: i32,
+pub(super) struct AuthUser {
+  pub(super) id: i32,
   username: String,
   password_hash: String,
   name: String,
 }
 
-pub async fn login(req: &Request) -> Response {
-  let payload: LoginPayload = match serde_json::from_slice(req.body.as_bytes()) {
-    Ok(p) => p,
-    Err(_) => return error_response(StatusCode::BadRequest, "Invalid request body"),
+impl AuthUser {
+  /// Payload stored with the person's tokens.
+  pub(super) fn token_payload(&self) -> serde_json::Value {
+    json!({
+      "user_id": self.id,
+      "username": self.username,
+      "name": self.name,
+    })
+  }
+}
+
+/// Why a login, or one of its factors, was not accepted.
+pub(super) enum LoginFailure {
+  Locked {
+    retry_after: i64,
+  },
+  InvalidCredentials,
+  /// The person has a second factor and no code was given.
+  CodeRequired,
+  InvalidCode,
+  Internal(&'static str),
+}
+
+impl LoginFailure {
+  pub(super) fn message(&self) -> &'static str {
+    match self {
+      LoginFailure::Locked { .. } => "Too many failed login attempts",
+      LoginFailure::InvalidCredentials => "Invalid credentials",
+      LoginFailure::CodeRequired => "Verification code required",
+      LoginFailure::InvalidCode => "Invalid verification code",
+      LoginFailure::Internal(message) => message,
+    }
+  }
+
+  pub(super) fn status(&self) -> StatusCode {
+    match self {
+      LoginFailure::Locked { .. } => StatusCode::TooManyRequests,
+      LoginFailure::InvalidCredentials | LoginFailure::CodeRequired | LoginFailure::InvalidCode => {
+        StatusCode::Unauthorized
+      }
+      LoginFailure::Internal(_) => StatusCode::InternalServerError,
+    }
+  }
+
+  pub(super) fn into_response(self) -> Response {
+    match self {
+      LoginFailure::Locked { retry_after } => too_many_attempts_response(retry_after),
+      _ => error_response(self.status(), self.message()),
+    }
+  }
+}
+
+// Failed attempts are counted per username and per client IP. Requests without a
+// resolvable IP are only counted per username so they cannot lock each other out.
+fn login_subjects(username: &str, req: &Request) -> Vec<LoginSubject> {
+  with_client_ip(LoginSubject::Username(username.to_string()), req)
+}
+
+pub(super) fn with_client_ip(subject: LoginSubject, req: &Request) -> Vec<LoginSubject> {
+  let mut subjects = vec![subject];
+  let ip = extract_ip(req);
+  if ip != "unknown" {
+    subjects.push(LoginSubject::Ip(ip));
+  }
+  subjects
+}
+
+fn too_many_attempts_response(retry_after: i64) -> Response {
+  Response {
+    status: StatusCode::TooManyRequests.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({
+      "error": "Too many failed login attempts",
+      "retry_after": retry_after,
+    })
+    .to_string()
+    .into_bytes(),
+  }
+}
+
+fn session_limit_response(limit: i64) -> Response {
+  Response {
+    status: StatusCode::Conflict.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({
+      "error": "Session limit reached",
+      "limit": limit,
+    })
+    .to_string()
+    .into_bytes(),
+  }
+}
+
+async fn reject_login(guard: &LoginGuard<'_>, subjects: &[LoginSubject]) -> LoginFailure {
+  if let Err(err) = guard.record_failure(subjects).await {
+    eprintln!("[login-guard-error] {}", err);
+  }
+  LoginFailure::InvalidCredentials
+}
+
+/// The person a session belongs to, for operations on one's own account. Impersonation
+/// tokens are refused: they must not change the person's credentials.
+pub(super) fn session_person_id(validation: &TokenValidation) -> Result<i32, Response> {
+  let token_payload = &validation.record.payload;
+  let user_id = match token_payload
+    .get("user_id")
+    .and_then(|value| value.as_i64())
+  {
+    Some(id) => id as i32,
+    None => return Err(error_response(StatusCode::Forbidden, "Token has no user")),
   };
+  if impersonator_id(token_payload).is_some() {
+    return Err(error_response(
+      StatusCode::Forbidden,
+      "Not allowed while impersonating",
+    ));
+  }
+  if payload_client_id(token_payload).is_some() {
+    return Err(error_response(
+      StatusCode::Forbidden,
+      "Not allowed with a client token",
+    ));
+  }
+  Ok(user_id)
+}
 
-  let db = match get_db_connection().await {
-    Ok(db) => db,
-    Err(response) => return response,
+pub(super) async fn find_auth_user(
+  db: &DB,
+  person_id: i32,
+) -> Result<Option<AuthUser>, sqlx::Error> {
+  sqlx::query_as::<_, AuthUser>(
+    "SELECT id, username, password_hash, name FROM auth.person WHERE id = $1 AND removed_at IS NULL",
+  )
+  .bind(person_id)
+  .fetch_optional(db.pool())
+  .await
+}
+
+// Rewrites a legacy password_hash as Argon2id once it has been verified.
+// Failures are logged but do not block the login; the next login retries.
+async fn migrate_legacy_password(db: &DB, user: &AuthUser, password: &str) {
+  let hash = match hash_password(password).await {
+    Ok(hash) => hash,
+    Err(err) => {
+      eprintln!("[password-migration-error] user_id={} {}", user.id, err);
+      return;
+    }
   };
+  if let Err(err) =
+    sqlx::query("UPDATE auth.person SET password_hash = $1 WHERE id = $2 AND password_hash = $3")
+      .bind(hash)
+      .bind(user.id)
+      .bind(&user.password_hash)
+      .execute(db.pool())
+      .await
+  {
+    eprintln!("[password-migration-error] user_id={} {}", user.id, err);
+  }
+}
+
+/// Check a new password against [`PasswordPolicy`]. A failure answers `400` with every
+/// broken rule under `violations`.
+pub(super) fn check_password_policy(
+  password: &str,
+  owner: &PasswordOwner<'_>,
+) -> Result<(), Response> {
+  let violations = PasswordPolicy::new().check(password, owner);
+  if violations.is_empty() {
+    return Ok(());
+  }
+  Err(Response {
+    status: StatusCode::BadRequest.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({
+      "error": "Password does not meet the policy",
+      "violations": violations,
+    })
+    .to_string()
+    .into_bytes(),
+  })
+}
+
+/// [`check_password_policy`] for an existing person. `username` replaces the stored one
+/// when it changes in the same request.
+pub(super) async fn check_person_password_policy(
+  db: &DB,
+  person_id: i32,
+  username: Option<&str>,
+  password: &str,
+) -> Result<(), Response> {
+  let person = sqlx::query_as::<_, (String, String)>(
+    "SELECT username, document_number FROM auth.person WHERE id = $1 AND removed_at IS NULL",
+  )
+  .bind(person_id)
+  .fetch_optional(db.pool())
+  .await;
+  match person {
+    Ok(Some((stored_username, document_number))) => {
+      let owner = PasswordOwner {
+        username: username.unwrap_or(&stored_username),
+        document_number: &document_number,
+      };
+      check_password_policy(password, &owner)
+    }
+    Ok(None) => Err(error_response(StatusCode::NotFound, "User not found")),
+    Err(err) => {
+      eprintln!("[handler-error] check_person_password_policy: {}", err);
+      Err(error_response(
+        StatusCode::InternalServerError,
+        "Failed to fetch user",
+      ))
+    }
+  }
+}
+
+/// Check a username and password, with the lockout counters of [`LoginGuard`].
+/// Shared by every way of logging in.
+pub(super) async fn verify_login(
+  db: &DB,
+  req: &Request,
+  username: &str,
+  password: &str,
+) -> Result<AuthUser, LoginFailure> {
+  let guard = LoginGuard::new(db.pool());
+  let subjects = login_subjects(username, req);
+  match guard.retry_after(&subjects).await {
+    Ok(Some(retry_after)) => return Err(LoginFailure::Locked { retry_after }),
+    Ok(None) => {}
+    Err(_) => return Err(LoginFailure::Internal("Failed to check login attempts")),
+  }
 
   let user = match sqlx::query_as::<_, AuthUser>(
     "SELECT id, username, password_hash, name FROM auth.person WHERE username = $1 AND removed_at IS NULL",
   )
-  .bind(&payload.username)
+  .bind(username)
   .fetch_optional(db.pool())
   .await
   {
     Ok(Some(user)) => user,
-    Ok(None) => return unauthorized_response("Invalid credentials"),
-    Err(_) => {
+    Ok(None) => {
+      verify_dummy_password(password).await;
+      return Err(reject_login(&guard, &subjects).await);
+    }
+    Err(_) => return Err(LoginFailure::Internal("Failed to query user credentials")),
+  };
+
+  if is_legacy_hash(&user.password_hash) {
+    if !verify_legacy_password(password, &user.password_hash).await {
+      return Err(reject_login(&guard, &subjects).await);
+    }
+    migrate_legacy_password(db, &user, password).await;
+  } else if !verify_password(password, &user.password_hash).await {
+    return Err(reject_login(&guard, &subjects).await);
+  }
+
+  if let Err(err) = guard.reset(&subjects).await {
+    eprintln!("[login-guard-error] {}", err);
+  }
+  Ok(user)
+}
+
+pub async fn login(req: &Request) -> Response {
+  let payload: LoginPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "Invalid request body"),
+  };
+
+  let db = match get_db_connection().await {
+    Ok(db) => db,
+    Err(response) => return response,
+  };
+
+  let user = match verify_login(&db, req, &payload.username, &payload.password).await {
+    Ok(user) => user,
+    Err(failure) => return failure.into_response(),
+  };
+
+  if let Some(service_id) = payload.service_id {
+    match sqlx::query_scalar::<_, bool>("SELECT status FROM auth.services WHERE id = $1")
+      .bind(service_id)
+      .fetch_optional(db.pool())
+      .await
+    {
+      Ok(Some(true)) => {}
+      Ok(Some(false)) => return error_response(StatusCode::Forbidden, "Service is disabled"),
+      Ok(None) => return error_response(StatusCode::Forbidden, "Service not found"),
+      Err(_) => {
+        return error_response(StatusCode::InternalServerError, "Failed to load service");
+      }
+    }
+  }
+
+  // With a second factor the password only opens a challenge; `/auth/login/verify`
+  // completes it.
+  match TotpManager::new(db.pool()).is_enabled(user.id).await {
+    Ok(false) => {}
+    Ok(true) => {
+      let challenge = LoginChallenge {
+        person_id: user.id,
+        service_id: payload.service_id,
+      };
+      return match LoginChallenges::new(db.pool()).create(&challenge).await {
+        Ok((challenge_id, expires_at)) => Response {
+          status: StatusCode::Ok.to_string(),
+          content_type: "application/json".to_string(),
+          content: json!({
+            "mfa_required": true,
+            "challenge_id": challenge_id,
+            "expires_at": expires_at,
+            "methods": ["totp", "recovery_code"],
+          })
+          .to_string()
+          .into_bytes(),
+        },
+        Err(err) => {
+          eprintln!("[handler-error] login: {}", err);
+          error_response(
+            StatusCode::InternalServerError,
+            "Failed to create login challenge",
+          )
+        }
+      };
+    }
+    Err(err) => {
+      eprintln!("[handler-error] login: {}", err);
       return error_response(
         StatusCode::InternalServerError,
-        "Failed to query user credentials",
+        "Failed to check second factor",
       );
     }
-  };
-
-  if user.password_hash != payload.password {
-    return unauthorized_response("Invalid credentials");
   }
 
-  let user_payload = json!({
-    "user_id": user.id,
-    "username": user.username,
-    "name": user.name,
-  });
+  complete_login(&db, req, &user, payload.service_id).await
+}
 
+/// Open the session of a person whose login passed every factor.
+pub(super) async fn complete_login(
+  db: &DB,
+  req: &Request,
+  user: &AuthUser,
+  service_id: Option<i32>,
+) -> Response {
+  let user_payload = user.token_payload();
   let manager = TokenManager::new(db.pool());
-  let issued = match manager.issue_token(user_payload.clone()).await {
+  let context = SessionContext {
+    ip: Some(extract_ip(req)).filter(|ip| ip != "unknown"),
+    user_agent: extract_user_agent(req),
+    service_id,
+    // The password (and second factor) were checked just now.
+    verified_at: Some(current_epoch()),
+  };
+  let issued = match manager.issue_session(user_payload.clone(), &context).await {
     Ok(issue) => issue,
-    Err(_) => {
+    Err(SessionError::LimitReached { limit }) => return session_limit_response(limit),
+    Err(err) => {
+      eprintln!("[handler-error] login: {}", err);
       return error_response(
         StatusCode::InternalServerError,
         "Failed to create login token",
@@ -80,7 +414,23 @@ pub async fn login(req: &Request) -> Response {
     }
   };
 
-  log_access(&issued.token, req);
+  // A direct login has no relying party, so the ID token is addressed to the issuer.
+  let signing_keys = SigningKeys::new(db.pool());
+  let request = IdTokenRequest {
+    audience: &signing_keys.config().issuer,
+    session_id: &issued.session_id,
+    nonce: None,
+  };
+  let id_token = match signing_keys.issue_id_token(&user_payload, &request).await {
+    Ok(id_token) => id_token,
+    Err(err) => {
+      eprintln!("[handler-error] login: {}", err);
+      let _ = manager.revoke_session(&issued.session_id).await;
+      return error_response(StatusCode::InternalServerError, "Failed to sign ID token");
+    }
+  };
+
+  log_access(&issued.token, &user_payload, req, None);
 
   Response {
     status: StatusCode::Ok.to_string(),
@@ -88,6 +438,10 @@ pub async fn login(req: &Request) -> Response {
     content: json!({
       "token": issued.token,
       "expires_at": issued.expires_at,
+      "max_expires_at": issued.max_expires_at,
+      "refresh_token": issued.refresh_token,
+      "refresh_expires_at": issued.refresh_expires_at,
+      "id_token": id_token,
       "payload": user_payload,
     })
     .to_string()
@@ -95,50 +449,305 @@ pub async fn login(req: &Request) -> Response {
   }
 }
 
+#[derive(Deserialize)]
+pub struct RefreshPayload {
+  refresh_token: String,
+}
+
+pub async fn refresh_token(req: &Request) -> Response {
+  let payload: RefreshPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "Invalid request body"),
+  };
+
+  let db = match get_db_connection().await {
+    Ok(db) => db,
+    Err(response) => return response,
+  };
+
+  let manager = TokenManager::new(db.pool());
+  // Refresh tokens issued to an OAuth client are only accepted on /oauth/token.
+  match manager.refresh_token_payload(&payload.refresh_token).await {
+    Ok(Some(stored)) if stored.get("client_id").is_some() => {
+      return unauthorized_response("Invalid refresh token");
+    }
+    Ok(_) => {}
+    Err(err) => {
+      eprintln!("[handler-error] refresh_token: {}", err);
+      return error_response(StatusCode::InternalServerError, "Failed to refresh token");
+    }
+  }
+  let issued = match manager.refresh_session(&payload.refresh_token).await {
+    Ok(issue) => issue,
+    Err(TokenError::NotFound) => return unauthorized_response("Invalid refresh token"),
+    Err(TokenError::Expired) => return unauthorized_response("Expired refresh token"),
+    Err(TokenError::Reused) => return unauthorized_response("Refresh token reuse detected"),
+    Err(TokenError::Database(err)) => {
+      eprintln!("[handler-error] refresh_token: {}", err);
+      return error_response(StatusCode::InternalServerError, "Failed to refresh token");
+    }
+  };
+
+  log_access(&issued.token, &issued.payload, req, None);
+
+  Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({
+      "token": issued.token,
+      "expires_at": issued.expires_at,
+      "max_expires_at": issued.max_expires_at,
+      "refresh_token": issued.refresh_token,
+      "refresh_expires_at": issued.refresh_expires_at,
+      "payload": issued.payload,
+    })
+    .to_string()
+    .into_bytes(),
+  }
+}
+
+#[derive(Deserialize)]
+pub struct UnlockPayload {
+  username: Option<String>,
+  ip: Option<String>,
+}
+
+pub async fn unlock_login(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "users.write").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let payload: UnlockPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "Invalid request body"),
+  };
+  let mut subjects = Vec::new();
+  if let Some(username) = payload.username {
+    subjects.push(LoginSubject::Username(username));
+  }
+  if let Some(ip) = payload.ip {
+    subjects.push(LoginSubject::Ip(ip));
+  }
+  if subjects.is_empty() {
+    return error_response(StatusCode::BadRequest, "Invalid request body");
+  }
+  match LoginGuard::new(db.pool()).reset(&subjects).await {
+    Ok(cleared) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: json!({ "status": "unlocked", "cleared": cleared })
+        .to_string()
+        .into_bytes(),
+    },
+    Err(_) => error_response(StatusCode::InternalServerError, "Failed to unlock login"),
+  }
+}
+
 pub async fn logout(req: &Request) -> Response {
-  with_auth_no_renew(req, |_req, db, _, token| async move {
+  with_auth_no_renew(req, |_req, db, validation, _token| async move {
     let manager = TokenManager::new(db.pool());
-    match manager.delete_token(&token).await {
-      Ok(_) => Response {
+    // Logging out also retires the refresh token issued with this session.
+    match manager.revoke_session(&validation.record.session_id).await {
+      Ok(_) => {
+        record_impersonation_stop(
+          &db,
+          req,
+          &validation.record.payload,
+          &validation.record.session_id,
+        )
+        .await;
+        Response {
+          status: StatusCode::Ok.to_string(),
+          content_type: "application/json".to_string(),
+          content: json!({ "status": "logged_out" }).to_string().into_bytes(),
+        }
+      }
+      Err(_) => error_response(StatusCode::InternalServerError, "Failed to revoke token"),
+    }
+  })
+  .await
+}
+
+#[derive(Deserialize)]
+pub struct ReauthenticatePayload {
+  password: String,
+  code: Option<String>,
+}
+
+/// Check the password (and TOTP code, when enabled) again for the caller's session,
+/// unlocking sensitive operations for `REAUTH_MAX_AGE_SECONDS`.
+pub async fn reauthenticate(req: &Request) -> Response {
+  let payload: ReauthenticatePayload = match serde_json::from_str(&req.body) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "Invalid request body"),
+  };
+  with_auth_no_renew(req, |_req, db, validation, _token| async move {
+    let user_id = match session_person_id(&validation) {
+      Ok(id) => id,
+      Err(response) => return response,
+    };
+    let username = match sqlx::query_scalar::<_, String>(
+      "SELECT username FROM auth.person WHERE id = $1 AND removed_at IS NULL",
+    )
+    .bind(user_id)
+    .fetch_optional(db.pool())
+    .await
+    {
+      Ok(Some(username)) => username,
+      Ok(None) => return error_response(StatusCode::NotFound, "User not found"),
+      Err(err) => {
+        eprintln!("[handler-error] reauthenticate: {}", err);
+        return error_response(StatusCode::InternalServerError, "Failed to fetch user");
+      }
+    };
+    if let Err(failure) = verify_login(&db, req, &username, &payload.password).await {
+      return failure.into_response();
+    }
+    if let Err(failure) = verify_second_factor(&db, req, user_id, payload.code.as_deref()).await {
+      return failure.into_response();
+    }
+    match TokenManager::new(db.pool())
+      .mark_verified(&validation.record.session_id)
+      .await
+    {
+      Ok(verified_at) => Response {
         status: StatusCode::Ok.to_string(),
         content_type: "application/json".to_string(),
-        content: json!({ "status": "logged_out" }).to_string().into_bytes(),
+        content: json!({ "status": "verified", "verified_at": verified_at })
+          .to_string()
+          .into_bytes(),
       },
-      Err(_) => error_response(StatusCode::InternalServerError, "Failed to revoke token"),
+      Err(err) => {
+        eprintln!("[handler-error] reauthenticate: {}", err);
+        error_response(
+          StatusCode::InternalServerError,
+          "Failed to record verification",
+        )
+      }
     }
   })
   .await
 }
 
-pub async fn profile(req: &Request) -> Response {
-  with_auth(req, true, |_req, _db, validation, _token| async move {
-    let payload = validation.record.payload.clone();
+#[derive(Deserialize)]
+pub struct ChangePasswordPayload {
+  current_password: String,
+  new_password: String,
+  code: Option<String>,
+}
+
+/// Change the caller's own password. Other sessions of the person end; the calling one
+/// stays and counts as freshly verified.
+pub async fn change_password(req: &Request) -> Response {
+  let payload: ChangePasswordPayload = match serde_json::from_str(&req.body) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "Invalid request body"),
+  };
+  with_auth_no_renew(req, |_req, db, validation, _token| async move {
+    let user_id = match session_person_id(&validation) {
+      Ok(id) => id,
+      Err(response) => return response,
+    };
+    let username = match sqlx::query_scalar::<_, String>(
+      "SELECT username FROM auth.person WHERE id = $1 AND removed_at IS NULL",
+    )
+    .bind(user_id)
+    .fetch_optional(db.pool())
+    .await
+    {
+      Ok(Some(username)) => username,
+      Ok(None) => return error_response(StatusCode::NotFound, "User not found"),
+      Err(err) => {
+        eprintln!("[handler-error] change_password: {}", err);
+        return error_response(StatusCode::InternalServerError, "Failed to fetch user");
+      }
+    };
+    if let Err(failure) = verify_login(&db, req, &username, &payload.current_password).await {
+      return failure.into_response();
+    }
+    if let Err(failure) = verify_second_factor(&db, req, user_id, payload.code.as_deref()).await {
+      return failure.into_response();
+    }
+    if payload.new_password == payload.current_password {
+      return error_response(
+        StatusCode::BadRequest,
+        "New password must differ from the current one",
+      );
+    }
+    if let Err(response) =
+      check_person_password_policy(&db, user_id, None, &payload.new_password).await
+    {
+      return response;
+    }
+
+    let password_hash = match hash_password(&payload.new_password).await {
+      Ok(hash) => hash,
+      Err(err) => {
+        eprintln!("[handler-error] change_password: {}", err);
+        return error_response(StatusCode::InternalServerError, "Failed to hash password");
+      }
+    };
+    if let Err(err) = sqlx::query("UPDATE auth.person SET password_hash = $1 WHERE id = $2")
+      .bind(password_hash)
+      .bind(user_id)
+      .execute(db.pool())
+      .await
+    {
+      eprintln!("[handler-error] change_password: {}", err);
+      return error_response(StatusCode::InternalServerError, "Failed to update password");
+    }
+
+    let manager = TokenManager::new(db.pool());
+    let session_id = &validation.record.session_id;
+    let revoked = match manager
+      .delete_other_sessions_for_user(user_id, session_id)
+      .await
+    {
+      Ok(revoked) => revoked,
+      Err(err) => {
+        eprintln!("[handler-error] change_password: {}", err);
+        return error_response(
+          StatusCode::InternalServerError,
+          "Failed to revoke other sessions",
+        );
+      }
+    };
+    close_ended_impersonations(&db, None).await;
+    if let Err(err) = manager.mark_verified(session_id).await {
+      eprintln!("[handler-error] change_password: {}", err);
+    }
+    record_security_event(
+      &db,
+      req,
+      user_id,
+      PASSWORD_CHANGED,
+      json!({ "revoked_sessions": revoked }),
+    )
+    .await;
+
     Response {
       status: StatusCode::Ok.to_string(),
       content_type: "application/json".to_string(),
-      content: json!({
-        "payload": payload,
-        "renewed": validation.renewed,
-        "expires_at": validation.expires_at,
-      })
-      .to_string()
-      .into_bytes(),
+      content: json!({ "status": "password_changed", "revoked_sessions": revoked })
+        .to_string()
+        .into_bytes(),
     }
   })
   .await
 }
 
-pub async fn check_token(req: &Request) -> Response {
+pub async fn profile(req: &Request) -> Response {
   with_auth(req, true, |_req, _db, validation, _token| async move {
     let payload = validation.record.payload.clone();
     Response {
       status: StatusCode::Ok.to_string(),
       content_type: "application/json".to_string(),
       content: json!({
-        "valid": true,
         "payload": payload,
         "renewed": validation.renewed,
         "expires_at": validation.expires_at,
+        "max_expires_at": validation.max_expires_at,
+        "verified_at": validation.record.verified_at,
       })
       .to_string()
       .into_bytes(),
@@ -147,6 +756,137 @@ pub async fn check_token(req: &Request) -> Response {
   .await
 }
 
+#[derive(Deserialize, Default)]
+pub struct CheckTokenPayload {
+  token: Option<String>,
+  service_id: Option<i32>,
+  user_id: Option<i32>,
+}
+
+fn invalid_check_response(status_code: StatusCode, message: &str) -> Response {
+  Response {
+    status: status_code.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({ "valid": false, "error": message })
+      .to_string()
+      .into_bytes(),
+  }
+}
+
+pub async fn check_token(req: &Request) -> Response {
+  let payload: CheckTokenPayload = if req.body.trim().is_empty() {
+    CheckTokenPayload::default()
+  } else {
+    match serde_json::from_slice(req.body.as_bytes()) {
+      Ok(p) => p,
+      Err(_) => return error_response(StatusCode::BadRequest, "Invalid request body"),
+    }
+  };
+  let caller = match authenticate_service(req).await {
+    Ok(caller) => caller,
+    Err(response) => return response,
+  };
+  // A service authenticated by API key may only check tokens against itself.
+  let service_id = match (&caller, payload.service_id) {
+    (Some(caller), Some(requested)) if requested != caller.service_id => {
+      return invalid_check_response(StatusCode::Forbidden, "API key does not belong to service");
+    }
+    (Some(caller), _) => Some(caller.service_id),
+    (None, requested) => requested,
+  };
+  let token = match extract_token(req).or(payload.token) {
+    Some(token) => token,
+    None => return unauthorized_response("Missing token header"),
+  };
+  let (db, validation, _token) = match authenticate_token(req, token, true, caller.as_ref()).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+
+  let token_user_id = validation
+    .record
+    .payload
+    .get("user_id")
+    .and_then(|value| value.as_i64())
+    .map(|id| id as i32);
+  if let Some(expected) = payload.user_id
+    && token_user_id != Some(expected)
+  {
+    return invalid_check_response(StatusCode::Unauthorized, "Token does not belong to user");
+  }
+
+  let mut body = json!({
+    "valid": true,
+    "payload": validation.record.payload,
+    "renewed": validation.renewed,
+    "expires_at": validation.expires_at,
+    "max_expires_at": validation.max_expires_at,
+    "verified_at": validation.record.verified_at,
+  });
+
+  if let Some(service_id) = service_id {
+    let person_id = match token_user_id {
+      Some(id) => id,
+      None => return invalid_check_response(StatusCode::Forbidden, "Token has no user"),
+    };
+    match sqlx::query_scalar::<_, bool>("SELECT status FROM auth.services WHERE id = $1")
+      .bind(service_id)
+      .fetch_optional(db.pool())
+      .await
+    {
+      Ok(Some(true)) => {}
+      Ok(Some(false)) => {
+        return invalid_check_response(StatusCode::Forbidden, "Service is disabled");
+      }
+      Ok(None) => return invalid_check_response(StatusCode::Forbidden, "Service not found"),
+      Err(_) => return error_response(StatusCode::InternalServerError, "Failed to fetch service"),
+    }
+    let roles =
+      match sqlx::query_as::<_, Role>("SELECT * FROM auth.list_person_roles_in_service($1, $2)")
+        .bind(person_id)
+        .bind(service_id)
+        .fetch_all(db.pool())
+        .await
+      {
+        Ok(roles) => roles,
+        Err(_) => {
+          return error_response(
+            StatusCode::InternalServerError,
+            "Failed to fetch person roles in service",
+          );
+        }
+      };
+    if roles.is_empty() {
+      return invalid_check_response(StatusCode::Forbidden, "Person has no role in service");
+    }
+    let permissions = match sqlx::query_as::<_, Permission>(
+      "SELECT * FROM auth.list_person_permissions_in_service($1, $2)",
+    )
+    .bind(person_id)
+    .bind(service_id)
+    .fetch_all(db.pool())
+    .await
+    {
+      Ok(permissions) => permissions,
+      Err(_) => {
+        return error_response(
+          StatusCode::InternalServerError,
+          "Failed to fetch person permissions in service",
+        );
+      }
+    };
+    body["service_id"] = json!(service_id);
+    body["roles"] = json!(roles);
+    body["permissions"] = json!(permissions);
+  }
+
+  Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "application/json".to_string(),
+    content: body.to_string().into_bytes(),
+  }
+}
+
 // User Handlers
 #[derive(Serialize, sqlx::FromRow)]
 pub struct User {
@@ -158,15 +898,18 @@ pub struct User {
 #[derive(Deserialize)]
 pub struct CreateUserPayload {
   username: String,
-  password_hash: String,
+  #[serde(alias = "password_hash")]
+  password: String,
   name: String,
   person_type: String,   // N or J
   document_type: String, // DNI, CE, or RUC
   document_number: String,
+  /// Where password reset tokens are sent.
+  email: Option<String>,
 }
 
 pub async fn create_user(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "users.write").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -183,15 +926,32 @@ pub async fn create_user(req: &Request) -> Response {
     serde_json::from_str(&format!("\"{}\"", payload.document_type))
       .unwrap_or(auth_types::DocumentType::DNI);
 
+  let owner = PasswordOwner {
+    username: &payload.username,
+    document_number: &payload.document_number,
+  };
+  if let Err(response) = check_password_policy(&payload.password, &owner) {
+    return response;
+  }
+
+  let password_hash = match hash_password(&payload.password).await {
+    Ok(hash) => hash,
+    Err(err) => {
+      eprintln!("[handler-error] create_user: {}", err);
+      return error_response(StatusCode::InternalServerError, "Failed to hash password");
+    }
+  };
+
   match sqlx::query_as::<_, User>(
-    "SELECT id, username, name FROM auth.create_person($1, $2, $3, $4, $5, $6)",
+    "SELECT id, username, name FROM auth.create_person($1, $2, $3, $4, $5, $6, $7)",
   )
   .bind(payload.username)
-  .bind(payload.password_hash)
+  .bind(password_hash)
   .bind(payload.name)
   .bind(person_type)
   .bind(document_type)
   .bind(payload.document_number)
+  .bind(payload.email)
   .fetch_one(db.pool())
   .await
   {
@@ -205,7 +965,7 @@ pub async fn create_user(req: &Request) -> Response {
 }
 
 pub async fn list_people(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "users.read").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -223,7 +983,7 @@ pub async fn list_people(req: &Request) -> Response {
 }
 
 pub async fn get_user(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, _, _) = match require_permission(req, "users.read").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -249,12 +1009,14 @@ pub async fn get_user(req: &Request) -> Response {
 #[derive(Deserialize)]
 pub struct UpdateUserPayload {
   username: Option<String>,
-  password_hash: Option<String>,
+  #[serde(alias = "password_hash")]
+  password: Option<String>,
   name: Option<String>,
+  email: Option<String>,
 }
 
 pub async fn update_user(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, validation, _) = match require_permission(req, "users.write").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -266,11 +1028,34 @@ pub async fn update_user(req: &Request) -> Response {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "Invalid request body"),
   };
-  match sqlx::query("CALL auth.update_person($1, $2, $3, $4)")
+  // The email receives password resets, so changing it is as sensitive as the password.
+  if (payload.password.is_some() || payload.email.is_some())
+    && let Err(response) = require_recent_verification(&db, &validation)
+  {
+    return response;
+  }
+  if let Some(password) = &payload.password
+    && let Err(response) =
+      check_person_password_policy(&db, id, payload.username.as_deref(), password).await
+  {
+    return response;
+  }
+  let password_hash = match payload.password {
+    Some(password) => match hash_password(&password).await {
+      Ok(hash) => Some(hash),
+      Err(err) => {
+        eprintln!("[handler-error] update_user: {}", err);
+        return error_response(StatusCode::InternalServerError, "Failed to hash password");
+      }
+    },
+    None => None,
+  };
+  match sqlx::query("CALL auth.update_person($1, $2, $3, $4, $5)")
     .bind(id)
     .bind(payload.username)
-    .bind(payload.password_hash)
+    .bind(password_hash)
     .bind(payload.name)
+    .bind(payload.email)
     .execute(db.pool())
     .await
   {
@@ -284,10 +1069,13 @@ pub async fn update_user(req: &Request) -> Response {
 }
 
 pub async fn delete_user(req: &Request) -> Response {
-  let (db, _, _) = match require_token_without_renew(req).await {
+  let (db, validation, _) = match require_permission(req, "users.write").await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
+  if let Err(response) = require_recent_verification(&db, &validation) {
+    return response;
+  }
   let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
     Some(id) => id,
     None => return error_response(StatusCode::BadRequest, "Invalid user ID"),
@@ -299,11 +1087,14 @@ pub async fn delete_user(req: &Request) -> Response {
     .await
   {
     Ok(_) => match manager.delete_tokens_for_user(id).await {
-      Ok(_) => Response {
-        status: StatusCode::NoContent.to_string(),
-        content_type: "application/json".to_string(),
-        content: Vec::new(),
-      },
+      Ok(_) => {
+        close_ended_impersonations(&db, None).await;
+        Response {
+          status: StatusCode::NoContent.to_string(),
+          content_type: "application/json".to_string(),
+          content: Vec::new(),
+        }
+      }
       Err(_) => error_response(
         StatusCode::InternalServerError,
         "Failed to remove user tokens",
@@ -313,6 +1104,35 @@ pub async fn delete_user(req: &Request) -> Response {
   }
 }
 
+#[derive(Serialize, sqlx::FromRow)]
+pub struct PasswordHashReport {
+  legacy_count: i64,
+  total_count: i64,
+}
+
+pub async fn legacy_password_report(req: &Request) -> Response {
+  let (db, _, _) = match require_permission(req, "users.read").await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  match sqlx::query_as::<_, PasswordHashReport>(
+    "SELECT legacy_count, total_count FROM auth.password_hash_report()",
+  )
+  .fetch_one(db.pool())
+  .await
+  {
+    Ok(report) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&report).unwrap(),
+    },
+    Err(_) => error_response(
+      StatusCode::InternalServerError,
+      "Failed to build password report",
+    ),
+  }
+}
+
 // These are needed for the create_person handler to deserialize the enums
 mod auth_types {
   use serde::Deserialize;
@@ -323,6 +1143,7 @@ mod auth_types {
     J,
   }
 
+  #[allow(clippy::upper_case_acronyms)]
   #[derive(Debug, Deserialize, sqlx::Type)]
   #[sqlx(type_name = "document_type", rename_all = "UPPERCASE")]
   pub enum DocumentType {
@@ -0,0 +1,106 @@
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+
+/// One line of the impersonation audit trail.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ImpersonationEvent {
+  pub id: i64,
+  /// `start` or `stop`.
+  pub event: String,
+  pub impersonator_id: i32,
+  pub person_id: i32,
+  pub session_id: String,
+  pub reason: Option<String>,
+  pub ip: Option<String>,
+  pub user_agent: Option<String>,
+  pub created_at: i64,
+}
+
+/// What is recorded when an impersonation starts or stops.
+pub struct ImpersonationRecord<'a> {
+  pub impersonator_id: i32,
+  pub person_id: i32,
+  pub session_id: &'a str,
+  pub reason: Option<&'a str>,
+  pub ip: Option<&'a str>,
+  pub user_agent: Option<&'a str>,
+}
+
+/// Audit trail of administrators acting as other people, in `auth.impersonation_audit`.
+/// Rows are never updated or deleted by the API. Every session has one `start` and at
+/// most one `stop`.
+pub struct ImpersonationAudit<'a> {
+  pool: &'a Pool<Postgres>,
+}
+
+impl<'a> ImpersonationAudit<'a> {
+  pub fn new(pool: &'a Pool<Postgres>) -> Self {
+    Self { pool }
+  }
+
+  async fn record(&self, event: &str, record: &ImpersonationRecord<'_>) -> Result<(), sqlx::Error> {
+    sqlx::query(
+      "INSERT INTO auth.impersonation_audit (event, impersonator_id, person_id, session_id, reason, ip, user_agent)
+       VALUES ($1, $2, $3, $4, $5, $6, $7)
+       ON CONFLICT DO NOTHING",
+    )
+    .bind(event)
+    .bind(record.impersonator_id)
+    .bind(record.person_id)
+    .bind(record.session_id)
+    .bind(record.reason)
+    .bind(record.ip)
+    .bind(record.user_agent)
+    .execute(self.pool)
+    .await?;
+    Ok(())
+  }
+
+  pub async fn record_start(&self, record: &ImpersonationRecord<'_>) -> Result<(), sqlx::Error> {
+    self.record("start", record).await
+  }
+
+  /// A session that already has its `stop` is left as it is.
+  pub async fn record_stop(&self, record: &ImpersonationRecord<'_>) -> Result<(), sqlx::Error> {
+    self.record("stop", record).await
+  }
+
+  /// Record the `stop` of impersonation sessions whose token is gone without one, e.g.
+  /// after it expired or was revoked with the person's other sessions. Only
+  /// `session_id` is checked when given. Returns how many sessions were closed.
+  pub async fn close_ended(&self, session_id: Option<&str>) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query(
+      "INSERT INTO auth.impersonation_audit (event, impersonator_id, person_id, session_id)
+       SELECT 'stop', s.impersonator_id, s.person_id, s.session_id
+       FROM auth.impersonation_audit s
+       WHERE s.event = 'start'
+         AND ($1::TEXT IS NULL OR s.session_id = $1)
+         AND NOT EXISTS (
+           SELECT 1 FROM auth.impersonation_audit e WHERE e.event = 'stop' AND e.session_id = s.session_id
+         )
+         AND NOT EXISTS (SELECT 1 FROM auth.tokens_cache t WHERE t.session_id = s.session_id)
+       ON CONFLICT DO NOTHING",
+    )
+    .bind(session_id)
+    .execute(self.pool)
+    .await?
+    .rows_affected();
+    Ok(rows)
+  }
+
+  /// Events where `person_id` was impersonated or was the impersonator, newest first.
+  pub async fn list_for_person(
+    &self,
+    person_id: i32,
+  ) -> Result<Vec<ImpersonationEvent>, sqlx::Error> {
+    sqlx::query_as::<_, ImpersonationEvent>(
+      "SELECT id, event, impersonator_id, person_id, session_id, reason, ip, user_agent, created_at
+       FROM auth.impersonation_audit
+       WHERE person_id = $1 OR impersonator_id = $1
+       ORDER BY created_at DESC, id DESC",
+    )
+    .bind(person_id)
+    .fetch_all(self.pool)
+    .await
+  }
+}
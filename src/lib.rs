@@ -1,11 +1,28 @@
 use httpageboy::{Rt, Server, handler};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::time::Duration;
+mod access_log;
+mod api_keys;
 pub mod auth;
+pub mod config;
 mod database;
 mod handlers;
+mod impersonation;
+mod login_challenge;
+mod login_guard;
+mod notifier;
+mod oauth;
+mod oidc;
+mod password;
+mod password_policy;
+mod password_reset;
+mod recovery_codes;
+mod security_events;
+mod totp;
 use crate::handlers::*;
+pub use crate::password_policy::{BreachedListError, load_breached_list};
 
-async fn token_cleanup_loop(config: auth::TokenConfig) {
+async fn token_cleanup_loop(db: database::DB, config: auth::TokenConfig) {
   let interval_seconds = if config.ttl_seconds > 0 {
     (config.ttl_seconds / 2).max(30)
   } else {
@@ -13,60 +30,283 @@ async fn token_cleanup_loop(config: auth::TokenConfig) {
   } as u64;
 
   loop {
-    match database::DB::new().await {
-      Ok(db) => {
-        let manager = auth::TokenManager::new(db.pool());
-        match manager.cleanup_expired().await {
-          Ok(removed) => {
-            if removed > 0 {
-              println!("[cleanup] removed {} expired tokens", removed);
-            }
-          }
-          Err(err) => {
-            eprintln!("[cleanup-error] {}", err);
-          }
+    let manager = auth::TokenManager::new(db.pool());
+    match manager.cleanup_expired().await {
+      Ok(removed) => {
+        if removed > 0 {
+          println!("[cleanup] removed {} expired tokens", removed);
         }
       }
       Err(err) => {
-        eprintln!("[cleanup-db-error] {}", err);
+        eprintln!("[cleanup-error] {}", err);
+      }
+    }
+    let audit = impersonation::ImpersonationAudit::new(db.pool());
+    match audit.close_ended(None).await {
+      Ok(closed) => {
+        if closed > 0 {
+          println!("[cleanup] closed {} ended impersonation sessions", closed);
+        }
+      }
+      Err(err) => {
+        eprintln!("[cleanup-error] {}", err);
+      }
+    }
+    let oauth_clients = oauth::OAuthClientManager::new(db.pool());
+    match oauth_clients.cleanup_expired_codes().await {
+      Ok(removed) => {
+        if removed > 0 {
+          println!("[cleanup] removed {} expired authorization codes", removed);
+        }
+      }
+      Err(err) => {
+        eprintln!("[cleanup-error] {}", err);
+      }
+    }
+    let signing_keys = oidc::SigningKeys::new(db.pool());
+    match signing_keys.cleanup_retired().await {
+      Ok(removed) => {
+        if removed > 0 {
+          println!("[cleanup] removed {} retired signing keys", removed);
+        }
+      }
+      Err(err) => {
+        eprintln!("[cleanup-error] {}", err);
+      }
+    }
+    let guard = login_guard::LoginGuard::new(db.pool());
+    match guard.cleanup_stale().await {
+      Ok(removed) => {
+        if removed > 0 {
+          println!("[cleanup] removed {} stale login counters", removed);
+        }
+      }
+      Err(err) => {
+        eprintln!("[cleanup-error] {}", err);
+      }
+    }
+    let resets = password_reset::PasswordResets::new(db.pool());
+    match resets.cleanup_expired().await {
+      Ok(removed) => {
+        if removed > 0 {
+          println!(
+            "[cleanup] removed {} expired password reset tokens",
+            removed
+          );
+        }
+      }
+      Err(err) => {
+        eprintln!("[cleanup-error] {}", err);
+      }
+    }
+    let challenges = login_challenge::LoginChallenges::new(db.pool());
+    match challenges.cleanup_expired().await {
+      Ok(removed) => {
+        if removed > 0 {
+          println!("[cleanup] removed {} expired login challenges", removed);
+        }
+      }
+      Err(err) => {
+        eprintln!("[cleanup-error] {}", err);
+      }
+    }
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs() as i64;
+    match access_log::cleanup_expired(&db, now).await {
+      Ok(removed) => {
+        if removed > 0 {
+          println!("[cleanup] removed {} expired access log entries", removed);
+        }
+      }
+      Err(err) => {
+        eprintln!("[cleanup-error] {}", err);
       }
     }
     tokio::time::sleep(Duration::from_secs(interval_seconds)).await;
   }
 }
 
-fn spawn_token_cleanup_job() {
+fn spawn_token_cleanup_job(db: database::DB) {
   let config = auth::TokenConfig::load();
-  tokio::spawn(token_cleanup_loop(config));
+  tokio::spawn(token_cleanup_loop(db, config));
+}
+
+/// Settings read on demand by the handlers, checked once so that a missing or incomplete
+/// one stops the server at startup instead of failing requests later.
+pub fn check_settings() -> Result<(), config::ConfigError> {
+  notifier::NotifierConfig::load()?;
+  oauth::OAuthConfig::load()?;
+  oidc::OidcConfig::load()?;
+  totp::TotpConfig::load()?;
+  Ok(())
 }
 
-pub async fn auth_server(url: &str, _threads_number: u8) -> Server {
+pub async fn auth_server(url: &str) -> Server {
+  let db = database::DB::shared()
+    .await
+    .expect("Failed to connect to database");
+
   let mut server = Server::new(url, None)
     .await
     .expect("Failed to create server");
 
-  spawn_token_cleanup_job();
+  match oidc::SigningKeys::new(db.pool())
+    .encrypt_stored_keys()
+    .await
+  {
+    Ok(0) => {}
+    Ok(count) => println!("[signing-keys] encrypted {} stored private keys", count),
+    Err(err) => eprintln!("[signing-keys-error] {}", err),
+  }
+
+  spawn_token_cleanup_job(db);
 
   server.add_route("/", Rt::GET, handler!(home));
 
   // Auth
   server.add_route("/auth/login", Rt::POST, handler!(login));
+  server.add_route("/auth/login/verify", Rt::POST, handler!(login_verify));
   server.add_route("/auth/logout", Rt::POST, handler!(logout));
+  server.add_route("/auth/refresh", Rt::POST, handler!(refresh_token));
   server.add_route("/auth/profile", Rt::GET, handler!(profile));
+  server.add_route("/auth/unlock", Rt::POST, handler!(unlock_login));
+  server.add_route("/auth/reauthenticate", Rt::POST, handler!(reauthenticate));
+  server.add_route("/auth/password", Rt::POST, handler!(change_password));
+  server.add_route("/auth/password/forgot", Rt::POST, handler!(forgot_password));
+  server.add_route("/auth/password/reset", Rt::POST, handler!(reset_password));
+  server.add_route("/auth/totp", Rt::POST, handler!(enroll_totp));
+  server.add_route("/auth/totp", Rt::DELETE, handler!(disable_totp));
+  server.add_route("/auth/totp/confirm", Rt::POST, handler!(confirm_totp));
+  server.add_route(
+    "/auth/totp/recovery-codes",
+    Rt::GET,
+    handler!(recovery_codes_status),
+  );
+  server.add_route(
+    "/auth/totp/recovery-codes",
+    Rt::POST,
+    handler!(regenerate_recovery_codes),
+  );
+  server.add_route(
+    "/auth/security-events",
+    Rt::GET,
+    handler!(list_own_security_events),
+  );
+  server.add_route("/auth/sessions", Rt::GET, handler!(list_sessions));
+  server.add_route("/auth/sessions/{id}", Rt::DELETE, handler!(revoke_session));
+  server.add_route(
+    "/auth/impersonation/stop",
+    Rt::POST,
+    handler!(stop_impersonation),
+  );
   server.add_route("/check-token", Rt::POST, handler!(check_token));
 
+  // OAuth
+  server.add_route("/oauth/authorize", Rt::GET, handler!(oauth_authorize_page));
+  server.add_route("/oauth/authorize", Rt::POST, handler!(oauth_authorize));
+  server.add_route("/oauth/token", Rt::POST, handler!(oauth_token));
+  server.add_route("/oauth/introspect", Rt::POST, handler!(oauth_introspect));
+  server.add_route("/oauth/revoke", Rt::POST, handler!(oauth_revoke));
+
+  // OpenID Connect
+  server.add_route(
+    "/.well-known/openid-configuration",
+    Rt::GET,
+    handler!(openid_configuration),
+  );
+  server.add_route("/.well-known/jwks.json", Rt::GET, handler!(jwks));
+  server.add_route("/userinfo", Rt::GET, handler!(userinfo));
+  server.add_route("/userinfo", Rt::POST, handler!(userinfo));
+  server.add_route("/oidc/keys", Rt::GET, handler!(list_signing_keys));
+  server.add_route("/oidc/keys/rotate", Rt::POST, handler!(rotate_signing_key));
+
   // Users
   server.add_route("/users", Rt::GET, handler!(list_people));
   server.add_route("/users", Rt::POST, handler!(create_user));
+  server.add_route(
+    "/users/password-report",
+    Rt::GET,
+    handler!(legacy_password_report),
+  );
   server.add_route("/users/{id}", Rt::GET, handler!(get_user));
   server.add_route("/users/{id}", Rt::PUT, handler!(update_user));
   server.add_route("/users/{id}", Rt::DELETE, handler!(delete_user));
+  server.add_route(
+    "/users/{id}/sessions",
+    Rt::DELETE,
+    handler!(revoke_user_sessions),
+  );
+  server.add_route("/users/{id}/totp", Rt::DELETE, handler!(reset_totp));
+  server.add_route(
+    "/users/{id}/security-events",
+    Rt::GET,
+    handler!(list_security_events),
+  );
+  server.add_route(
+    "/users/{id}/impersonate",
+    Rt::POST,
+    handler!(start_impersonation),
+  );
+  server.add_route(
+    "/users/{id}/impersonations",
+    Rt::GET,
+    handler!(list_impersonations),
+  );
 
   // Services
   server.add_route("/services", Rt::GET, handler!(list_services));
   server.add_route("/services", Rt::POST, handler!(create_service));
   server.add_route("/services/{id}", Rt::PUT, handler!(update_service));
   server.add_route("/services/{id}", Rt::DELETE, handler!(delete_service));
+  server.add_route("/services/{id}/api-keys", Rt::GET, handler!(list_api_keys));
+  server.add_route("/services/{id}/api-keys", Rt::POST, handler!(issue_api_key));
+  server.add_route(
+    "/services/{id}/api-keys/{key_id}",
+    Rt::DELETE,
+    handler!(revoke_api_key),
+  );
+  server.add_route(
+    "/services/{id}/api-keys/{key_id}/rotate",
+    Rt::POST,
+    handler!(rotate_api_key),
+  );
+  server.add_route(
+    "/services/{id}/oauth-clients",
+    Rt::GET,
+    handler!(list_oauth_clients),
+  );
+  server.add_route(
+    "/services/{id}/oauth-clients",
+    Rt::POST,
+    handler!(register_oauth_client),
+  );
+  server.add_route(
+    "/services/{id}/oauth-clients/{client_id}",
+    Rt::DELETE,
+    handler!(delete_oauth_client),
+  );
+  server.add_route(
+    "/services/{id}/oauth-clients/{client_id}/rotate",
+    Rt::POST,
+    handler!(rotate_oauth_client_secret),
+  );
+  server.add_route(
+    "/services/{id}/redirect-uris",
+    Rt::GET,
+    handler!(list_redirect_uris),
+  );
+  server.add_route(
+    "/services/{id}/redirect-uris",
+    Rt::POST,
+    handler!(add_redirect_uri),
+  );
+  server.add_route(
+    "/services/{id}/redirect-uris/{uri_id}",
+    Rt::DELETE,
+    handler!(remove_redirect_uri),
+  );
 
   // Roles
   server.add_route("/roles", Rt::GET, handler!(list_roles));
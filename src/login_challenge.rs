@@ -0,0 +1,104 @@
+use crate::auth::TokenManager;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sqlx::{Pool, Postgres};
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone)]
+pub struct LoginChallengeConfig {
+  pub ttl_seconds: i64,
+}
+
+impl LoginChallengeConfig {
+  pub fn load() -> Self {
+    let ttl_seconds = env::var("LOGIN_CHALLENGE_TTL_SECONDS")
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .filter(|v| *v > 0)
+      .unwrap_or(300);
+    Self { ttl_seconds }
+  }
+}
+
+/// A login whose password was accepted and that waits for its second factor.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct LoginChallenge {
+  pub person_id: i32,
+  /// Service named in the login; the session is opened for it once the challenge is met.
+  pub service_id: Option<i32>,
+}
+
+/// Pending second login steps in `auth.login_challenges`, stored by the SHA-256 of their id.
+pub struct LoginChallenges<'a> {
+  pool: &'a Pool<Postgres>,
+  config: LoginChallengeConfig,
+}
+
+impl<'a> LoginChallenges<'a> {
+  pub fn new(pool: &'a Pool<Postgres>) -> Self {
+    let config = LoginChallengeConfig::load();
+    Self { pool, config }
+  }
+
+  fn now_epoch() -> i64 {
+    SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs() as i64
+  }
+
+  /// Open a challenge; returns its id and expiry.
+  pub async fn create(&self, challenge: &LoginChallenge) -> Result<(String, i64), sqlx::Error> {
+    let mut random = [0u8; 32];
+    OsRng.fill_bytes(&mut random);
+    let challenge_id: String = random.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let expires_at = Self::now_epoch() + self.config.ttl_seconds;
+    sqlx::query(
+      "INSERT INTO auth.login_challenges (challenge_hash, person_id, service_id, expires_at)
+       VALUES ($1, $2, $3, $4)",
+    )
+    .bind(TokenManager::hash_token(&challenge_id))
+    .bind(challenge.person_id)
+    .bind(challenge.service_id)
+    .bind(expires_at)
+    .execute(self.pool)
+    .await?;
+    Ok((challenge_id, expires_at))
+  }
+
+  /// The challenge behind `challenge_id`, if it is still open.
+  pub async fn find(&self, challenge_id: &str) -> Result<Option<LoginChallenge>, sqlx::Error> {
+    sqlx::query_as::<_, LoginChallenge>(
+      "SELECT person_id, service_id FROM auth.login_challenges
+       WHERE challenge_hash = $1 AND expires_at > $2",
+    )
+    .bind(TokenManager::hash_token(challenge_id))
+    .bind(Self::now_epoch())
+    .fetch_optional(self.pool)
+    .await
+  }
+
+  /// Close a challenge once its second factor was accepted. `None` if another request
+  /// completed it first or it expired meanwhile.
+  pub async fn complete(&self, challenge_id: &str) -> Result<Option<LoginChallenge>, sqlx::Error> {
+    sqlx::query_as::<_, LoginChallenge>(
+      "DELETE FROM auth.login_challenges
+       WHERE challenge_hash = $1 AND expires_at > $2
+       RETURNING person_id, service_id",
+    )
+    .bind(TokenManager::hash_token(challenge_id))
+    .bind(Self::now_epoch())
+    .fetch_optional(self.pool)
+    .await
+  }
+
+  pub async fn cleanup_expired(&self) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query("DELETE FROM auth.login_challenges WHERE expires_at <= $1")
+      .bind(Self::now_epoch())
+      .execute(self.pool)
+      .await?
+      .rows_affected();
+    Ok(rows)
+  }
+}
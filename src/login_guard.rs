@@ -0,0 +1,266 @@
+use sqlx::{Pool, Postgres};
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone)]
+pub struct LoginGuardConfig {
+  pub max_attempts_per_user: i32,
+  pub max_attempts_per_ip: i32,
+  pub lockout_seconds: i64,
+  pub max_lockout_seconds: i64,
+  pub window_seconds: i64,
+}
+
+impl LoginGuardConfig {
+  pub fn load() -> Self {
+    let max_attempts_per_user = env::var("LOGIN_MAX_ATTEMPTS")
+      .ok()
+      .and_then(|v| v.parse::<i32>().ok())
+      .unwrap_or(5);
+    let max_attempts_per_ip = env::var("LOGIN_MAX_ATTEMPTS_PER_IP")
+      .ok()
+      .and_then(|v| v.parse::<i32>().ok())
+      .unwrap_or(20);
+    let lockout_seconds = env::var("LOGIN_LOCKOUT_SECONDS")
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .unwrap_or(60);
+    let max_lockout_seconds = env::var("LOGIN_LOCKOUT_MAX_SECONDS")
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .unwrap_or(3600);
+    let window_seconds = env::var("LOGIN_ATTEMPT_WINDOW_SECONDS")
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .unwrap_or(900);
+    Self {
+      max_attempts_per_user,
+      max_attempts_per_ip,
+      lockout_seconds,
+      max_lockout_seconds,
+      window_seconds,
+    }
+  }
+}
+
+/// A key failed attempts are counted against.
+#[derive(Debug, Clone)]
+pub enum LoginSubject {
+  Username(String),
+  Ip(String),
+  /// Codes entered at the second login step, counted per person so that guessing
+  /// them does not start over with each password login.
+  SecondFactor(i32),
+}
+
+impl LoginSubject {
+  fn key(&self) -> String {
+    match self {
+      LoginSubject::Username(username) => format!("user:{}", username),
+      LoginSubject::Ip(ip) => format!("ip:{}", ip),
+      LoginSubject::SecondFactor(person_id) => format!("mfa:{}", person_id),
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoginGuard<'a> {
+  pool: &'a Pool<Postgres>,
+  config: LoginGuardConfig,
+}
+
+impl<'a> LoginGuard<'a> {
+  pub fn new(pool: &'a Pool<Postgres>) -> Self {
+    let config = LoginGuardConfig::load();
+    Self { pool, config }
+  }
+
+  fn now_epoch() -> i64 {
+    SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs() as i64
+  }
+
+  fn threshold(&self, subject: &LoginSubject) -> i32 {
+    match subject {
+      LoginSubject::Username(_) | LoginSubject::SecondFactor(_) => {
+        self.config.max_attempts_per_user
+      }
+      LoginSubject::Ip(_) => self.config.max_attempts_per_ip,
+    }
+  }
+
+  /// Lock duration once `failures` reaches `threshold`; doubles with each further failure.
+  fn lockout_for(&self, failures: i32, threshold: i32) -> i64 {
+    let exponent = (failures - threshold).clamp(0, 30) as u32;
+    self
+      .config
+      .lockout_seconds
+      .saturating_mul(1i64 << exponent)
+      .min(self.config.max_lockout_seconds)
+  }
+
+  /// Seconds until every subject is allowed to try again, if any of them is locked.
+  pub async fn retry_after(&self, subjects: &[LoginSubject]) -> Result<Option<i64>, sqlx::Error> {
+    let keys: Vec<String> = subjects.iter().map(LoginSubject::key).collect();
+    let now = Self::now_epoch();
+    let locked_until = sqlx::query_scalar::<_, Option<i64>>(
+      "SELECT MAX(locked_until) FROM auth.login_attempts WHERE subject = ANY($1) AND locked_until > $2",
+    )
+    .bind(&keys)
+    .bind(now)
+    .fetch_one(self.pool)
+    .await?;
+    Ok(locked_until.map(|until| until - now))
+  }
+
+  /// Count a failure against each subject. The count restarts once a subject has been
+  /// quiet for the attempt window, measured from the end of its lockout so that
+  /// lockouts longer than the window keep escalating.
+  pub async fn record_failure(&self, subjects: &[LoginSubject]) -> Result<(), sqlx::Error> {
+    let now = Self::now_epoch();
+    let window_start = now - self.config.window_seconds;
+    for subject in subjects {
+      let failures = sqlx::query_scalar::<_, i32>(
+        "INSERT INTO auth.login_attempts (subject, failures, last_failure_at)
+         VALUES ($1, 1, $2)
+         ON CONFLICT (subject) DO UPDATE
+         SET
+           failures = CASE
+             WHEN GREATEST(auth.login_attempts.last_failure_at, COALESCE(auth.login_attempts.locked_until, 0)) < $3 THEN 1
+             ELSE auth.login_attempts.failures + 1
+           END,
+           last_failure_at = EXCLUDED.last_failure_at
+         RETURNING failures",
+      )
+      .bind(subject.key())
+      .bind(now)
+      .bind(window_start)
+      .fetch_one(self.pool)
+      .await?;
+
+      let threshold = self.threshold(subject);
+      if failures >= threshold {
+        sqlx::query("UPDATE auth.login_attempts SET locked_until = $1 WHERE subject = $2")
+          .bind(now + self.lockout_for(failures, threshold))
+          .bind(subject.key())
+          .execute(self.pool)
+          .await?;
+      }
+    }
+    Ok(())
+  }
+
+  pub async fn reset(&self, subjects: &[LoginSubject]) -> Result<u64, sqlx::Error> {
+    let keys: Vec<String> = subjects.iter().map(LoginSubject::key).collect();
+    let rows = sqlx::query("DELETE FROM auth.login_attempts WHERE subject = ANY($1)")
+      .bind(&keys)
+      .execute(self.pool)
+      .await?
+      .rows_affected();
+    Ok(rows)
+  }
+
+  /// Drop counters whose last failure and lockout both ended before the attempt window.
+  pub async fn cleanup_stale(&self) -> Result<u64, sqlx::Error> {
+    let now = Self::now_epoch();
+    let rows = sqlx::query(
+      "DELETE FROM auth.login_attempts
+       WHERE GREATEST(last_failure_at, COALESCE(locked_until, 0)) < $1",
+    )
+    .bind(now - self.config.window_seconds)
+    .execute(self.pool)
+    .await?
+    .rows_affected();
+    Ok(rows)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::database::DB;
+
+  fn test_guard(pool: &Pool<Postgres>) -> LoginGuard<'_> {
+    LoginGuard {
+      pool,
+      config: LoginGuardConfig {
+        max_attempts_per_user: 2,
+        max_attempts_per_ip: 2,
+        lockout_seconds: 10,
+        max_lockout_seconds: 1000,
+        window_seconds: 5,
+      },
+    }
+  }
+
+  async fn counter(pool: &Pool<Postgres>, key: &str) -> (i32, Option<i64>) {
+    sqlx::query_as::<_, (i32, Option<i64>)>(
+      "SELECT failures, locked_until FROM auth.login_attempts WHERE subject = $1",
+    )
+    .bind(key)
+    .fetch_one(pool)
+    .await
+    .unwrap()
+  }
+
+  #[tokio::test]
+  async fn lockout_longer_than_window_keeps_escalating() {
+    let db = DB::new().await.expect("database connection");
+    let guard = test_guard(db.pool());
+    let nanos = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_nanos();
+    let subject = LoginSubject::Username(format!("guard-test-{}", nanos));
+    let now = LoginGuard::now_epoch();
+    // Five failures, the last one 100s ago, locked for longer than the 5s window.
+    sqlx::query(
+      "INSERT INTO auth.login_attempts (subject, failures, last_failure_at, locked_until) VALUES ($1, 5, $2, $3)",
+    )
+    .bind(subject.key())
+    .bind(now - 100)
+    .bind(now - 1)
+    .execute(db.pool())
+    .await
+    .unwrap();
+
+    guard
+      .record_failure(std::slice::from_ref(&subject))
+      .await
+      .unwrap();
+    let (failures, locked_until) = counter(db.pool(), &subject.key()).await;
+    assert_eq!(failures, 6);
+    assert!(locked_until.unwrap() >= now + 160);
+    guard.reset(&[subject]).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn count_restarts_after_a_quiet_window() {
+    let db = DB::new().await.expect("database connection");
+    let guard = test_guard(db.pool());
+    let nanos = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_nanos();
+    let subject = LoginSubject::Username(format!("guard-quiet-{}", nanos));
+    let now = LoginGuard::now_epoch();
+    sqlx::query(
+      "INSERT INTO auth.login_attempts (subject, failures, last_failure_at, locked_until) VALUES ($1, 5, $2, $3)",
+    )
+    .bind(subject.key())
+    .bind(now - 100)
+    .bind(now - 50)
+    .execute(db.pool())
+    .await
+    .unwrap();
+
+    guard
+      .record_failure(std::slice::from_ref(&subject))
+      .await
+      .unwrap();
+    assert_eq!(counter(db.pool(), &subject.key()).await.0, 1);
+    guard.reset(&[subject]).await.unwrap();
+  }
+}
@@ -1,9 +1,36 @@
-use auth_api::auth_server;
+use auth_api::config::ServerConfig;
+use auth_api::{auth_server, check_settings, load_breached_list};
 
-#[tokio::main]
-async fn main() {
+fn main() {
   let _ = dotenvy::dotenv();
 
-  let server = auth_server("127.0.0.1:7878", 10).await;
-  server.run().await;
+  let config = match ServerConfig::load() {
+    Ok(config) => config,
+    Err(err) => {
+      eprintln!("[config-error] {}", err);
+      std::process::exit(1);
+    }
+  };
+  if let Err(err) = check_settings() {
+    eprintln!("[config-error] {}", err);
+    std::process::exit(1);
+  }
+  match load_breached_list() {
+    Ok(count) => println!("[password-policy] {} breached passwords loaded", count),
+    Err(err) => {
+      eprintln!("[config-error] {}", err);
+      std::process::exit(1);
+    }
+  }
+
+  let runtime = tokio::runtime::Builder::new_multi_thread()
+    .worker_threads(config.workers)
+    .enable_all()
+    .build()
+    .expect("Failed to build runtime");
+
+  runtime.block_on(async {
+    let server = auth_server(&config.bind_address()).await;
+    server.run().await;
+  });
 }
@@ -0,0 +1,320 @@
+use crate::config::ConfigError;
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::Serialize;
+use std::env;
+use std::fmt;
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How the SMTP connection is secured.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SmtpSecurity {
+  StartTls,
+  /// TLS from the first byte (SMTPS, usually port 465).
+  Tls,
+  /// Plaintext; only for local relays.
+  None,
+}
+
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+  pub host: String,
+  pub port: u16,
+  pub username: Option<String>,
+  pub password: Option<String>,
+  pub from: String,
+  pub security: SmtpSecurity,
+}
+
+#[derive(Debug, Clone)]
+pub enum NotifierTransport {
+  /// One JSON file per message in a directory, for development and tests.
+  Outbox(PathBuf),
+  Smtp(SmtpConfig),
+}
+
+#[derive(Debug, Clone)]
+pub struct NotifierConfig {
+  pub transport: NotifierTransport,
+}
+
+impl NotifierConfig {
+  /// Read `NOTIFIER` and the settings of the transport it names. `NOTIFIER` must be set,
+  /// and `smtp` needs at least `SMTP_HOST` and `SMTP_FROM`.
+  pub fn load() -> Result<Self, ConfigError> {
+    Self::from_vars(|key| env::var(key).ok().filter(|value| !value.trim().is_empty()))
+  }
+
+  fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+    let notifier =
+      var("NOTIFIER").ok_or_else(|| ConfigError::missing("NOTIFIER", "outbox or smtp"))?;
+    let transport = match notifier.trim().to_ascii_lowercase().as_str() {
+      "outbox" => NotifierTransport::Outbox(PathBuf::from(
+        var("NOTIFIER_OUTBOX_DIR").unwrap_or_else(|| "outbox".to_string()),
+      )),
+      "smtp" => {
+        let security = match var("SMTP_SECURITY").map(|v| v.trim().to_ascii_lowercase()) {
+          None => SmtpSecurity::StartTls,
+          Some(value) => match value.as_str() {
+            "starttls" => SmtpSecurity::StartTls,
+            "tls" => SmtpSecurity::Tls,
+            "none" => SmtpSecurity::None,
+            _ => {
+              return Err(ConfigError::new(
+                "SMTP_SECURITY",
+                value,
+                "starttls, tls or none",
+              ));
+            }
+          },
+        };
+        let port = match var("SMTP_PORT") {
+          Some(value) => match value.trim().parse::<u16>() {
+            Ok(port) if port > 0 => port,
+            _ => {
+              return Err(ConfigError::new(
+                "SMTP_PORT",
+                value,
+                "a port number between 1 and 65535",
+              ));
+            }
+          },
+          None => match security {
+            SmtpSecurity::StartTls => 587,
+            SmtpSecurity::Tls => 465,
+            SmtpSecurity::None => 25,
+          },
+        };
+        let host = var("SMTP_HOST")
+          .ok_or_else(|| ConfigError::missing("SMTP_HOST", "the SMTP server host"))?;
+        let from = var("SMTP_FROM")
+          .ok_or_else(|| ConfigError::missing("SMTP_FROM", "the sender address"))?;
+        let username = var("SMTP_USERNAME");
+        let password = var("SMTP_PASSWORD");
+        if username.is_some() && password.is_none() {
+          return Err(ConfigError::missing(
+            "SMTP_PASSWORD",
+            "the password of SMTP_USERNAME",
+          ));
+        }
+        NotifierTransport::Smtp(SmtpConfig {
+          host: host.trim().to_string(),
+          port,
+          username,
+          password,
+          from: from.trim().to_string(),
+          security,
+        })
+      }
+      _ => return Err(ConfigError::new("NOTIFIER", notifier, "outbox or smtp")),
+    };
+    Ok(Self { transport })
+  }
+}
+
+/// A message for one person.
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+  pub to: String,
+  pub subject: String,
+  pub body: String,
+}
+
+#[derive(Debug)]
+pub enum NotifyError {
+  Config(ConfigError),
+  Io(std::io::Error),
+  Smtp(String),
+}
+
+impl fmt::Display for NotifyError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      NotifyError::Config(err) => write!(f, "{}", err),
+      NotifyError::Io(err) => write!(f, "{}", err),
+      NotifyError::Smtp(err) => write!(f, "smtp: {}", err),
+    }
+  }
+}
+
+/// Delivers notifications to people, e.g. password reset links.
+pub trait Notifier {
+  fn deliver(
+    &self,
+    notification: &Notification,
+  ) -> impl Future<Output = Result<(), NotifyError>> + Send;
+}
+
+pub struct OutboxNotifier {
+  dir: PathBuf,
+}
+
+impl OutboxNotifier {
+  pub fn new(dir: PathBuf) -> Self {
+    Self { dir }
+  }
+}
+
+impl Notifier for OutboxNotifier {
+  async fn deliver(&self, notification: &Notification) -> Result<(), NotifyError> {
+    tokio::fs::create_dir_all(&self.dir)
+      .await
+      .map_err(NotifyError::Io)?;
+    let nanos = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_nanos();
+    let path = self.dir.join(format!("{}.json", nanos));
+    let content = serde_json::to_vec_pretty(notification).unwrap();
+    tokio::fs::write(path, content)
+      .await
+      .map_err(NotifyError::Io)
+  }
+}
+
+pub struct SmtpNotifier {
+  config: SmtpConfig,
+}
+
+impl SmtpNotifier {
+  pub fn new(config: SmtpConfig) -> Self {
+    Self { config }
+  }
+
+  fn transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>, NotifyError> {
+    let config = &self.config;
+    let builder = match config.security {
+      SmtpSecurity::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)
+        .map_err(|err| NotifyError::Smtp(err.to_string()))?,
+      SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)
+        .map_err(|err| NotifyError::Smtp(err.to_string()))?,
+      SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+    };
+    let builder = builder.port(config.port);
+    let builder = match &config.username {
+      Some(username) => builder.credentials(Credentials::new(
+        username.clone(),
+        config.password.clone().unwrap_or_default(),
+      )),
+      None => builder,
+    };
+    Ok(builder.build())
+  }
+}
+
+impl Notifier for SmtpNotifier {
+  async fn deliver(&self, notification: &Notification) -> Result<(), NotifyError> {
+    let invalid = |err: String| NotifyError::Smtp(err);
+    let message = Message::builder()
+      .from(
+        self
+          .config
+          .from
+          .parse()
+          .map_err(|err: lettre::address::AddressError| invalid(err.to_string()))?,
+      )
+      .to(
+        notification
+          .to
+          .parse()
+          .map_err(|err: lettre::address::AddressError| invalid(err.to_string()))?,
+      )
+      .subject(&notification.subject)
+      .header(ContentType::TEXT_PLAIN)
+      .body(notification.body.clone())
+      .map_err(|err| invalid(err.to_string()))?;
+    self
+      .transport()?
+      .send(message)
+      .await
+      .map_err(|err| invalid(err.to_string()))?;
+    Ok(())
+  }
+}
+
+/// Deliver through the transport chosen by `NOTIFIER`.
+pub async fn send(notification: &Notification) -> Result<(), NotifyError> {
+  match NotifierConfig::load()
+    .map_err(NotifyError::Config)?
+    .transport
+  {
+    NotifierTransport::Outbox(dir) => OutboxNotifier::new(dir).deliver(notification).await,
+    NotifierTransport::Smtp(config) => SmtpNotifier::new(config).deliver(notification).await,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn load_with(vars: &[(&str, &str)]) -> Result<NotifierConfig, ConfigError> {
+    NotifierConfig::from_vars(|key| {
+      vars
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| value.to_string())
+    })
+  }
+
+  #[test]
+  fn requires_notifier() {
+    let err = load_with(&[]).unwrap_err();
+    assert_eq!(err.to_string(), "missing NOTIFIER: expected outbox or smtp");
+    assert!(load_with(&[("NOTIFIER", "pigeon")]).is_err());
+  }
+
+  #[test]
+  fn outbox_defaults_to_local_dir() {
+    let config = load_with(&[("NOTIFIER", "outbox")]).unwrap();
+    assert!(
+      matches!(config.transport, NotifierTransport::Outbox(dir) if dir.as_os_str() == "outbox")
+    );
+  }
+
+  #[test]
+  fn smtp_requires_host_and_sender() {
+    let err =
+      load_with(&[("NOTIFIER", "smtp"), ("SMTP_FROM", "no-reply@example.com")]).unwrap_err();
+    assert!(err.to_string().starts_with("missing SMTP_HOST"));
+    let err = load_with(&[("NOTIFIER", "smtp"), ("SMTP_HOST", "smtp.example.com")]).unwrap_err();
+    assert!(err.to_string().starts_with("missing SMTP_FROM"));
+    let err = load_with(&[
+      ("NOTIFIER", "smtp"),
+      ("SMTP_HOST", "smtp.example.com"),
+      ("SMTP_FROM", "no-reply@example.com"),
+      ("SMTP_USERNAME", "mailer"),
+    ])
+    .unwrap_err();
+    assert!(err.to_string().starts_with("missing SMTP_PASSWORD"));
+  }
+
+  #[test]
+  fn smtp_port_follows_security() {
+    let config = load_with(&[
+      ("NOTIFIER", "SMTP"),
+      ("SMTP_HOST", "smtp.example.com"),
+      ("SMTP_FROM", "no-reply@example.com"),
+      ("SMTP_SECURITY", "tls"),
+    ])
+    .unwrap();
+    match config.transport {
+      NotifierTransport::Smtp(smtp) => {
+        assert_eq!(smtp.port, 465);
+        assert_eq!(smtp.security, SmtpSecurity::Tls);
+      }
+      NotifierTransport::Outbox(_) => panic!("expected smtp"),
+    }
+    assert!(
+      load_with(&[
+        ("NOTIFIER", "smtp"),
+        ("SMTP_HOST", "smtp.example.com"),
+        ("SMTP_FROM", "no-reply@example.com"),
+        ("SMTP_PORT", "0"),
+      ])
+      .is_err()
+    );
+  }
+}
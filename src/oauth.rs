@@ -0,0 +1,460 @@
+use crate::auth::{TokenError, TokenManager};
+use crate::config::{self, ConfigError};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone)]
+pub struct OAuthConfig {
+  /// How long an authorization code can be exchanged for tokens.
+  pub code_ttl_seconds: i64,
+  /// How long the login page can be submitted after it was shown.
+  pub consent_ttl_seconds: i64,
+  /// HMAC key for the login page's consent token, derived from `OAUTH_CONSENT_KEY`.
+  consent_key: [u8; 32],
+}
+
+impl OAuthConfig {
+  /// Fails when neither `OAUTH_CONSENT_KEY` nor `JWT_SECRET` is set outside development.
+  pub fn load() -> Result<Self, ConfigError> {
+    let code_ttl_seconds = env::var("OAUTH_CODE_TTL_SECONDS")
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .filter(|v| *v > 0)
+      .unwrap_or(60);
+    let consent_ttl_seconds = env::var("OAUTH_CONSENT_TTL_SECONDS")
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .filter(|v| *v > 0)
+      .unwrap_or(600);
+    let passphrase = config::secret("OAUTH_CONSENT_KEY")?;
+    Ok(Self {
+      code_ttl_seconds,
+      consent_ttl_seconds,
+      consent_key: Sha256::digest(passphrase.as_bytes()).into(),
+    })
+  }
+}
+
+/// The authorization request a login page was rendered for; its consent token is only
+/// accepted back for the same client, redirect URI and PKCE challenge.
+pub struct ConsentRequest<'a> {
+  pub client_id: &'a str,
+  pub redirect_uri: &'a str,
+  pub code_challenge: &'a str,
+}
+
+/// Registered client as shown to administrators; the secret is only returned when issued.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct OAuthClient {
+  pub client_id: String,
+  pub service_id: i32,
+  pub name: String,
+  pub scopes: Vec<String>,
+  /// Public clients (browser and mobile apps) have no secret and rely on PKCE alone.
+  pub public: bool,
+  pub created_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IssuedClient {
+  pub client_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub client_secret: Option<String>,
+  pub service_id: i32,
+  pub name: String,
+  pub scopes: Vec<String>,
+  pub public: bool,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct RedirectUri {
+  pub id: i32,
+  pub service_id: i32,
+  pub redirect_uri: String,
+  pub created_at: i64,
+}
+
+/// What an authorization code was issued for, as read back when it is exchanged.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct AuthorizationCode {
+  pub client_id: String,
+  pub person_id: i32,
+  pub redirect_uri: String,
+  pub scope: String,
+  pub code_challenge: String,
+  /// OpenID Connect `nonce`, echoed in the ID token.
+  pub nonce: Option<String>,
+}
+
+/// Scope characters allowed by RFC 6749 §3.3 (printable ASCII except space, `"` and `\`).
+pub fn is_valid_scope(scope: &str) -> bool {
+  !scope.is_empty()
+    && scope
+      .bytes()
+      .all(|byte| byte == 0x21 || (0x23..=0x5B).contains(&byte) || (0x5D..=0x7E).contains(&byte))
+}
+
+/// Absolute URI with a scheme and no fragment (RFC 6749 §3.1.2). Custom schemes are
+/// allowed for native apps.
+pub fn is_valid_redirect_uri(uri: &str) -> bool {
+  let Some((scheme, rest)) = uri.split_once(':') else {
+    return false;
+  };
+  scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+    && scheme
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    && !rest.is_empty()
+    && !uri.contains('#')
+    && !uri.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// PKCE `S256` check (RFC 7636 §4.6): the challenge is the unpadded base64url SHA-256
+/// of the verifier.
+pub fn verify_pkce(code_verifier: &str, code_challenge: &str) -> bool {
+  let well_formed = (43..=128).contains(&code_verifier.len())
+    && code_verifier
+      .bytes()
+      .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~'));
+  well_formed && URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())) == code_challenge
+}
+
+pub struct OAuthClientManager<'a> {
+  pool: &'a Pool<Postgres>,
+  config: OAuthConfig,
+}
+
+impl<'a> OAuthClientManager<'a> {
+  pub fn new(pool: &'a Pool<Postgres>) -> Self {
+    let config = OAuthConfig::load().expect("OAuth settings are checked at startup");
+    Self { pool, config }
+  }
+
+  fn now_epoch() -> i64 {
+    SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs() as i64
+  }
+
+  fn random_hex(len: usize) -> String {
+    let mut random = vec![0u8; len];
+    OsRng.fill_bytes(&mut random);
+    random.iter().map(|byte| format!("{:02x}", byte)).collect()
+  }
+
+  fn consent_mac(&self, request: &ConsentRequest<'_>, expires_at: i64, nonce: &str) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.config.consent_key)
+      .expect("HMAC accepts any key length");
+    for part in [
+      request.client_id,
+      request.redirect_uri,
+      request.code_challenge,
+      &expires_at.to_string(),
+      nonce,
+    ] {
+      mac.update(part.as_bytes());
+      mac.update(b"\n");
+    }
+    mac
+  }
+
+  /// Consent token for the login page, valid for `OAUTH_CONSENT_TTL_SECONDS`. It is
+  /// signed rather than stored: `<expires_at>.<nonce>.<base64url HMAC-SHA256>`. It is not
+  /// tied to a browser, so it does not stop cross-site request forgery on its own.
+  pub fn consent_token(&self, request: &ConsentRequest<'_>) -> String {
+    let expires_at = Self::now_epoch() + self.config.consent_ttl_seconds;
+    let nonce = Self::random_hex(16);
+    let signature = self
+      .consent_mac(request, expires_at, &nonce)
+      .finalize()
+      .into_bytes();
+    format!(
+      "{}.{}.{}",
+      expires_at,
+      nonce,
+      URL_SAFE_NO_PAD.encode(signature)
+    )
+  }
+
+  /// Whether `token` was issued by [`Self::consent_token`] for `request` and has not expired.
+  pub fn verify_consent_token(&self, token: &str, request: &ConsentRequest<'_>) -> bool {
+    let mut parts = token.splitn(3, '.');
+    let (Some(expires_at), Some(nonce), Some(signature)) =
+      (parts.next(), parts.next(), parts.next())
+    else {
+      return false;
+    };
+    let Ok(expires_at) = expires_at.parse::<i64>() else {
+      return false;
+    };
+    let Ok(signature) = URL_SAFE_NO_PAD.decode(signature) else {
+      return false;
+    };
+    expires_at > Self::now_epoch()
+      && self
+        .consent_mac(request, expires_at, nonce)
+        .verify_slice(&signature)
+        .is_ok()
+  }
+
+  /// Register a client for an active service; `None` when the service is unknown or disabled.
+  pub async fn register(
+    &self,
+    service_id: i32,
+    name: &str,
+    scopes: &[String],
+    public: bool,
+  ) -> Result<Option<IssuedClient>, sqlx::Error> {
+    let client_id = Self::random_hex(16);
+    let client_secret = (!public).then(|| Self::random_hex(32));
+    let inserted = sqlx::query(
+      "INSERT INTO auth.oauth_clients (client_id, secret_hash, service_id, name, scopes, public)
+       SELECT $1, $2, s.id, $4, $5, $6 FROM auth.services s WHERE s.id = $3 AND s.status = TRUE",
+    )
+    .bind(&client_id)
+    .bind(client_secret.as_deref().map(TokenManager::hash_token))
+    .bind(service_id)
+    .bind(name)
+    .bind(scopes)
+    .bind(public)
+    .execute(self.pool)
+    .await?
+    .rows_affected();
+    if inserted == 0 {
+      return Ok(None);
+    }
+    Ok(Some(IssuedClient {
+      client_id,
+      client_secret,
+      service_id,
+      name: name.to_string(),
+      scopes: scopes.to_vec(),
+      public,
+    }))
+  }
+
+  pub async fn list(&self, service_id: i32) -> Result<Vec<OAuthClient>, sqlx::Error> {
+    sqlx::query_as::<_, OAuthClient>(
+      "SELECT client_id, service_id, name, scopes, public, created_at
+       FROM auth.oauth_clients WHERE service_id = $1 ORDER BY id",
+    )
+    .bind(service_id)
+    .fetch_all(self.pool)
+    .await
+  }
+
+  /// Replace a confidential client's secret; `None` when the client does not belong to
+  /// the service or is public.
+  pub async fn rotate_secret(
+    &self,
+    service_id: i32,
+    client_id: &str,
+  ) -> Result<Option<IssuedClient>, sqlx::Error> {
+    let client_secret = Self::random_hex(32);
+    let client = sqlx::query_as::<_, OAuthClient>(
+      "UPDATE auth.oauth_clients SET secret_hash = $3
+       WHERE client_id = $1 AND service_id = $2 AND NOT public
+       RETURNING client_id, service_id, name, scopes, public, created_at",
+    )
+    .bind(client_id)
+    .bind(service_id)
+    .bind(TokenManager::hash_token(&client_secret))
+    .fetch_optional(self.pool)
+    .await?;
+    Ok(client.map(|client| IssuedClient {
+      client_id: client.client_id,
+      client_secret: Some(client_secret),
+      service_id: client.service_id,
+      name: client.name,
+      scopes: client.scopes,
+      public: client.public,
+    }))
+  }
+
+  pub async fn delete(&self, service_id: i32, client_id: &str) -> Result<bool, sqlx::Error> {
+    let rows =
+      sqlx::query("DELETE FROM auth.oauth_clients WHERE client_id = $1 AND service_id = $2")
+        .bind(client_id)
+        .bind(service_id)
+        .execute(self.pool)
+        .await?
+        .rows_affected();
+    Ok(rows > 0)
+  }
+
+  /// Look up a client by id without checking a secret. Clients of disabled services are not found.
+  pub async fn find(&self, client_id: &str) -> Result<Option<OAuthClient>, sqlx::Error> {
+    sqlx::query_as::<_, OAuthClient>(
+      "SELECT c.client_id, c.service_id, c.name, c.scopes, c.public, c.created_at
+       FROM auth.oauth_clients c
+       JOIN auth.services s ON s.id = c.service_id
+       WHERE c.client_id = $1 AND s.status = TRUE",
+    )
+    .bind(client_id)
+    .fetch_optional(self.pool)
+    .await
+  }
+
+  /// Check a client's credentials. Clients of disabled services cannot authenticate.
+  pub async fn authenticate(
+    &self,
+    client_id: &str,
+    client_secret: &str,
+  ) -> Result<Option<OAuthClient>, sqlx::Error> {
+    sqlx::query_as::<_, OAuthClient>(
+      "SELECT c.client_id, c.service_id, c.name, c.scopes, c.public, c.created_at
+       FROM auth.oauth_clients c
+       JOIN auth.services s ON s.id = c.service_id
+       WHERE c.client_id = $1 AND c.secret_hash = $2 AND s.status = TRUE",
+    )
+    .bind(client_id)
+    .bind(TokenManager::hash_token(client_secret))
+    .fetch_optional(self.pool)
+    .await
+  }
+
+  pub async fn list_redirect_uris(&self, service_id: i32) -> Result<Vec<RedirectUri>, sqlx::Error> {
+    sqlx::query_as::<_, RedirectUri>(
+      "SELECT id, service_id, redirect_uri, created_at
+       FROM auth.oauth_redirect_uris WHERE service_id = $1 ORDER BY id",
+    )
+    .bind(service_id)
+    .fetch_all(self.pool)
+    .await
+  }
+
+  /// Register a redirect URI for a service; registering it twice returns the existing row.
+  /// `None` when the service does not exist.
+  pub async fn add_redirect_uri(
+    &self,
+    service_id: i32,
+    redirect_uri: &str,
+  ) -> Result<Option<RedirectUri>, sqlx::Error> {
+    sqlx::query_as::<_, RedirectUri>(
+      "INSERT INTO auth.oauth_redirect_uris (service_id, redirect_uri)
+       SELECT s.id, $2 FROM auth.services s WHERE s.id = $1
+       ON CONFLICT (service_id, redirect_uri) DO UPDATE SET redirect_uri = EXCLUDED.redirect_uri
+       RETURNING id, service_id, redirect_uri, created_at",
+    )
+    .bind(service_id)
+    .bind(redirect_uri)
+    .fetch_optional(self.pool)
+    .await
+  }
+
+  pub async fn remove_redirect_uri(&self, service_id: i32, id: i32) -> Result<bool, sqlx::Error> {
+    let rows =
+      sqlx::query("DELETE FROM auth.oauth_redirect_uris WHERE id = $1 AND service_id = $2")
+        .bind(id)
+        .bind(service_id)
+        .execute(self.pool)
+        .await?
+        .rows_affected();
+    Ok(rows > 0)
+  }
+
+  /// Redirect URIs are compared exactly, as RFC 6749 §3.1.2.3 recommends.
+  pub async fn is_redirect_registered(
+    &self,
+    service_id: i32,
+    redirect_uri: &str,
+  ) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar::<_, bool>(
+      "SELECT EXISTS (SELECT 1 FROM auth.oauth_redirect_uris WHERE service_id = $1 AND redirect_uri = $2)",
+    )
+    .bind(service_id)
+    .bind(redirect_uri)
+    .fetch_one(self.pool)
+    .await
+  }
+
+  /// Issue a single-use authorization code valid for `OAUTH_CODE_TTL_SECONDS`. Only its
+  /// hash is stored.
+  pub async fn create_code(&self, grant: &AuthorizationCode) -> Result<String, sqlx::Error> {
+    let code = Self::random_hex(32);
+    sqlx::query(
+      "INSERT INTO auth.oauth_authorization_codes
+         (code_hash, client_id, person_id, redirect_uri, scope, code_challenge, nonce, expires_at)
+       VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+    )
+    .bind(TokenManager::hash_token(&code))
+    .bind(&grant.client_id)
+    .bind(grant.person_id)
+    .bind(&grant.redirect_uri)
+    .bind(&grant.scope)
+    .bind(&grant.code_challenge)
+    .bind(&grant.nonce)
+    .bind(Self::now_epoch() + self.config.code_ttl_seconds)
+    .execute(self.pool)
+    .await?;
+    Ok(code)
+  }
+
+  /// Spend an authorization code. A code presented a second time revokes the session
+  /// that was issued for it (RFC 6749 §4.1.2) and is reported as [`TokenError::Reused`].
+  pub async fn redeem_code(&self, code: &str) -> Result<AuthorizationCode, TokenError> {
+    let now = Self::now_epoch();
+    let code_hash = TokenManager::hash_token(code);
+    let claimed = sqlx::query_as::<_, AuthorizationCode>(
+      "UPDATE auth.oauth_authorization_codes SET used_at = $2
+       WHERE code_hash = $1 AND used_at IS NULL AND expires_at > $2
+       RETURNING client_id, person_id, redirect_uri, scope, code_challenge, nonce",
+    )
+    .bind(&code_hash)
+    .bind(now)
+    .fetch_optional(self.pool)
+    .await?;
+    if let Some(grant) = claimed {
+      return Ok(grant);
+    }
+
+    let spent = sqlx::query_as::<_, (Option<i64>, Option<String>)>(
+      "SELECT used_at, session_id FROM auth.oauth_authorization_codes WHERE code_hash = $1",
+    )
+    .bind(&code_hash)
+    .fetch_optional(self.pool)
+    .await?;
+    match spent {
+      Some((Some(_), session_id)) => {
+        if let Some(session_id) = session_id {
+          TokenManager::new(self.pool)
+            .revoke_session(&session_id)
+            .await?;
+        }
+        Err(TokenError::Reused)
+      }
+      Some((None, _)) => Err(TokenError::Expired),
+      None => Err(TokenError::NotFound),
+    }
+  }
+
+  /// Remember which session a code was exchanged for, so reusing the code can revoke it.
+  pub async fn attach_session(&self, code: &str, session_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE auth.oauth_authorization_codes SET session_id = $2 WHERE code_hash = $1")
+      .bind(TokenManager::hash_token(code))
+      .bind(session_id)
+      .execute(self.pool)
+      .await?;
+    Ok(())
+  }
+
+  pub async fn cleanup_expired_codes(&self) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query("DELETE FROM auth.oauth_authorization_codes WHERE expires_at <= $1")
+      .bind(Self::now_epoch())
+      .execute(self.pool)
+      .await?
+      .rows_affected();
+    Ok(rows)
+  }
+}
@@ -0,0 +1,338 @@
+use crate::config::{self, ConfigError, ServerConfig};
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use rsa::RsaPrivateKey;
+use rsa::pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey};
+use rsa::pkcs1v15::SigningKey;
+use rsa::signature::{SignatureEncoding, Signer};
+use rsa::traits::PublicKeyParts;
+use serde::Serialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+use std::env;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const KEY_BITS: usize = 2048;
+const NONCE_BYTES: usize = 12;
+
+#[derive(Clone)]
+pub struct OidcConfig {
+  /// `iss` of ID tokens and base URL of the endpoints in the discovery document.
+  pub issuer: String,
+  pub id_token_ttl_seconds: i64,
+  /// AES-256 key for the stored private keys, derived from `OIDC_KEY_ENCRYPTION_KEY`.
+  encryption_key: [u8; 32],
+}
+
+impl OidcConfig {
+  /// Fails when neither `OIDC_KEY_ENCRYPTION_KEY` nor `JWT_SECRET` is set outside development.
+  pub fn load() -> Result<Self, ConfigError> {
+    let issuer = env::var("OIDC_ISSUER")
+      .ok()
+      .map(|v| v.trim().trim_end_matches('/').to_string())
+      .filter(|v| !v.is_empty())
+      .or_else(|| {
+        ServerConfig::load()
+          .ok()
+          .map(|server| format!("http://{}", server.bind_address()))
+      })
+      .unwrap_or_else(|| "http://127.0.0.1:7878".to_string());
+    let id_token_ttl_seconds = env::var("ID_TOKEN_TTL_SECONDS")
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .filter(|v| *v > 0)
+      .unwrap_or(300);
+    let passphrase = config::secret("OIDC_KEY_ENCRYPTION_KEY")?;
+    Ok(Self {
+      issuer,
+      id_token_ttl_seconds,
+      encryption_key: Sha256::digest(passphrase.as_bytes()).into(),
+    })
+  }
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+  Key(String),
+  Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for OidcError {
+  fn from(err: sqlx::Error) -> Self {
+    OidcError::Database(err)
+  }
+}
+
+impl fmt::Display for OidcError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      OidcError::Key(message) => write!(f, "signing key: {}", message),
+      OidcError::Database(err) => write!(f, "{}", err),
+    }
+  }
+}
+
+/// Signing key as shown to administrators; private material is never returned.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct SigningKeyInfo {
+  pub kid: String,
+  pub created_at: i64,
+  /// Set once the key was replaced; it stays in the JWKS until tokens signed with it expire.
+  pub retired_at: Option<i64>,
+}
+
+/// `kid`, private key and its AES-GCM nonce as stored in `auth.signing_keys`.
+type StoredKey = (String, Vec<u8>, Option<Vec<u8>>);
+
+/// Claims of an ID token beyond the ones derived from the token payload.
+pub struct IdTokenRequest<'a> {
+  /// `client_id` of the relying party, or the issuer itself for a direct login.
+  pub audience: &'a str,
+  pub session_id: &'a str,
+  pub nonce: Option<&'a str>,
+}
+
+/// RS256 keys for ID tokens, kept in `auth.signing_keys`. The newest unretired key signs;
+/// retired keys are still published so existing ID tokens keep verifying. Private keys
+/// are stored encrypted with AES-256-GCM.
+pub struct SigningKeys<'a> {
+  pool: &'a Pool<Postgres>,
+  config: OidcConfig,
+}
+
+impl<'a> SigningKeys<'a> {
+  pub fn new(pool: &'a Pool<Postgres>) -> Self {
+    let config = OidcConfig::load().expect("OIDC settings are checked at startup");
+    Self { pool, config }
+  }
+
+  pub fn config(&self) -> &OidcConfig {
+    &self.config
+  }
+
+  fn now_epoch() -> i64 {
+    SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs() as i64
+  }
+
+  fn encrypt(&self, der: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let cipher = Aes256Gcm::new(&self.config.encryption_key.into());
+    let mut nonce = [0u8; NONCE_BYTES];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher
+      .encrypt(Nonce::from_slice(&nonce), der)
+      .expect("AES-GCM encryption of a private key cannot fail");
+    (ciphertext, nonce.to_vec())
+  }
+
+  /// Keys stored before encryption was introduced have no nonce and are read as is.
+  fn decrypt(&self, stored: &[u8], nonce: Option<&[u8]>) -> Result<RsaPrivateKey, OidcError> {
+    let der = match nonce {
+      Some(nonce) if nonce.len() == NONCE_BYTES => {
+        Aes256Gcm::new(&self.config.encryption_key.into())
+          .decrypt(Nonce::from_slice(nonce), stored)
+          .map_err(|_| {
+            OidcError::Key("cannot decrypt, was OIDC_KEY_ENCRYPTION_KEY changed?".to_string())
+          })?
+      }
+      Some(_) => return Err(OidcError::Key("malformed nonce".to_string())),
+      None => stored.to_vec(),
+    };
+    RsaPrivateKey::from_pkcs1_der(&der).map_err(|err| OidcError::Key(err.to_string()))
+  }
+
+  /// RSA key generation is slow; keep it off the async workers.
+  async fn generate_key() -> Result<(String, Vec<u8>, RsaPrivateKey), OidcError> {
+    let key = tokio::task::spawn_blocking(|| RsaPrivateKey::new(&mut OsRng, KEY_BITS))
+      .await
+      .map_err(|err| OidcError::Key(err.to_string()))?
+      .map_err(|err| OidcError::Key(err.to_string()))?;
+    let der = key
+      .to_pkcs1_der()
+      .map_err(|err| OidcError::Key(err.to_string()))?
+      .as_bytes()
+      .to_vec();
+    let mut random = [0u8; 8];
+    OsRng.fill_bytes(&mut random);
+    let kid: String = random.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok((kid, der, key))
+  }
+
+  async fn insert_key(
+    &self,
+    conn: &mut sqlx::PgConnection,
+    kid: &str,
+    der: &[u8],
+    key: &RsaPrivateKey,
+  ) -> Result<SigningKeyInfo, sqlx::Error> {
+    let (ciphertext, nonce) = self.encrypt(der);
+    sqlx::query_as::<_, SigningKeyInfo>(
+      "INSERT INTO auth.signing_keys (kid, private_key, private_key_nonce, public_n, public_e)
+       VALUES ($1, $2, $3, $4, $5)
+       RETURNING kid, created_at, retired_at",
+    )
+    .bind(kid)
+    .bind(ciphertext)
+    .bind(nonce)
+    .bind(URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()))
+    .bind(URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()))
+    .fetch_one(conn)
+    .await
+  }
+
+  /// The key that signs new ID tokens. The first call on an empty table creates one.
+  async fn active_key(&self) -> Result<(String, RsaPrivateKey), OidcError> {
+    let query = "SELECT kid, private_key, private_key_nonce FROM auth.signing_keys
+       WHERE retired_at IS NULL ORDER BY created_at DESC, kid LIMIT 1";
+    let row = sqlx::query_as::<_, StoredKey>(query)
+      .fetch_optional(self.pool)
+      .await?;
+    let (kid, stored, nonce) = match row {
+      Some(row) => row,
+      None => {
+        let generated = Self::generate_key().await?;
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext('auth.signing_keys'))")
+          .execute(&mut *tx)
+          .await?;
+        // Another request may have created the first key while this one was generating.
+        let row = sqlx::query_as::<_, StoredKey>(query)
+          .fetch_optional(&mut *tx)
+          .await?;
+        let Some(row) = row else {
+          let (kid, der, key) = generated;
+          self.insert_key(&mut tx, &kid, &der, &key).await?;
+          tx.commit().await?;
+          return Ok((kid, key));
+        };
+        tx.commit().await?;
+        row
+      }
+    };
+    let key = self.decrypt(&stored, nonce.as_deref())?;
+    Ok((kid, key))
+  }
+
+  /// Encrypt private keys stored in plain form by earlier versions. Returns how many
+  /// were rewritten.
+  pub async fn encrypt_stored_keys(&self) -> Result<u64, OidcError> {
+    let plain = sqlx::query_as::<_, (String, Vec<u8>)>(
+      "SELECT kid, private_key FROM auth.signing_keys WHERE private_key_nonce IS NULL",
+    )
+    .fetch_all(self.pool)
+    .await?;
+    let mut encrypted = 0;
+    for (kid, der) in plain {
+      let (ciphertext, nonce) = self.encrypt(&der);
+      encrypted += sqlx::query(
+        "UPDATE auth.signing_keys SET private_key = $2, private_key_nonce = $3
+         WHERE kid = $1 AND private_key_nonce IS NULL",
+      )
+      .bind(&kid)
+      .bind(ciphertext)
+      .bind(nonce)
+      .execute(self.pool)
+      .await?
+      .rows_affected();
+    }
+    Ok(encrypted)
+  }
+
+  /// Create a new signing key and retire the current one.
+  pub async fn rotate(&self) -> Result<SigningKeyInfo, OidcError> {
+    let (kid, der, key) = Self::generate_key().await?;
+    let mut tx = self.pool.begin().await?;
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext('auth.signing_keys'))")
+      .execute(&mut *tx)
+      .await?;
+    sqlx::query("UPDATE auth.signing_keys SET retired_at = $1 WHERE retired_at IS NULL")
+      .bind(Self::now_epoch())
+      .execute(&mut *tx)
+      .await?;
+    let info = self.insert_key(&mut tx, &kid, &der, &key).await?;
+    tx.commit().await?;
+    Ok(info)
+  }
+
+  pub async fn list(&self) -> Result<Vec<SigningKeyInfo>, sqlx::Error> {
+    sqlx::query_as::<_, SigningKeyInfo>(
+      "SELECT kid, created_at, retired_at FROM auth.signing_keys ORDER BY created_at DESC, kid",
+    )
+    .fetch_all(self.pool)
+    .await
+  }
+
+  /// JSON Web Key Set (RFC 7517) with the active key and the retired keys still in use.
+  pub async fn jwks(&self) -> Result<Value, sqlx::Error> {
+    let keys = sqlx::query_as::<_, (String, String, String)>(
+      "SELECT kid, public_n, public_e FROM auth.signing_keys
+       WHERE retired_at IS NULL OR retired_at > $1
+       ORDER BY created_at DESC, kid",
+    )
+    .bind(Self::now_epoch() - self.config.id_token_ttl_seconds)
+    .fetch_all(self.pool)
+    .await?;
+    let keys: Vec<Value> = keys
+      .into_iter()
+      .map(|(kid, n, e)| {
+        json!({ "kty": "RSA", "use": "sig", "alg": "RS256", "kid": kid, "n": n, "e": e })
+      })
+      .collect();
+    Ok(json!({ "keys": keys }))
+  }
+
+  /// Sign an ID token for the person in a token `payload` (as built by login).
+  pub async fn issue_id_token(
+    &self,
+    payload: &Value,
+    request: &IdTokenRequest<'_>,
+  ) -> Result<String, OidcError> {
+    let now = Self::now_epoch();
+    let mut claims = json!({
+      "iss": self.config.issuer,
+      "sub": payload.get("user_id").map(|id| id.to_string()).unwrap_or_default(),
+      "aud": request.audience,
+      "iat": now,
+      "exp": now + self.config.id_token_ttl_seconds,
+      "sid": request.session_id,
+      "user_id": payload.get("user_id"),
+      "preferred_username": payload.get("username"),
+      "name": payload.get("name"),
+    });
+    if let Some(nonce) = request.nonce {
+      claims["nonce"] = json!(nonce);
+    }
+
+    let (kid, key) = self.active_key().await?;
+    let header = json!({ "alg": "RS256", "typ": "JWT", "kid": kid });
+    let signing_input = format!(
+      "{}.{}",
+      URL_SAFE_NO_PAD.encode(header.to_string()),
+      URL_SAFE_NO_PAD.encode(claims.to_string())
+    );
+    let signature = SigningKey::<Sha256>::new(key).sign(signing_input.as_bytes());
+    Ok(format!(
+      "{}.{}",
+      signing_input,
+      URL_SAFE_NO_PAD.encode(signature.to_bytes())
+    ))
+  }
+
+  /// Drop retired keys once every ID token they signed has expired.
+  pub async fn cleanup_retired(&self) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query("DELETE FROM auth.signing_keys WHERE retired_at <= $1")
+      .bind(Self::now_epoch() - self.config.id_token_ttl_seconds)
+      .execute(self.pool)
+      .await?
+      .rows_affected();
+    Ok(rows)
+  }
+}
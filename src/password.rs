@@ -0,0 +1,132 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::rngs::OsRng;
+use std::env;
+use std::fmt;
+use std::sync::OnceLock;
+use subtle::ConstantTimeEq;
+
+/// Shortest password accepted when `PASSWORD_MIN_LENGTH` is not set.
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Hash checked when there is no account, generated on first use with the current cost.
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+#[derive(Debug, Clone)]
+pub struct PasswordConfig {
+  pub memory_kib: u32,
+  pub iterations: u32,
+  pub parallelism: u32,
+}
+
+#[derive(Debug)]
+pub enum PasswordError {
+  Hash(argon2::password_hash::Error),
+  Task,
+}
+
+impl fmt::Display for PasswordError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      PasswordError::Hash(err) => write!(f, "password hashing failed: {}", err),
+      PasswordError::Task => write!(f, "password hashing task failed"),
+    }
+  }
+}
+
+impl PasswordConfig {
+  pub fn load() -> Self {
+    let memory_kib = env::var("PASSWORD_HASH_MEMORY_KIB")
+      .ok()
+      .and_then(|v| v.parse::<u32>().ok())
+      .unwrap_or(Params::DEFAULT_M_COST);
+    let iterations = env::var("PASSWORD_HASH_ITERATIONS")
+      .ok()
+      .and_then(|v| v.parse::<u32>().ok())
+      .unwrap_or(Params::DEFAULT_T_COST);
+    let parallelism = env::var("PASSWORD_HASH_PARALLELISM")
+      .ok()
+      .and_then(|v| v.parse::<u32>().ok())
+      .unwrap_or(Params::DEFAULT_P_COST);
+    Self {
+      memory_kib,
+      iterations,
+      parallelism,
+    }
+  }
+
+  fn hasher(&self) -> Result<Argon2<'static>, PasswordError> {
+    let params = Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+      .map_err(|err| PasswordError::Hash(err.into()))?;
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+  }
+}
+
+fn hash_blocking(password: &str, config: &PasswordConfig) -> Result<String, PasswordError> {
+  let salt = SaltString::generate(&mut OsRng);
+  let hash = config
+    .hasher()?
+    .hash_password(password.as_bytes(), &salt)
+    .map_err(PasswordError::Hash)?;
+  Ok(hash.to_string())
+}
+
+fn verify_blocking(password: &str, stored: &str) -> bool {
+  match PasswordHash::new(stored) {
+    Ok(parsed) => Argon2::default()
+      .verify_password(password.as_bytes(), &parsed)
+      .is_ok(),
+    Err(_) => false,
+  }
+}
+
+/// Prefix of every hash written by [`hash_password`].
+const CURRENT_HASH_PREFIX: &str = "$argon2id$";
+
+/// Anything that is not an Argon2id PHC string: raw passwords from before hashing was
+/// introduced, or PHC strings of another scheme. `auth.password_hash_report()` counts
+/// legacy rows with the same rule.
+pub fn is_legacy_hash(stored: &str) -> bool {
+  !stored.starts_with(CURRENT_HASH_PREFIX)
+}
+
+/// Check a password against a legacy value: PHC strings of another scheme are verified
+/// as such, raw passwords are compared in constant time.
+pub async fn verify_legacy_password(password: &str, stored: &str) -> bool {
+  if PasswordHash::new(stored).is_ok() {
+    return verify_password(password, stored).await;
+  }
+  password.as_bytes().ct_eq(stored.as_bytes()).into()
+}
+
+/// Hash a plaintext password into a salted Argon2id PHC string.
+pub async fn hash_password(password: &str) -> Result<String, PasswordError> {
+  let password = password.to_string();
+  let config = PasswordConfig::load();
+  tokio::task::spawn_blocking(move || hash_blocking(&password, &config))
+    .await
+    .map_err(|_| PasswordError::Task)?
+}
+
+/// Check a plaintext password against a stored PHC string.
+/// Cost parameters are read from the stored hash, so older hashes keep verifying
+/// after the configured cost changes.
+pub async fn verify_password(password: &str, stored: &str) -> bool {
+  let password = password.to_string();
+  let stored = stored.to_string();
+  tokio::task::spawn_blocking(move || verify_blocking(&password, &stored))
+    .await
+    .unwrap_or(false)
+}
+
+/// Do the work of [`verify_password`] against a throwaway hash, so a login for an unknown
+/// username takes as long as one with a wrong password.
+pub async fn verify_dummy_password(password: &str) {
+  let password = password.to_string();
+  let _ = tokio::task::spawn_blocking(move || {
+    let stored = DUMMY_HASH
+      .get_or_init(|| hash_blocking("dummy-password", &PasswordConfig::load()).unwrap_or_default());
+    verify_blocking(&password, stored)
+  })
+  .await;
+}
@@ -0,0 +1,379 @@
+use crate::password::MIN_PASSWORD_LENGTH;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+const BUILT_IN_BREACHED_LIST: &str = include_str!("../assets/common-passwords.txt");
+
+static BREACHED_LIST: OnceLock<HashSet<String>> = OnceLock::new();
+
+/// Usernames and document numbers shorter than this are not searched for in passwords.
+const MIN_CONTAINED_LENGTH: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct PasswordPolicyConfig {
+  pub min_length: usize,
+  pub max_length: usize,
+  pub require_lowercase: bool,
+  pub require_uppercase: bool,
+  pub require_digit: bool,
+  pub require_symbol: bool,
+  /// One breached or common password per line; the built-in list is used when unset.
+  pub breached_list: Option<PathBuf>,
+}
+
+fn env_flag(key: &str) -> bool {
+  env::var(key)
+    .map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+    .unwrap_or(false)
+}
+
+impl PasswordPolicyConfig {
+  pub fn load() -> Self {
+    let min_length = env::var("PASSWORD_MIN_LENGTH")
+      .ok()
+      .and_then(|v| v.parse::<usize>().ok())
+      .filter(|v| *v > 0)
+      .unwrap_or(MIN_PASSWORD_LENGTH);
+    let max_length = env::var("PASSWORD_MAX_LENGTH")
+      .ok()
+      .and_then(|v| v.parse::<usize>().ok())
+      .unwrap_or(128)
+      .max(min_length);
+    let breached_list = env::var("PASSWORD_BREACHED_LIST")
+      .ok()
+      .map(|v| v.trim().to_string())
+      .filter(|v| !v.is_empty())
+      .map(PathBuf::from);
+    Self {
+      min_length,
+      max_length,
+      require_lowercase: env_flag("PASSWORD_REQUIRE_LOWERCASE"),
+      require_uppercase: env_flag("PASSWORD_REQUIRE_UPPERCASE"),
+      require_digit: env_flag("PASSWORD_REQUIRE_DIGIT"),
+      require_symbol: env_flag("PASSWORD_REQUIRE_SYMBOL"),
+      breached_list,
+    }
+  }
+}
+
+/// The rule a password broke, as reported to clients.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyRule {
+  MinLength,
+  MaxLength,
+  Lowercase,
+  Uppercase,
+  Digit,
+  Symbol,
+  ContainsUsername,
+  ContainsDocumentNumber,
+  Breached,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyViolation {
+  pub rule: PolicyRule,
+  pub message: String,
+  /// The configured bound, for the length rules.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub limit: Option<usize>,
+}
+
+impl PolicyViolation {
+  fn new(rule: PolicyRule, message: &str) -> Self {
+    Self {
+      rule,
+      message: message.to_string(),
+      limit: None,
+    }
+  }
+
+  fn length(rule: PolicyRule, message: String, limit: usize) -> Self {
+    Self {
+      rule,
+      message,
+      limit: Some(limit),
+    }
+  }
+}
+
+/// Whose password is being checked.
+pub struct PasswordOwner<'a> {
+  pub username: &'a str,
+  pub document_number: &'a str,
+}
+
+#[derive(Debug)]
+pub struct BreachedListError {
+  path: PathBuf,
+  err: std::io::Error,
+}
+
+impl fmt::Display for BreachedListError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "cannot read PASSWORD_BREACHED_LIST={}: {}",
+      self.path.display(),
+      self.err
+    )
+  }
+}
+
+impl std::error::Error for BreachedListError {}
+
+fn parse_breached_list(content: &str) -> HashSet<String> {
+  content
+    .lines()
+    .map(str::trim)
+    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    .map(str::to_lowercase)
+    .collect()
+}
+
+/// Read the breached password list once, before the server starts. Returns how many
+/// entries it has. Until it is called the built-in list applies.
+pub fn load_breached_list() -> Result<usize, BreachedListError> {
+  let list = match PasswordPolicyConfig::load().breached_list {
+    Some(path) => match std::fs::read_to_string(&path) {
+      Ok(content) => parse_breached_list(&content),
+      Err(err) => return Err(BreachedListError { path, err }),
+    },
+    None => parse_breached_list(BUILT_IN_BREACHED_LIST),
+  };
+  let _ = BREACHED_LIST.set(list);
+  Ok(breached_list().len())
+}
+
+fn breached_list() -> &'static HashSet<String> {
+  BREACHED_LIST.get_or_init(|| parse_breached_list(BUILT_IN_BREACHED_LIST))
+}
+
+fn contains_ignore_case(password: &str, value: &str) -> bool {
+  let value = value.trim().to_lowercase();
+  value.chars().count() >= MIN_CONTAINED_LENGTH && password.contains(&value)
+}
+
+/// Rules new passwords must follow, from `PASSWORD_*` settings.
+pub struct PasswordPolicy {
+  config: PasswordPolicyConfig,
+}
+
+impl PasswordPolicy {
+  pub fn new() -> Self {
+    Self {
+      config: PasswordPolicyConfig::load(),
+    }
+  }
+
+  /// Every rule `password` breaks; empty when it is acceptable.
+  pub fn check(&self, password: &str, owner: &PasswordOwner<'_>) -> Vec<PolicyViolation> {
+    let config = &self.config;
+    let mut violations = Vec::new();
+
+    let length = password.chars().count();
+    if length < config.min_length {
+      violations.push(PolicyViolation::length(
+        PolicyRule::MinLength,
+        format!("Password must be at least {} characters", config.min_length),
+        config.min_length,
+      ));
+    }
+    if length > config.max_length {
+      violations.push(PolicyViolation::length(
+        PolicyRule::MaxLength,
+        format!("Password must be at most {} characters", config.max_length),
+        config.max_length,
+      ));
+    }
+
+    let classes = [
+      (
+        config.require_lowercase,
+        password.chars().any(char::is_lowercase),
+        PolicyRule::Lowercase,
+        "Password must contain a lowercase letter",
+      ),
+      (
+        config.require_uppercase,
+        password.chars().any(char::is_uppercase),
+        PolicyRule::Uppercase,
+        "Password must contain an uppercase letter",
+      ),
+      (
+        config.require_digit,
+        password.chars().any(char::is_numeric),
+        PolicyRule::Digit,
+        "Password must contain a digit",
+      ),
+      (
+        config.require_symbol,
+        password
+          .chars()
+          .any(|c| !c.is_alphanumeric() && !c.is_whitespace()),
+        PolicyRule::Symbol,
+        "Password must contain a symbol",
+      ),
+    ];
+    for (required, present, rule, message) in classes {
+      if required && !present {
+        violations.push(PolicyViolation::new(rule, message));
+      }
+    }
+
+    let lowered = password.to_lowercase();
+    if contains_ignore_case(&lowered, owner.username) {
+      violations.push(PolicyViolation::new(
+        PolicyRule::ContainsUsername,
+        "Password must not contain the username",
+      ));
+    }
+    if contains_ignore_case(&lowered, owner.document_number) {
+      violations.push(PolicyViolation::new(
+        PolicyRule::ContainsDocumentNumber,
+        "Password must not contain the document number",
+      ));
+    }
+    if breached_list().contains(&lowered) {
+      violations.push(PolicyViolation::new(
+        PolicyRule::Breached,
+        "Password is too common or has appeared in a data breach",
+      ));
+    }
+
+    violations
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn policy(configure: impl FnOnce(&mut PasswordPolicyConfig)) -> PasswordPolicy {
+    let mut config = PasswordPolicyConfig {
+      min_length: 8,
+      max_length: 64,
+      require_lowercase: false,
+      require_uppercase: false,
+      require_digit: false,
+      require_symbol: false,
+      breached_list: None,
+    };
+    configure(&mut config);
+    PasswordPolicy { config }
+  }
+
+  const OWNER: PasswordOwner<'static> = PasswordOwner {
+    username: "jdoe",
+    document_number: "40123456",
+  };
+
+  fn rules(policy: &PasswordPolicy, password: &str) -> Vec<PolicyRule> {
+    policy
+      .check(password, &OWNER)
+      .into_iter()
+      .map(|violation| violation.rule)
+      .collect()
+  }
+
+  #[test]
+  fn accepts_a_compliant_password() {
+    assert!(rules(&policy(|_| {}), "Horse-Battery-7").is_empty());
+  }
+
+  #[test]
+  fn enforces_length_bounds() {
+    let policy = policy(|config| config.max_length = 20);
+    let violations = policy.check("Short-1", &OWNER);
+    assert_eq!(violations.len(), 1);
+    assert_eq!(violations[0].rule, PolicyRule::MinLength);
+    assert_eq!(violations[0].limit, Some(8));
+    assert_eq!(
+      rules(&policy, "Horse-Battery-Staple-Correct"),
+      vec![PolicyRule::MaxLength]
+    );
+    // Length counts characters, not bytes.
+    assert!(rules(&policy, "ñandúñandú").is_empty());
+  }
+
+  #[test]
+  fn enforces_required_character_classes() {
+    let policy = policy(|config| {
+      config.require_lowercase = true;
+      config.require_uppercase = true;
+      config.require_digit = true;
+      config.require_symbol = true;
+    });
+    assert_eq!(
+      rules(&policy, "horsebattery"),
+      vec![PolicyRule::Uppercase, PolicyRule::Digit, PolicyRule::Symbol]
+    );
+    assert_eq!(
+      rules(&policy, "HORSE-BATTERY-7"),
+      vec![PolicyRule::Lowercase]
+    );
+    assert!(rules(&policy, "Horse-Battery-7").is_empty());
+  }
+
+  #[test]
+  fn rejects_the_username_in_any_case() {
+    assert_eq!(
+      rules(&policy(|_| {}), "xx-JDoe-horse"),
+      vec![PolicyRule::ContainsUsername]
+    );
+  }
+
+  #[test]
+  fn rejects_the_document_number() {
+    assert_eq!(
+      rules(&policy(|_| {}), "horse40123456"),
+      vec![PolicyRule::ContainsDocumentNumber]
+    );
+  }
+
+  #[test]
+  fn ignores_personal_values_too_short_to_matter() {
+    let owner = PasswordOwner {
+      username: "jo",
+      document_number: "12",
+    };
+    assert!(
+      policy(|_| {})
+        .check("jo-horse-12-battery", &owner)
+        .is_empty()
+    );
+  }
+
+  #[test]
+  fn rejects_breached_passwords_in_any_case() {
+    assert_eq!(
+      rules(&policy(|_| {}), "QWERTY123"),
+      vec![PolicyRule::Breached]
+    );
+  }
+
+  #[test]
+  fn reports_every_violation() {
+    let policy = policy(|config| config.require_symbol = true);
+    let violations = policy.check("jdoe", &OWNER);
+    let reported: Vec<PolicyRule> = violations.iter().map(|violation| violation.rule).collect();
+    assert_eq!(
+      reported,
+      vec![
+        PolicyRule::MinLength,
+        PolicyRule::Symbol,
+        PolicyRule::ContainsUsername,
+      ]
+    );
+    assert!(
+      violations
+        .iter()
+        .all(|violation| !violation.message.is_empty())
+    );
+  }
+}
@@ -0,0 +1,143 @@
+use crate::auth::TokenManager;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sqlx::{Pool, Postgres};
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone)]
+pub struct PasswordResetConfig {
+  pub ttl_seconds: i64,
+  /// Minimum time between two tokens for the same person.
+  pub cooldown_seconds: i64,
+  /// Page of the client app that takes the token; the token is appended to it.
+  pub url: Option<String>,
+}
+
+impl PasswordResetConfig {
+  pub fn load() -> Self {
+    let ttl_seconds = env::var("PASSWORD_RESET_TTL_SECONDS")
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .filter(|v| *v > 0)
+      .unwrap_or(1800);
+    let cooldown_seconds = env::var("PASSWORD_RESET_COOLDOWN_SECONDS")
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .filter(|v| *v >= 0)
+      .unwrap_or(60);
+    let url = env::var("PASSWORD_RESET_URL")
+      .ok()
+      .map(|v| v.trim().to_string())
+      .filter(|v| !v.is_empty());
+    Self {
+      ttl_seconds,
+      cooldown_seconds,
+      url,
+    }
+  }
+}
+
+/// Single-use password reset tokens in `auth.password_reset_tokens`, stored as SHA-256.
+pub struct PasswordResets<'a> {
+  pool: &'a Pool<Postgres>,
+  config: PasswordResetConfig,
+}
+
+impl<'a> PasswordResets<'a> {
+  pub fn new(pool: &'a Pool<Postgres>) -> Self {
+    let config = PasswordResetConfig::load();
+    Self { pool, config }
+  }
+
+  pub fn config(&self) -> &PasswordResetConfig {
+    &self.config
+  }
+
+  fn now_epoch() -> i64 {
+    SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs() as i64
+  }
+
+  /// Mint a token for `person_id`; earlier unused tokens of the person stop working.
+  /// Returns the token and its expiry, or `None` while the person is within the cooldown
+  /// of their previous token.
+  pub async fn issue(&self, person_id: i32) -> Result<Option<(String, i64)>, sqlx::Error> {
+    let now = Self::now_epoch();
+    let mut tx = self.pool.begin().await?;
+    // Concurrent requests for the same person queue here so only one passes the cooldown.
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext('auth.password_reset_tokens'), $1)")
+      .bind(person_id)
+      .execute(&mut *tx)
+      .await?;
+    let recent = sqlx::query_scalar::<_, bool>(
+      "SELECT EXISTS (
+         SELECT 1 FROM auth.password_reset_tokens WHERE person_id = $1 AND created_at > $2
+       )",
+    )
+    .bind(person_id)
+    .bind(now - self.config.cooldown_seconds)
+    .fetch_one(&mut *tx)
+    .await?;
+    if recent {
+      return Ok(None);
+    }
+    let mut random = [0u8; 32];
+    OsRng.fill_bytes(&mut random);
+    let token: String = random.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let expires_at = now + self.config.ttl_seconds;
+    sqlx::query("DELETE FROM auth.password_reset_tokens WHERE person_id = $1 AND used_at IS NULL")
+      .bind(person_id)
+      .execute(&mut *tx)
+      .await?;
+    sqlx::query(
+      "INSERT INTO auth.password_reset_tokens (token_hash, person_id, expires_at)
+       VALUES ($1, $2, $3)",
+    )
+    .bind(TokenManager::hash_token(&token))
+    .bind(person_id)
+    .bind(expires_at)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(Some((token, expires_at)))
+  }
+
+  /// The person a usable token was issued for, without spending it.
+  pub async fn find(&self, token: &str) -> Result<Option<i32>, sqlx::Error> {
+    sqlx::query_scalar::<_, i32>(
+      "SELECT person_id FROM auth.password_reset_tokens
+       WHERE token_hash = $1 AND used_at IS NULL AND expires_at > $2",
+    )
+    .bind(TokenManager::hash_token(token))
+    .bind(Self::now_epoch())
+    .fetch_optional(self.pool)
+    .await
+  }
+
+  /// Spend a token. Returns the person it was issued for, or `None` if it is unknown,
+  /// expired or already used.
+  pub async fn consume(&self, token: &str) -> Result<Option<i32>, sqlx::Error> {
+    let now = Self::now_epoch();
+    sqlx::query_scalar::<_, i32>(
+      "UPDATE auth.password_reset_tokens SET used_at = $2
+       WHERE token_hash = $1 AND used_at IS NULL AND expires_at > $2
+       RETURNING person_id",
+    )
+    .bind(TokenManager::hash_token(token))
+    .bind(now)
+    .fetch_optional(self.pool)
+    .await
+  }
+
+  pub async fn cleanup_expired(&self) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query("DELETE FROM auth.password_reset_tokens WHERE expires_at <= $1")
+      .bind(Self::now_epoch())
+      .execute(self.pool)
+      .await?
+      .rows_affected();
+    Ok(rows)
+  }
+}
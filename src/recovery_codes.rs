@@ -0,0 +1,108 @@
+use crate::auth::TokenManager;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sqlx::{Pool, Postgres};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Codes in a set; a new set replaces the previous one entirely.
+const CODE_COUNT: usize = 10;
+/// Random bytes per code, shown as two groups of five hex digits.
+const CODE_BYTES: usize = 5;
+
+/// Single-use codes that stand in for a TOTP code when the authenticator is lost.
+/// Stored in `auth.recovery_codes` by SHA-256 only; spent codes are kept for counting.
+pub struct RecoveryCodes<'a> {
+  pool: &'a Pool<Postgres>,
+}
+
+impl<'a> RecoveryCodes<'a> {
+  pub fn new(pool: &'a Pool<Postgres>) -> Self {
+    Self { pool }
+  }
+
+  fn now_epoch() -> i64 {
+    SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs() as i64
+  }
+
+  fn generate_code() -> String {
+    let mut random = [0u8; CODE_BYTES];
+    OsRng.fill_bytes(&mut random);
+    let hex: String = random.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}", &hex[..5], &hex[5..])
+  }
+
+  /// Codes are compared without case, spaces or dashes, however they were typed.
+  fn hash_code(code: &str) -> String {
+    let normalized: String = code
+      .chars()
+      .filter(char::is_ascii_alphanumeric)
+      .map(|c| c.to_ascii_lowercase())
+      .collect();
+    TokenManager::hash_token(&normalized)
+  }
+
+  /// Replace the person's codes with a fresh set and return it; this is the only time
+  /// the codes are readable.
+  pub async fn regenerate(&self, person_id: i32) -> Result<Vec<String>, sqlx::Error> {
+    let codes: Vec<String> = (0..CODE_COUNT).map(|_| Self::generate_code()).collect();
+    let hashes: Vec<String> = codes.iter().map(|code| Self::hash_code(code)).collect();
+    let mut tx = self.pool.begin().await?;
+    sqlx::query("DELETE FROM auth.recovery_codes WHERE person_id = $1")
+      .bind(person_id)
+      .execute(&mut *tx)
+      .await?;
+    sqlx::query(
+      "INSERT INTO auth.recovery_codes (person_id, code_hash)
+       SELECT $1, UNNEST($2::TEXT[])",
+    )
+    .bind(person_id)
+    .bind(&hashes)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(codes)
+  }
+
+  /// Spend a code. Returns how many unused codes are left, or `None` if the code is
+  /// unknown or already spent.
+  pub async fn redeem(&self, person_id: i32, code: &str) -> Result<Option<i64>, sqlx::Error> {
+    let rows = sqlx::query(
+      "UPDATE auth.recovery_codes SET used_at = $3
+       WHERE person_id = $1 AND code_hash = $2 AND used_at IS NULL",
+    )
+    .bind(person_id)
+    .bind(Self::hash_code(code))
+    .bind(Self::now_epoch())
+    .execute(self.pool)
+    .await?
+    .rows_affected();
+    if rows == 0 {
+      return Ok(None);
+    }
+    let (remaining, _) = self.counts(person_id).await?;
+    Ok(Some(remaining))
+  }
+
+  /// Unused and spent codes of the current set.
+  pub async fn counts(&self, person_id: i32) -> Result<(i64, i64), sqlx::Error> {
+    sqlx::query_as::<_, (i64, i64)>(
+      "SELECT COUNT(*) FILTER (WHERE used_at IS NULL), COUNT(*) FILTER (WHERE used_at IS NOT NULL)
+       FROM auth.recovery_codes WHERE person_id = $1",
+    )
+    .bind(person_id)
+    .fetch_one(self.pool)
+    .await
+  }
+
+  pub async fn clear(&self, person_id: i32) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query("DELETE FROM auth.recovery_codes WHERE person_id = $1")
+      .bind(person_id)
+      .execute(self.pool)
+      .await?
+      .rows_affected();
+    Ok(rows)
+  }
+}
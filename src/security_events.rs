@@ -0,0 +1,71 @@
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{Pool, Postgres};
+
+pub const TOTP_ENABLED: &str = "totp_enabled";
+pub const TOTP_DISABLED: &str = "totp_disabled";
+pub const TOTP_RESET: &str = "totp_reset";
+pub const RECOVERY_CODES_GENERATED: &str = "recovery_codes_generated";
+pub const RECOVERY_CODE_USED: &str = "recovery_code_used";
+pub const PASSWORD_RESET_REQUESTED: &str = "password_reset_requested";
+pub const PASSWORD_RESET: &str = "password_reset";
+pub const PASSWORD_CHANGED: &str = "password_changed";
+
+/// A change to, or unusual use of, a person's credentials.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct SecurityEvent {
+  pub id: i64,
+  pub event: String,
+  pub detail: Value,
+  pub ip: Option<String>,
+  pub user_agent: Option<String>,
+  pub created_at: i64,
+}
+
+pub struct SecurityEventRecord<'a> {
+  pub person_id: i32,
+  pub event: &'a str,
+  pub detail: Value,
+  pub ip: Option<&'a str>,
+  pub user_agent: Option<&'a str>,
+}
+
+/// Per-person security history in `auth.security_events`, shown to the person and to
+/// administrators. Rows are never updated by the API.
+pub struct SecurityEvents<'a> {
+  pool: &'a Pool<Postgres>,
+}
+
+impl<'a> SecurityEvents<'a> {
+  pub fn new(pool: &'a Pool<Postgres>) -> Self {
+    Self { pool }
+  }
+
+  pub async fn record(&self, record: &SecurityEventRecord<'_>) -> Result<(), sqlx::Error> {
+    sqlx::query(
+      "INSERT INTO auth.security_events (person_id, event, detail, ip, user_agent)
+       VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(record.person_id)
+    .bind(record.event)
+    .bind(&record.detail)
+    .bind(record.ip)
+    .bind(record.user_agent)
+    .execute(self.pool)
+    .await?;
+    Ok(())
+  }
+
+  /// Newest first.
+  pub async fn list_for_person(&self, person_id: i32) -> Result<Vec<SecurityEvent>, sqlx::Error> {
+    sqlx::query_as::<_, SecurityEvent>(
+      "SELECT id, event, detail, ip, user_agent, created_at
+       FROM auth.security_events
+       WHERE person_id = $1
+       ORDER BY created_at DESC, id DESC",
+    )
+    .bind(person_id)
+    .fetch_all(self.pool)
+    .await
+  }
+}
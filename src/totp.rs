@@ -0,0 +1,277 @@
+use crate::config::{self, ConfigError};
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+use std::env;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// RFC 6238 parameters understood by every common authenticator app.
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+const SECRET_BYTES: usize = 20;
+/// Codes of the previous and next time step are accepted to absorb clock drift.
+const SKEW_STEPS: i64 = 1;
+const NONCE_BYTES: usize = 12;
+
+#[derive(Clone)]
+pub struct TotpConfig {
+  /// Shown as the account's issuer in authenticator apps.
+  pub issuer: String,
+  /// AES-256 key for the stored secrets, derived from `TOTP_ENCRYPTION_KEY`.
+  encryption_key: [u8; 32],
+}
+
+impl TotpConfig {
+  /// Fails when neither `TOTP_ENCRYPTION_KEY` nor `JWT_SECRET` is set outside development.
+  pub fn load() -> Result<Self, ConfigError> {
+    let issuer = env::var("TOTP_ISSUER")
+      .ok()
+      .map(|v| v.trim().to_string())
+      .filter(|v| !v.is_empty())
+      .unwrap_or_else(|| "Eqeqo".to_string());
+    let passphrase = config::secret("TOTP_ENCRYPTION_KEY")?;
+    Ok(Self {
+      issuer,
+      encryption_key: Sha256::digest(passphrase.as_bytes()).into(),
+    })
+  }
+}
+
+#[derive(Debug)]
+pub enum TotpError {
+  /// The person has no enrollment in the state the operation needs.
+  NotEnrolled,
+  AlreadyEnabled,
+  /// A stored secret could not be decrypted, e.g. after changing `TOTP_ENCRYPTION_KEY`.
+  Crypto,
+  Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for TotpError {
+  fn from(err: sqlx::Error) -> Self {
+    TotpError::Database(err)
+  }
+}
+
+impl fmt::Display for TotpError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TotpError::NotEnrolled => write!(f, "TOTP not enrolled"),
+      TotpError::AlreadyEnabled => write!(f, "TOTP already enabled"),
+      TotpError::Crypto => write!(f, "failed to decrypt TOTP secret"),
+      TotpError::Database(err) => write!(f, "{}", err),
+    }
+  }
+}
+
+/// A new, not yet confirmed secret; the only time it leaves the server.
+#[derive(Debug, serde::Serialize)]
+pub struct TotpEnrollment {
+  pub secret: String,
+  pub provisioning_uri: String,
+}
+
+/// RFC 4226 HOTP value of `secret` for `counter`, zero-padded to six digits.
+fn hotp(secret: &[u8], counter: u64) -> String {
+  let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC accepts any key length");
+  mac.update(&counter.to_be_bytes());
+  let digest = mac.finalize().into_bytes();
+  let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+  let binary = u32::from_be_bytes([
+    digest[offset] & 0x7f,
+    digest[offset + 1],
+    digest[offset + 2],
+    digest[offset + 3],
+  ]);
+  format!(
+    "{:0width$}",
+    binary % 10u32.pow(DIGITS),
+    width = DIGITS as usize
+  )
+}
+
+/// Percent-encode a label or parameter of an `otpauth://` URI.
+fn uri_component(value: &str) -> String {
+  form_urlencoded::byte_serialize(value.as_bytes())
+    .collect::<String>()
+    .replace('+', "%20")
+}
+
+/// TOTP second factors in `auth.person_totp`. Secrets are encrypted with AES-256-GCM;
+/// an enrollment only counts once a first code has confirmed it.
+pub struct TotpManager<'a> {
+  pool: &'a Pool<Postgres>,
+  config: TotpConfig,
+}
+
+impl<'a> TotpManager<'a> {
+  pub fn new(pool: &'a Pool<Postgres>) -> Self {
+    let config = TotpConfig::load().expect("TOTP settings are checked at startup");
+    Self { pool, config }
+  }
+
+  fn current_step() -> i64 {
+    SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs() as i64
+      / STEP_SECONDS
+  }
+
+  fn encrypt(&self, secret: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let cipher = Aes256Gcm::new(&self.config.encryption_key.into());
+    let mut nonce = [0u8; NONCE_BYTES];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher
+      .encrypt(Nonce::from_slice(&nonce), secret)
+      .expect("AES-GCM encryption of a short secret cannot fail");
+    (ciphertext, nonce.to_vec())
+  }
+
+  fn decrypt(&self, ciphertext: &[u8], nonce: &[u8]) -> Result<Vec<u8>, TotpError> {
+    if nonce.len() != NONCE_BYTES {
+      return Err(TotpError::Crypto);
+    }
+    Aes256Gcm::new(&self.config.encryption_key.into())
+      .decrypt(Nonce::from_slice(nonce), ciphertext)
+      .map_err(|_| TotpError::Crypto)
+  }
+
+  /// The time step `code` belongs to, if it is valid for `secret` around now.
+  fn matching_step(secret: &[u8], code: &str) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+      return None;
+    }
+    let now = Self::current_step();
+    (now - SKEW_STEPS..=now + SKEW_STEPS).find(|step| hotp(secret, *step as u64) == code)
+  }
+
+  /// Whether the person has a confirmed enrollment, i.e. logins need a second step.
+  pub async fn is_enabled(&self, person_id: i32) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar::<_, bool>(
+      "SELECT EXISTS (SELECT 1 FROM auth.person_totp WHERE person_id = $1 AND confirmed_at IS NOT NULL)",
+    )
+    .bind(person_id)
+    .fetch_one(self.pool)
+    .await
+  }
+
+  /// Start (or restart) an enrollment with a fresh secret. An enabled enrollment has
+  /// to be removed first.
+  pub async fn begin_enrollment(
+    &self,
+    person_id: i32,
+    username: &str,
+  ) -> Result<TotpEnrollment, TotpError> {
+    let mut secret = [0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut secret);
+    let (ciphertext, nonce) = self.encrypt(&secret);
+    let rows = sqlx::query(
+      "INSERT INTO auth.person_totp (person_id, secret_ciphertext, secret_nonce)
+       VALUES ($1, $2, $3)
+       ON CONFLICT (person_id) DO UPDATE
+         SET secret_ciphertext = EXCLUDED.secret_ciphertext,
+             secret_nonce = EXCLUDED.secret_nonce,
+             last_used_step = NULL
+         WHERE auth.person_totp.confirmed_at IS NULL",
+    )
+    .bind(person_id)
+    .bind(ciphertext)
+    .bind(nonce)
+    .execute(self.pool)
+    .await?
+    .rows_affected();
+    if rows == 0 {
+      return Err(TotpError::AlreadyEnabled);
+    }
+
+    let encoded = base32::encode(base32::Alphabet::Rfc4648 { padding: false }, &secret);
+    let issuer = uri_component(&self.config.issuer);
+    let provisioning_uri = format!(
+      "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+      issuer,
+      uri_component(username),
+      encoded,
+      issuer,
+      DIGITS,
+      STEP_SECONDS
+    );
+    Ok(TotpEnrollment {
+      secret: encoded,
+      provisioning_uri,
+    })
+  }
+
+  async fn load_secret(&self, person_id: i32, confirmed: bool) -> Result<Vec<u8>, TotpError> {
+    let row = sqlx::query_as::<_, (Vec<u8>, Vec<u8>)>(
+      "SELECT secret_ciphertext, secret_nonce FROM auth.person_totp
+       WHERE person_id = $1 AND (confirmed_at IS NOT NULL) = $2",
+    )
+    .bind(person_id)
+    .bind(confirmed)
+    .fetch_optional(self.pool)
+    .await?;
+    match row {
+      Some((ciphertext, nonce)) => self.decrypt(&ciphertext, &nonce),
+      None => Err(TotpError::NotEnrolled),
+    }
+  }
+
+  /// Spend the time step of a valid code. Each step works once, so an observed code
+  /// cannot be replayed.
+  async fn claim_step(
+    &self,
+    person_id: i32,
+    step: i64,
+    confirm: bool,
+  ) -> Result<bool, sqlx::Error> {
+    let rows = sqlx::query(
+      "UPDATE auth.person_totp
+       SET last_used_step = $2,
+           confirmed_at = CASE WHEN $3 THEN EXTRACT(EPOCH FROM NOW())::BIGINT ELSE confirmed_at END
+       WHERE person_id = $1 AND (last_used_step IS NULL OR last_used_step < $2)",
+    )
+    .bind(person_id)
+    .bind(step)
+    .bind(confirm)
+    .execute(self.pool)
+    .await?
+    .rows_affected();
+    Ok(rows > 0)
+  }
+
+  /// Finish an enrollment with the first code from the authenticator app.
+  pub async fn confirm(&self, person_id: i32, code: &str) -> Result<bool, TotpError> {
+    let secret = self.load_secret(person_id, false).await?;
+    match Self::matching_step(&secret, code) {
+      Some(step) => Ok(self.claim_step(person_id, step, true).await?),
+      None => Ok(false),
+    }
+  }
+
+  /// Check a code against a confirmed enrollment.
+  pub async fn verify(&self, person_id: i32, code: &str) -> Result<bool, TotpError> {
+    let secret = self.load_secret(person_id, true).await?;
+    match Self::matching_step(&secret, code) {
+      Some(step) => Ok(self.claim_step(person_id, step, false).await?),
+      None => Ok(false),
+    }
+  }
+
+  /// Remove the enrollment, pending or confirmed; false when there was none.
+  pub async fn remove(&self, person_id: i32) -> Result<bool, sqlx::Error> {
+    let rows = sqlx::query("DELETE FROM auth.person_totp WHERE person_id = $1")
+      .bind(person_id)
+      .execute(self.pool)
+      .await?
+      .rows_affected();
+    Ok(rows > 0)
+  }
+}
@@ -1,40 +1,2162 @@
-use auth_api::auth_server;
+// Tests keep the closure style they were written in.
+#![allow(clippy::redundant_closure, clippy::manual_pattern_char_comparison)]
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use auth_api::{auth_server, check_settings, load_breached_list};
 use httpageboy::Server;
 use httpageboy::test_utils::{SERVER_URL, run_test, setup_test_server};
+use rand::rngs::OsRng;
 use std::time::Duration;
 use tokio::time::sleep;
 
-async fn create_test_server() -> Server {
-  let _ = dotenvy::dotenv();
-  auth_server(SERVER_URL, 10).await
-}
+async fn create_test_server() -> Server {
+  let _ = dotenvy::dotenv();
+  // Settings the local .env may leave out; values already set take precedence.
+  let _ = dotenvy::from_path("tests/test.env");
+  check_settings().expect("settings");
+  load_breached_list().expect("breached password list");
+  auth_server(SERVER_URL).await
+}
+
+/// Move the last password check of `token`'s session an hour into the past.
+async fn backdate_verification(token: &str) {
+  let _ = dotenvy::dotenv();
+  let url = std::env::var("DATABASE_URL").expect("DATABASE_URL");
+  let pool = sqlx::PgPool::connect(&url)
+    .await
+    .expect("database connection");
+  sqlx::query(
+    "UPDATE auth.tokens_cache SET verified_at = verified_at - 3600
+     WHERE token_hash = encode(sha256(convert_to($1, 'UTF8')), 'hex')",
+  )
+  .bind(token)
+  .execute(&pool)
+  .await
+  .expect("backdate verification");
+}
+
+/// Whether `token`'s session has a password check on record.
+async fn has_verification(token: &str) -> bool {
+  let _ = dotenvy::dotenv();
+  let url = std::env::var("DATABASE_URL").expect("DATABASE_URL");
+  let pool = sqlx::PgPool::connect(&url)
+    .await
+    .expect("database connection");
+  sqlx::query_scalar::<_, bool>(
+    "SELECT verified_at IS NOT NULL FROM auth.tokens_cache
+     WHERE token_hash = encode(sha256(convert_to($1, 'UTF8')), 'hex')",
+  )
+  .bind(token)
+  .fetch_one(&pool)
+  .await
+  .expect("verification state")
+}
+
+/// Make `token` look idle for an hour, past any token TTL used in tests.
+async fn backdate_activity(token: &str) {
+  let _ = dotenvy::dotenv();
+  let url = std::env::var("DATABASE_URL").expect("DATABASE_URL");
+  let pool = sqlx::PgPool::connect(&url)
+    .await
+    .expect("database connection");
+  sqlx::query(
+    "UPDATE auth.tokens_cache SET modified_at = modified_at - 3600
+     WHERE token_hash = encode(sha256(convert_to($1, 'UTF8')), 'hex')",
+  )
+  .bind(token)
+  .execute(&pool)
+  .await
+  .expect("backdate activity");
+}
+
+/// The `password_hash` column of `username` as stored.
+async fn stored_password_hash(username: &str) -> String {
+  let _ = dotenvy::dotenv();
+  let url = std::env::var("DATABASE_URL").expect("DATABASE_URL");
+  let pool = sqlx::PgPool::connect(&url)
+    .await
+    .expect("database connection");
+  sqlx::query_scalar::<_, String>("SELECT password_hash FROM auth.person WHERE username = $1")
+    .bind(username)
+    .fetch_one(&pool)
+    .await
+    .expect("stored password hash")
+}
+
+/// Overwrite the `password_hash` column of `username`.
+async fn set_stored_password_hash(username: &str, hash: &str) {
+  let _ = dotenvy::dotenv();
+  let url = std::env::var("DATABASE_URL").expect("DATABASE_URL");
+  let pool = sqlx::PgPool::connect(&url)
+    .await
+    .expect("database connection");
+  sqlx::query("UPDATE auth.person SET password_hash = $1 WHERE username = $2")
+    .bind(hash)
+    .bind(username)
+    .execute(&pool)
+    .await
+    .expect("set password hash");
+}
+
+/// RFC 6238 code for a base32 `secret`, `offset` time steps away from now.
+fn totp_code(secret: &str, offset: i64) -> String {
+  use hmac::{Hmac, Mac};
+  let key = base32::decode(base32::Alphabet::Rfc4648 { padding: false }, secret).expect("base32");
+  let step = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_secs() as i64
+    / 30
+    + offset;
+  let mut mac = Hmac::<sha1::Sha1>::new_from_slice(&key).expect("hmac key");
+  mac.update(&(step as u64).to_be_bytes());
+  let digest = mac.finalize().into_bytes();
+  let offset = (digest[19] & 0x0f) as usize;
+  let binary = u32::from_be_bytes([
+    digest[offset] & 0x7f,
+    digest[offset + 1],
+    digest[offset + 2],
+    digest[offset + 3],
+  ]);
+  format!("{:06}", binary % 1_000_000)
+}
+
+/// Body of the newest outbox message for `recipient`, waiting for background delivery.
+async fn read_outbox(recipient: &str) -> String {
+  let _ = dotenvy::dotenv();
+  let dir = std::env::var("NOTIFIER_OUTBOX_DIR").unwrap_or_else(|_| "outbox".to_string());
+  for _ in 0..50 {
+    let mut messages: Vec<(std::path::PathBuf, String)> = std::fs::read_dir(&dir)
+      .into_iter()
+      .flatten()
+      .flatten()
+      .filter_map(|entry| {
+        let content = std::fs::read_to_string(entry.path()).ok()?;
+        content
+          .contains(&format!("\"to\": \"{}\"", recipient))
+          .then(|| (entry.path(), content))
+      })
+      .collect();
+    messages.sort();
+    if let Some((_, content)) = messages.pop() {
+      return content;
+    }
+    sleep(Duration::from_millis(100)).await;
+  }
+  panic!("no outbox message for {}", recipient);
+}
+
+/// Number of outbox messages for `recipient` so far.
+fn outbox_count(recipient: &str) -> usize {
+  let _ = dotenvy::dotenv();
+  let dir = std::env::var("NOTIFIER_OUTBOX_DIR").unwrap_or_else(|_| "outbox".to_string());
+  std::fs::read_dir(&dir)
+    .into_iter()
+    .flatten()
+    .flatten()
+    .filter(|entry| {
+      std::fs::read_to_string(entry.path())
+        .is_ok_and(|content| content.contains(&format!("\"to\": \"{}\"", recipient)))
+    })
+    .count()
+}
+
+// Authentication
+
+#[tokio::test]
+async fn test_login_success() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+}
+
+#[tokio::test]
+async fn test_tokens_cache_stores_only_hash() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let token = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  )
+  .split("\"token\":\"")
+  .nth(1)
+  .and_then(|segment| segment.split('"').next())
+  .expect("token value")
+  .to_string();
+
+  let _ = dotenvy::dotenv();
+  let url = std::env::var("DATABASE_URL").expect("DATABASE_URL");
+  let pool = sqlx::PgPool::connect(&url)
+    .await
+    .expect("database connection");
+  let hashed = sqlx::query_scalar::<_, i64>(
+    "SELECT COUNT(*) FROM auth.tokens_cache
+     WHERE token_hash = encode(sha256(convert_to($1, 'UTF8')), 'hex')",
+  )
+  .bind(&token)
+  .fetch_one(&pool)
+  .await
+  .expect("hashed token count");
+  assert_eq!(hashed, 1);
+  // No column of any row, payload included, holds the raw token.
+  let raw = sqlx::query_scalar::<_, i64>(
+    "SELECT COUNT(*) FROM auth.tokens_cache t WHERE strpos(t::text, $1) > 0",
+  )
+  .bind(&token)
+  .fetch_one(&pool)
+  .await
+  .expect("raw token count");
+  assert_eq!(raw, 0);
+}
+
+#[tokio::test]
+async fn test_login_invalid_password() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"wrong\"}",
+    b"Invalid credentials",
+  );
+}
+
+#[tokio::test]
+async fn test_login_created_user_password_is_hashed() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("hashed_{}", suffix);
+  let password = format!("hashed_pass_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{uname}\",\"password\":\"{pwd}\",\"name\":\"Hashed User\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"h{suffix}\"}}",
+    token,
+    uname = username,
+    pwd = password,
+    suffix = suffix
+  );
+  run_test(create_request.as_bytes(), b"\"id\"");
+
+  let login_request = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  run_test(login_request.as_bytes(), b"\"token\"");
+
+  let stored = stored_password_hash(&username).await;
+  assert_ne!(stored, password);
+  let parsed = PasswordHash::new(&stored).expect("stored value is a PHC string");
+  assert_eq!(parsed.algorithm.as_str(), "argon2id");
+  assert!(parsed.salt.is_some());
+}
+
+#[tokio::test]
+async fn test_login_legacy_password_is_migrated() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"legacy1\",\"password\":\"legacy1-hash\"}";
+  run_test(login_request, b"\"token\"");
+  run_test(login_request, b"\"token\"");
+
+  run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"legacy1\",\"password\":\"wrong\"}",
+    b"Invalid credentials",
+  );
+}
+
+#[tokio::test]
+async fn test_login_other_phc_scheme_is_migrated() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let token = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  )
+  .split("\"token\":\"")
+  .nth(1)
+  .and_then(|segment| segment.split('"').next())
+  .expect("token value")
+  .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("argon2i_user_{}", suffix);
+  let password = format!("argon2i_pass_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{uname}\",\"password\":\"{pwd}\",\"name\":\"Argon2i User\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"a{suffix}\"}}",
+    token,
+    uname = username,
+    pwd = password,
+    suffix = suffix
+  );
+  run_test(create_request.as_bytes(), b"\"id\"");
+
+  // A PHC string of another scheme is legacy too: it still verifies, then is rewritten.
+  let argon2i_hash = Argon2::new(
+    Algorithm::Argon2i,
+    Version::V0x13,
+    Params::new(1024, 1, 1, None).unwrap(),
+  )
+  .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+  .unwrap()
+  .to_string();
+  set_stored_password_hash(&username, &argon2i_hash).await;
+
+  let login_request = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  run_test(login_request.as_bytes(), b"\"token\"");
+  assert!(
+    stored_password_hash(&username)
+      .await
+      .starts_with("$argon2id$")
+  );
+  run_test(login_request.as_bytes(), b"\"token\"");
+}
+
+#[tokio::test]
+async fn test_legacy_password_report_success() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let report_request = format!(
+    "GET /users/password-report HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    token
+  );
+  run_test(report_request.as_bytes(), b"\"legacy_count\"");
+}
+
+#[tokio::test]
+async fn test_login_lockout_and_unlock() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("lock_{}", suffix);
+  let password = format!("lock_pass_{}", suffix);
+  let ip = format!(
+    "10.{}.{}.{}",
+    suffix % 250,
+    (suffix / 250) % 250,
+    (suffix / 62500) % 250
+  );
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{uname}\",\"password\":\"{pwd}\",\"name\":\"Lock User\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"l{suffix}\"}}",
+    token,
+    uname = username,
+    pwd = password,
+    suffix = suffix
+  );
+  run_test(create_request.as_bytes(), b"\"id\"");
+
+  let wrong_request = format!(
+    "POST /auth/login HTTP/1.1\r\nX-Forwarded-For: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"wrong\"}}",
+    ip, username
+  );
+  for _ in 0..5 {
+    run_test(wrong_request.as_bytes(), b"Invalid credentials");
+  }
+
+  let login_request = format!(
+    "POST /auth/login HTTP/1.1\r\nX-Forwarded-For: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    ip, username, password
+  );
+  run_test(login_request.as_bytes(), b"\"retry_after\"");
+
+  let unlock_request = format!(
+    "POST /auth/unlock HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"ip\":\"{}\"}}",
+    token, username, ip
+  );
+  run_test(unlock_request.as_bytes(), b"\"status\":\"unlocked\"");
+  run_test(login_request.as_bytes(), b"\"token\"");
+}
+
+#[tokio::test]
+async fn test_unlock_invalid_body() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let unlock_request = format!(
+    "POST /auth/unlock HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{}}",
+    token
+  );
+  run_test(unlock_request.as_bytes(), b"Invalid request body");
+}
+
+#[tokio::test]
+async fn test_logout_success() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let logout_request = format!("POST /auth/logout HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
+  run_test(logout_request.as_bytes(), b"\"status\":\"logged_out\"");
+}
+
+#[tokio::test]
+async fn test_logout_missing_token() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  run_test(
+    b"POST /auth/logout HTTP/1.1\r\n\r\n",
+    b"Missing token header",
+  );
+}
+
+#[tokio::test]
+async fn test_refresh_rotates_and_detects_reuse() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"refresh_token\"",
+  );
+  let first_refresh = login_response
+    .split("\"refresh_token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("refresh token value")
+    .to_string();
+  let first_token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let refresh_request = format!(
+    "POST /auth/refresh HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"refresh_token\":\"{}\"}}",
+    first_refresh
+  );
+  let refresh_response = run_test(refresh_request.as_bytes(), b"\"refresh_token\"");
+  let token = refresh_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+  let second_refresh = refresh_response
+    .split("\"refresh_token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("refresh token value")
+    .to_string();
+  assert_ne!(first_refresh, second_refresh);
+
+  let profile_request = format!("GET /auth/profile HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
+  run_test(profile_request.as_bytes(), b"\"username\":\"adm1\"");
+  // The access token of the spent refresh token ends with it.
+  let first_profile_request = format!(
+    "GET /auth/profile HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    first_token
+  );
+  run_test(first_profile_request.as_bytes(), b"Invalid token");
+
+  // Presenting the already used refresh token revokes the whole family.
+  run_test(refresh_request.as_bytes(), b"Refresh token reuse detected");
+  let second_request = format!(
+    "POST /auth/refresh HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"refresh_token\":\"{}\"}}",
+    second_refresh
+  );
+  run_test(second_request.as_bytes(), b"Invalid refresh token");
+  run_test(profile_request.as_bytes(), b"Invalid token");
+}
+
+#[tokio::test]
+async fn test_refresh_invalid_token() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  run_test(
+    b"POST /auth/refresh HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"refresh_token\":\"invalid\"}",
+    b"Invalid refresh token",
+  );
+}
+
+#[tokio::test]
+async fn test_sessions_list_and_revoke() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let admin_token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("sessions_user_{}", suffix);
+  let password = format!("pass_sessions_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{uname}\",\"password\":\"{pwd}\",\"name\":\"Sessions User\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"s{suffix}\"}}",
+    admin_token,
+    uname = username,
+    pwd = password,
+    suffix = suffix
+  );
+  run_test(create_request.as_bytes(), b"\"id\"");
+
+  let login_request = format!(
+    "POST /auth/login HTTP/1.1\r\nUser-Agent: sessions-test-agent\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  let first_token = run_test(login_request.as_bytes(), b"\"token\"")
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+  let second_token = run_test(login_request.as_bytes(), b"\"token\"")
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let list_request = format!(
+    "GET /auth/sessions HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    first_token
+  );
+  let list_response = run_test(list_request.as_bytes(), b"sessions-test-agent");
+  assert!(!list_response.contains(&first_token));
+  assert!(!list_response.contains(&second_token));
+  let other_session = list_response
+    .split('}')
+    .find(|session| session.contains("\"current\":false"))
+    .and_then(|session| session.split("\"session_id\":\"").nth(1))
+    .and_then(|segment| segment.split('"').next())
+    .expect("other session id")
+    .to_string();
+
+  let revoke_request = format!(
+    "DELETE /auth/sessions/{} HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    other_session, first_token
+  );
+  run_test(revoke_request.as_bytes(), b"\"revoked\"");
+  let profile_request = format!(
+    "GET /auth/profile HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    second_token
+  );
+  run_test(profile_request.as_bytes(), b"Invalid token");
+  run_test(revoke_request.as_bytes(), b"Session not found");
+}
+
+#[tokio::test]
+async fn test_session_last_seen_without_renewal() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let token = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  )
+  .split("\"token\":\"")
+  .nth(1)
+  .and_then(|segment| segment.split('"').next())
+  .expect("token value")
+  .to_string();
+
+  let _ = dotenvy::dotenv();
+  let url = std::env::var("DATABASE_URL").expect("DATABASE_URL");
+  let pool = sqlx::PgPool::connect(&url)
+    .await
+    .expect("database connection");
+  let last_seen_at = || {
+    sqlx::query_scalar::<_, i64>(
+      "SELECT last_seen_at FROM auth.tokens_cache
+       WHERE token_hash = encode(sha256(convert_to($1, 'UTF8')), 'hex')",
+    )
+    .bind(&token)
+    .fetch_one(&pool)
+  };
+  sqlx::query(
+    "UPDATE auth.tokens_cache SET last_seen_at = last_seen_at - 3600
+     WHERE token_hash = encode(sha256(convert_to($1, 'UTF8')), 'hex')",
+  )
+  .bind(&token)
+  .execute(&pool)
+  .await
+  .expect("backdate last_seen_at");
+  let before = last_seen_at().await.expect("last_seen_at");
+
+  // A fresh token is not renewed, but using it still counts as activity.
+  let profile_request = format!("GET /auth/profile HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
+  let profile_response = run_test(profile_request.as_bytes(), b"\"payload\"");
+  assert!(profile_response.contains("\"renewed\":false"));
+  let after = last_seen_at().await.expect("last_seen_at");
+  assert!(after >= before + 3600);
+}
+
+#[tokio::test]
+async fn test_user_sessions_admin_revoke() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let admin_token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("revoked_user_{}", suffix);
+  let password = format!("pass_revoked_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{uname}\",\"password\":\"{pwd}\",\"name\":\"Revoked User\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"r{suffix}\"}}",
+    admin_token,
+    uname = username,
+    pwd = password,
+    suffix = suffix
+  );
+  let create_response = run_test(create_request.as_bytes(), b"\"id\"");
+  let user_id = create_response
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("user id segment")
+    .trim()
+    .to_string();
+
+  let login_request = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  let user_token = run_test(login_request.as_bytes(), b"\"token\"")
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let forbidden_request = format!(
+    "DELETE /users/{}/sessions HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    user_id, user_token
+  );
+  run_test(
+    forbidden_request.as_bytes(),
+    b"Missing permission: users.write",
+  );
+
+  let revoke_request = format!(
+    "DELETE /users/{}/sessions HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    user_id, admin_token
+  );
+  run_test(revoke_request.as_bytes(), b"HTTP/1.1 204 No Content");
+  let profile_request = format!(
+    "GET /auth/profile HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    user_token
+  );
+  run_test(profile_request.as_bytes(), b"Invalid token");
+}
+
+#[tokio::test]
+async fn test_service_session_limit_evicts_oldest() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_service_request = format!(
+    "POST /services HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"Terminal Service {}\",\"description\":\"Session limit\"}}",
+    token, suffix
+  );
+  let service_response = run_test(create_service_request.as_bytes(), b"\"id\"");
+  let service_id = service_response
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("service id segment")
+    .trim()
+    .to_string();
+
+  let limit_request = format!(
+    "PUT /services/{} HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"max_sessions\":1}}",
+    service_id, token
+  );
+  run_test(limit_request.as_bytes(), b"\"success\"");
+
+  let service_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"adm1\",\"password\":\"adm1-hash\",\"service_id\":{}}}",
+    service_id
+  );
+  let first_token = run_test(service_login.as_bytes(), b"\"token\"")
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+  let second_token = run_test(service_login.as_bytes(), b"\"token\"")
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let first_profile = format!(
+    "GET /auth/profile HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    first_token
+  );
+  run_test(first_profile.as_bytes(), b"Invalid token");
+  let second_profile = format!(
+    "GET /auth/profile HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    second_token
+  );
+  run_test(second_profile.as_bytes(), b"\"payload\"");
+  // Sessions outside the service are not affected by its limit.
+  let profile_request = format!("GET /auth/profile HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
+  run_test(profile_request.as_bytes(), b"\"payload\"");
+}
+
+#[tokio::test]
+async fn test_login_unknown_service() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\",\"service_id\":-1}",
+    b"Service not found",
+  );
+}
+
+#[tokio::test]
+async fn test_profile_success() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let profile_request = format!("GET /auth/profile HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
+  run_test(profile_request.as_bytes(), b"\"payload\"");
+}
+
+#[tokio::test]
+async fn test_profile_max_lifetime_survives_refresh() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+  let refresh_token = login_response
+    .split("\"refresh_token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("refresh token value")
+    .to_string();
+
+  let profile_request = format!("GET /auth/profile HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
+  let profile_response = run_test(profile_request.as_bytes(), b"\"max_expires_at\"");
+  let max_expires_at = profile_response
+    .split("\"max_expires_at\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("max_expires_at value")
+    .to_string();
+  assert_ne!(max_expires_at, "null");
+
+  let refresh_request = format!(
+    "POST /auth/refresh HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"refresh_token\":\"{}\"}}",
+    refresh_token
+  );
+  let refresh_response = run_test(refresh_request.as_bytes(), b"\"token\"");
+  let refreshed_token = refresh_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  // The absolute deadline is measured from the original login, not the refresh.
+  let check_request = format!(
+    "POST /check-token HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    refreshed_token
+  );
+  let expected = format!("\"max_expires_at\":{}", max_expires_at);
+  run_test(check_request.as_bytes(), expected.as_bytes());
+}
+
+#[tokio::test]
+async fn test_profile_concurrent_requests_share_pool() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let profile_request = format!("GET /auth/profile HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
+  let workers: Vec<_> = (0..50)
+    .map(|_| {
+      let request = profile_request.clone();
+      std::thread::spawn(move || {
+        run_test(request.as_bytes(), b"\"payload\"");
+      })
+    })
+    .collect();
+  for worker in workers {
+    worker.join().expect("profile request");
+  }
+}
+
+#[tokio::test]
+async fn test_profile_missing_token() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  run_test(
+    b"GET /auth/profile HTTP/1.1\r\n\r\n",
+    b"Missing token header",
+  );
+}
+
+#[tokio::test]
+async fn test_check_token_success() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let check_request = format!("POST /check-token HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
+  run_test(check_request.as_bytes(), b"\"valid\":true");
+}
+
+#[tokio::test]
+async fn test_check_token_service_binding() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+  let user_id = login_response
+    .split("\"user_id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("user id segment")
+    .trim()
+    .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_service_request = format!(
+    "POST /services HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"Bound Service {}\",\"description\":\"Check token\"}}",
+    token, suffix
+  );
+  let service_response = run_test(create_service_request.as_bytes(), b"\"id\"");
+  let service_id = service_response
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("service id segment")
+    .trim()
+    .to_string();
+
+  let check_body = format!(
+    "{{\"token\":\"{}\",\"service_id\":{},\"user_id\":{}}}",
+    token, service_id, user_id
+  );
+  let check_request = format!(
+    "POST /check-token HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{}",
+    check_body
+  );
+  run_test(check_request.as_bytes(), b"Person has no role in service");
+
+  let role_request = format!(
+    "POST /roles HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"bound_role_{}\"}}",
+    token, suffix
+  );
+  let role_response = run_test(role_request.as_bytes(), b"\"id\"");
+  let role_id = role_response
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("role id segment")
+    .trim()
+    .to_string();
+  let assign_service_request = format!(
+    "POST /service-roles HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":{},\"role_id\":{}}}",
+    token, service_id, role_id
+  );
+  run_test(assign_service_request.as_bytes(), b"\"status\":\"success\"");
+  let assign_person_request = format!(
+    "POST /person-service-roles HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":{},\"service_id\":{},\"role_id\":{}}}",
+    token, user_id, service_id, role_id
+  );
+  run_test(assign_person_request.as_bytes(), b"\"status\":\"success\"");
+
+  let expected_role = format!("\"name\":\"bound_role_{}\"", suffix);
+  let valid_response = run_test(check_request.as_bytes(), expected_role.as_bytes());
+  assert!(valid_response.contains("\"valid\":true"));
+  assert!(valid_response.contains("\"permissions\""));
+
+  let delete_service_request = format!(
+    "DELETE /services/{} HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    service_id, token
+  );
+  run_test(delete_service_request.as_bytes(), b"204");
+  run_test(check_request.as_bytes(), b"Service is disabled");
+}
+
+#[tokio::test]
+async fn test_check_token_user_mismatch() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let check_request = format!(
+    "POST /check-token HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"user_id\":-1}}",
+    token
+  );
+  run_test(check_request.as_bytes(), b"\"valid\":false");
+}
+
+#[tokio::test]
+async fn test_check_token_invalid_token() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  run_test(
+    b"POST /check-token HTTP/1.1\r\ntoken: invalid\r\n\r\n",
+    b"Invalid token",
+  );
+}
+
+#[tokio::test]
+async fn test_service_api_key_checks() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_service_request = format!(
+    "POST /services HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"Keyed Service {}\",\"description\":\"API key\"}}",
+    token, suffix
+  );
+  let service_response = run_test(create_service_request.as_bytes(), b"\"id\"");
+  let service_id = service_response
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("service id segment")
+    .trim()
+    .to_string();
+
+  let issue_request = format!(
+    "POST /services/{}/api-keys HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    service_id, token
+  );
+  let issue_response = run_test(issue_request.as_bytes(), b"\"api_key\"");
+  let api_key = issue_response
+    .split("\"api_key\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("api key value")
+    .to_string();
+  let key_id = issue_response
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("key id segment")
+    .trim()
+    .to_string();
+
+  let list_request = format!(
+    "GET /services/{}/api-keys HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    service_id, token
+  );
+  let list_response = run_test(list_request.as_bytes(), b"\"prefix\"");
+  assert!(!list_response.contains(&api_key));
+
+  // The key's service is used for the check when the body does not name one.
+  let check_request = format!(
+    "POST /check-token HTTP/1.1\r\nx-api-key: {}\r\nContent-Type: application/json\r\n\r\n{{\"token\":\"{}\"}}",
+    api_key, token
+  );
+  run_test(check_request.as_bytes(), b"Person has no role in service");
+  let other_service_request = format!(
+    "POST /check-token HTTP/1.1\r\nx-api-key: {}\r\nContent-Type: application/json\r\n\r\n{{\"token\":\"{}\",\"service_id\":-1}}",
+    api_key, token
+  );
+  run_test(
+    other_service_request.as_bytes(),
+    b"API key does not belong to service",
+  );
+  let permission_body = format!(
+    "{{\"person_id\":1,\"service_id\":{},\"permission_name\":\"users.read\"}}",
+    service_id
+  );
+  let permission_request = format!(
+    "GET /check-permission HTTP/1.1\r\nx-api-key: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+    api_key,
+    permission_body.len(),
+    permission_body
+  );
+  run_test(permission_request.as_bytes(), b"\"has_permission\":false");
+
+  let rotate_request = format!(
+    "POST /services/{}/api-keys/{}/rotate HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    service_id, key_id, token
+  );
+  let rotate_response = run_test(rotate_request.as_bytes(), b"\"api_key\"");
+  let rotated_key = rotate_response
+    .split("\"api_key\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("api key value")
+    .to_string();
+  let rotated_id = rotate_response
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("key id segment")
+    .trim()
+    .to_string();
+  run_test(permission_request.as_bytes(), b"Invalid API key");
+  let rotated_permission_request = permission_request.replace(&api_key, &rotated_key);
+  run_test(
+    rotated_permission_request.as_bytes(),
+    b"\"has_permission\":false",
+  );
+
+  let revoke_request = format!(
+    "DELETE /services/{}/api-keys/{} HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    service_id, rotated_id, token
+  );
+  run_test(revoke_request.as_bytes(), b"HTTP/1.1 204 No Content");
+  run_test(rotated_permission_request.as_bytes(), b"Invalid API key");
+}
+
+#[tokio::test]
+async fn test_check_token_invalid_api_key() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  run_test(
+    b"POST /check-token HTTP/1.1\r\nx-api-key: eqk_invalid\r\ntoken: invalid\r\n\r\n",
+    b"Invalid API key",
+  );
+}
+
+// OAuth
+
+#[tokio::test]
+async fn test_step_up_reauthentication() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let admin_token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+  let profile_request = format!(
+    "GET /auth/profile HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    admin_token
+  );
+  run_test(profile_request.as_bytes(), b"\"verified_at\":1");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("stepup_user_{}", suffix);
+  let password = format!("pass_stepup_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{uname}\",\"password\":\"{pwd}\",\"name\":\"Step Up User\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"u{suffix}\"}}",
+    admin_token,
+    uname = username,
+    pwd = password,
+    suffix = suffix
+  );
+  let user_id = run_test(create_request.as_bytes(), b"\"id\"")
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("user id")
+    .trim()
+    .to_string();
+
+  // A wrong password does not verify the session.
+  let user_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  let user_token = run_test(user_login.as_bytes(), b"\"token\"")
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+  let wrong_request = format!(
+    "POST /auth/reauthenticate HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"password\":\"wrong\"}}",
+    user_token
+  );
+  run_test(wrong_request.as_bytes(), b"Invalid credentials");
+
+  // Once the last password check is old, only sensitive operations are refused.
+  backdate_verification(&admin_token).await;
+  let rename_request = format!(
+    "PUT /users/{} HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"Renamed\"}}",
+    user_id, admin_token
+  );
+  run_test(rename_request.as_bytes(), b"\"status\":\"success\"");
+  let password_request = format!(
+    "PUT /users/{} HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"password\":\"pass_changed_{}\"}}",
+    user_id, admin_token, suffix
+  );
+  run_test(
+    password_request.as_bytes(),
+    b"\"code\":\"reauthentication_required\"",
+  );
+  let delete_request = format!(
+    "DELETE /users/{} HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    user_id, admin_token
+  );
+  run_test(
+    delete_request.as_bytes(),
+    b"\"code\":\"reauthentication_required\"",
+  );
+
+  let reauth_request = format!(
+    "POST /auth/reauthenticate HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"password\":\"adm1-hash\"}}",
+    admin_token
+  );
+  run_test(reauth_request.as_bytes(), b"\"status\":\"verified\"");
+  run_test(password_request.as_bytes(), b"\"status\":\"success\"");
+  run_test(delete_request.as_bytes(), b"HTTP/1.1 204 No Content");
+}
+
+#[tokio::test]
+async fn test_totp_two_step_login() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let admin_token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("totp_user_{}", suffix);
+  let password = format!("pass_totp_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{uname}\",\"password\":\"{pwd}\",\"name\":\"TOTP User\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"t{suffix}\"}}",
+    admin_token,
+    uname = username,
+    pwd = password,
+    suffix = suffix
+  );
+  let user_id = run_test(create_request.as_bytes(), b"\"id\"")
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("user id")
+    .trim()
+    .to_string();
+
+  let user_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  let user_token = run_test(user_login.as_bytes(), b"\"token\"")
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  // Enrollment is only active after a first code confirms it.
+  let enroll_request = format!("POST /auth/totp HTTP/1.1\r\ntoken: {}\r\n\r\n", user_token);
+  let enroll_response = run_test(enroll_request.as_bytes(), b"otpauth://totp/");
+  let secret = enroll_response
+    .split("\"secret\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("secret")
+    .to_string();
+  let confirm_request = |code: &str| {
+    format!(
+      "POST /auth/totp/confirm HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"code\":\"{}\"}}",
+      user_token, code
+    )
+  };
+  let wrong_code = if totp_code(&secret, 0) == "000000" {
+    "111111"
+  } else {
+    "000000"
+  };
+  run_test(
+    confirm_request(wrong_code).as_bytes(),
+    b"Invalid verification code",
+  );
+  run_test(
+    confirm_request(&totp_code(&secret, 0)).as_bytes(),
+    b"\"status\":\"enabled\"",
+  );
+  run_test(enroll_request.as_bytes(), b"TOTP already enabled");
+
+  // The password now only opens a challenge.
+  let challenge_response = run_test(user_login.as_bytes(), b"\"mfa_required\":true");
+  assert!(!challenge_response.contains("\"token\""));
+  let challenge_id = challenge_response
+    .split("\"challenge_id\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("challenge id")
+    .to_string();
+  let verify_request = |code: &str| {
+    format!(
+      "POST /auth/login/verify HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"challenge_id\":\"{}\",\"code\":\"{}\"}}",
+      challenge_id, code
+    )
+  };
+  run_test(
+    verify_request(wrong_code).as_bytes(),
+    b"Invalid verification code",
+  );
+  // The confirmation spent the current step; the next one is still inside the window.
+  run_test(
+    verify_request(&totp_code(&secret, 1)).as_bytes(),
+    b"\"refresh_token\"",
+  );
+  run_test(
+    verify_request(&totp_code(&secret, 1)).as_bytes(),
+    b"Invalid or expired challenge",
+  );
+
+  // An administrator reset brings back the single-step login.
+  let reset_request = format!(
+    "DELETE /users/{}/totp HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    user_id, admin_token
+  );
+  run_test(reset_request.as_bytes(), b"HTTP/1.1 204 No Content");
+  run_test(reset_request.as_bytes(), b"TOTP not enrolled");
+  run_test(user_login.as_bytes(), b"\"refresh_token\"");
+}
+
+#[tokio::test]
+async fn test_totp_recovery_codes() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let admin_token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("recovery_user_{}", suffix);
+  let password = format!("pass_recovery_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{uname}\",\"password\":\"{pwd}\",\"name\":\"Recovery User\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"r{suffix}\"}}",
+    admin_token,
+    uname = username,
+    pwd = password,
+    suffix = suffix
+  );
+  run_test(create_request.as_bytes(), b"\"id\"");
+
+  let user_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  let user_token = run_test(user_login.as_bytes(), b"\"token\"")
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+  let enroll_request = format!("POST /auth/totp HTTP/1.1\r\ntoken: {}\r\n\r\n", user_token);
+  let secret = run_test(enroll_request.as_bytes(), b"\"secret\"")
+    .split("\"secret\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("secret")
+    .to_string();
+  let confirm_request = format!(
+    "POST /auth/totp/confirm HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"code\":\"{}\"}}",
+    user_token,
+    totp_code(&secret, 0)
+  );
+  let recovery_code = run_test(confirm_request.as_bytes(), b"\"recovery_codes\"")
+    .split("\"recovery_codes\":[\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("recovery code")
+    .to_string();
+
+  // A recovery code replaces the TOTP code once; case and dashes do not matter.
+  let challenge_id = |response: String| {
+    response
+      .split("\"challenge_id\":\"")
+      .nth(1)
+      .and_then(|segment| segment.split('"').next())
+      .expect("challenge id")
+      .to_string()
+  };
+  let recovery_request = |challenge_id: &str, code: &str| {
+    format!(
+      "POST /auth/login/verify HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"challenge_id\":\"{}\",\"recovery_code\":\"{}\"}}",
+      challenge_id, code
+    )
+  };
+  let first_challenge = challenge_id(run_test(user_login.as_bytes(), b"\"recovery_code\""));
+  run_test(
+    recovery_request(
+      &first_challenge,
+      &recovery_code.to_uppercase().replace('-', ""),
+    )
+    .as_bytes(),
+    b"\"refresh_token\"",
+  );
+  let second_challenge = challenge_id(run_test(user_login.as_bytes(), b"\"mfa_required\""));
+  run_test(
+    recovery_request(&second_challenge, &recovery_code).as_bytes(),
+    b"Invalid verification code",
+  );
+
+  let status_request = format!(
+    "GET /auth/totp/recovery-codes HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    user_token
+  );
+  run_test(status_request.as_bytes(), b"\"remaining\":9");
+  let events_request = format!(
+    "GET /auth/security-events HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    user_token
+  );
+  let events = run_test(events_request.as_bytes(), b"\"recovery_code_used\"");
+  assert!(events.contains("\"remaining\":9"));
+  assert!(events.contains("\"totp_enabled\""));
+
+  // A new set replaces every previous code.
+  let regenerate_request = format!(
+    "POST /auth/totp/recovery-codes HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    user_token
+  );
+  run_test(regenerate_request.as_bytes(), b"\"recovery_codes\"");
+  run_test(status_request.as_bytes(), b"\"remaining\":10");
+}
+
+#[tokio::test]
+async fn test_password_reset() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let admin_token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("reset_user_{}", suffix);
+  let email = format!("reset_{}@example.com", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{uname}\",\"password\":\"pass_reset_{suffix}\",\"name\":\"Reset User\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"p{suffix}\",\"email\":\"{email}\"}}",
+    admin_token,
+    uname = username,
+    suffix = suffix,
+    email = email
+  );
+  run_test(create_request.as_bytes(), b"\"id\"");
+  let user_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"pass_reset_{}\"}}",
+    username, suffix
+  );
+  let user_token = run_test(user_login.as_bytes(), b"\"token\"")
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  // Unknown accounts get the same answer.
+  run_test(
+    b"POST /auth/password/forgot HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"nobody_here\"}",
+    b"HTTP/1.1 202 Accepted",
+  );
+  // Another person's email alongside the username does not redirect the message.
+  let other_email = format!("reset_other_{}@example.com", suffix);
+  let other_request = create_request
+    .replace(&username, &format!("reset_other_{}", suffix))
+    .replace(&format!("p{}", suffix), &format!("q{}", suffix))
+    .replace(&email, &other_email);
+  run_test(other_request.as_bytes(), b"\"id\"");
+  let forgot_request = format!(
+    "POST /auth/password/forgot HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"email\":\"{}\"}}",
+    username, other_email
+  );
+  run_test(forgot_request.as_bytes(), b"\"status\":\"requested\"");
+  let outbox_message = read_outbox(&email).await;
+  assert_eq!(outbox_count(&other_email), 0);
+
+  // A repeated request within the cooldown sends nothing new.
+  run_test(forgot_request.as_bytes(), b"HTTP/1.1 202 Accepted");
+  sleep(Duration::from_millis(300)).await;
+  assert_eq!(outbox_count(&email), 1);
+  let reset_token = outbox_message
+    .split("reset code is ")
+    .nth(1)
+    .map(|segment| {
+      segment
+        .chars()
+        .take_while(char::is_ascii_hexdigit)
+        .collect::<String>()
+    })
+    .expect("reset token");
+
+  let reset_request = |token: &str| {
+    format!(
+      "POST /auth/password/reset HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"token\":\"{}\",\"password\":\"pass_newreset_{}\"}}",
+      token, suffix
+    )
+  };
+  run_test(
+    reset_request("not-a-token").as_bytes(),
+    b"Invalid or expired reset token",
+  );
+  run_test(
+    reset_request(&reset_token).as_bytes(),
+    b"\"status\":\"password_reset\"",
+  );
+  run_test(
+    reset_request(&reset_token).as_bytes(),
+    b"Invalid or expired reset token",
+  );
+
+  // Existing sessions end and only the new password works.
+  let profile_request = format!(
+    "GET /auth/profile HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    user_token
+  );
+  run_test(profile_request.as_bytes(), b"Invalid token");
+  run_test(user_login.as_bytes(), b"Invalid credentials");
+  let new_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"pass_newreset_{}\"}}",
+    username, suffix
+  );
+  run_test(new_login.as_bytes(), b"\"token\"");
+}
+
+#[tokio::test]
+async fn test_change_own_password() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let admin_token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("selfpw_user_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{uname}\",\"password\":\"pass_selfpw_{suffix}\",\"name\":\"Self Service\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"s{suffix}\"}}",
+    admin_token,
+    uname = username,
+    suffix = suffix
+  );
+  run_test(create_request.as_bytes(), b"\"id\"");
+
+  let user_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"pass_selfpw_{}\"}}",
+    username, suffix
+  );
+  let login_token = || {
+    run_test(user_login.as_bytes(), b"\"token\"")
+      .split("\"token\":\"")
+      .nth(1)
+      .and_then(|segment| segment.split('"').next())
+      .expect("token value")
+      .to_string()
+  };
+  let current_token = login_token();
+  let other_token = login_token();
+
+  let change_request = |current: &str, new: &str| {
+    format!(
+      "POST /auth/password HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"current_password\":\"{}\",\"new_password\":\"{}\"}}",
+      current_token, current, new
+    )
+  };
+  let new_password = format!("pass_changed_{}", suffix);
+  run_test(
+    change_request("wrong", &new_password).as_bytes(),
+    b"Invalid credentials",
+  );
+  let old_password = format!("pass_selfpw_{}", suffix);
+  run_test(
+    change_request(&old_password, &old_password).as_bytes(),
+    b"New password must differ",
+  );
+  for short_password in ["", "short1"] {
+    run_test(
+      change_request(&old_password, short_password).as_bytes(),
+      b"Password must be at least 8 characters",
+    );
+  }
+  run_test(
+    change_request(&old_password, &new_password).as_bytes(),
+    b"\"revoked_sessions\":1",
+  );
+
+  // The calling session survives; the other one and the old password do not.
+  let profile_request =
+    |token: &str| format!("GET /auth/profile HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
+  run_test(profile_request(&current_token).as_bytes(), b"\"payload\"");
+  run_test(profile_request(&other_token).as_bytes(), b"Invalid token");
+  run_test(user_login.as_bytes(), b"Invalid credentials");
+  let new_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, new_password
+  );
+  run_test(new_login.as_bytes(), b"\"token\"");
+}
+
+#[tokio::test]
+async fn test_password_policy() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let admin_token = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  )
+  .split("\"token\":\"")
+  .nth(1)
+  .and_then(|segment| segment.split('"').next())
+  .expect("token value")
+  .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("policy_user_{}", suffix);
+  let create_request = |password: &str| {
+    format!(
+      "POST /users HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\",\"name\":\"Policy User\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"d{}\"}}",
+      admin_token, username, password, suffix
+    )
+  };
+
+  let response = run_test(create_request("").as_bytes(), b"HTTP/1.1 400 Bad Request");
+  assert!(response.contains("\"rule\":\"min_length\""));
+  assert!(response.contains("\"limit\":8"));
+  run_test(
+    create_request(&format!("x{}x", username.to_uppercase())).as_bytes(),
+    b"\"rule\":\"contains_username\"",
+  );
+  run_test(
+    create_request(&format!("doc-d{}", suffix)).as_bytes(),
+    b"\"rule\":\"contains_document_number\"",
+  );
+  run_test(
+    create_request("Password123").as_bytes(),
+    b"\"rule\":\"breached\"",
+  );
+  run_test(
+    create_request(&"a".repeat(129)).as_bytes(),
+    b"\"rule\":\"max_length\"",
+  );
+
+  let user_id = run_test(
+    create_request(&format!("pass_policy_{}", suffix)).as_bytes(),
+    b"\"id\"",
+  )
+  .split("\"id\":")
+  .nth(1)
+  .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+  .expect("user id")
+  .trim()
+  .to_string();
+
+  // On update the stored username applies, or the new one when it changes too.
+  let update_request = |body: String| {
+    format!(
+      "PUT /users/{} HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{}",
+      user_id, admin_token, body
+    )
+  };
+  run_test(
+    update_request(format!("{{\"password\":\"new_{}\"}}", username)).as_bytes(),
+    b"\"rule\":\"contains_username\"",
+  );
+  run_test(
+    update_request(format!(
+      "{{\"username\":\"renamed_{}\",\"password\":\"renamed_{}_pw\"}}",
+      suffix, suffix
+    ))
+    .as_bytes(),
+    b"\"rule\":\"contains_username\"",
+  );
+  run_test(
+    update_request(format!("{{\"password\":\"pass_updated_{}\"}}", suffix)).as_bytes(),
+    b"\"status\":\"success\"",
+  );
+}
+
+#[tokio::test]
+async fn test_impersonation() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let admin_token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+  let admin_id = login_response
+    .split("\"user_id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("admin id")
+    .trim()
+    .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"imp_user_{suffix}\",\"password\":\"pass_imp_{suffix}\",\"name\":\"Impersonated User\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"i{suffix}\"}}",
+    admin_token,
+    suffix = suffix
+  );
+  let person_id = run_test(create_request.as_bytes(), b"\"id\"")
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("user id")
+    .trim()
+    .to_string();
+
+  let missing_reason_request = format!(
+    "POST /users/{}/impersonate HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{}}",
+    person_id, admin_token
+  );
+  run_test(missing_reason_request.as_bytes(), b"Invalid request body");
+  let self_request = format!(
+    "POST /users/{}/impersonate HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"reason\":\"test\"}}",
+    admin_id, admin_token
+  );
+  run_test(self_request.as_bytes(), b"Cannot impersonate yourself");
+
+  let impersonate_request = format!(
+    "POST /users/{}/impersonate HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"reason\":\"Ticket 42\"}}",
+    person_id, admin_token
+  );
+  let impersonate_response = run_test(impersonate_request.as_bytes(), b"HTTP/1.1 201 Created");
+  let token = impersonate_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("impersonation token")
+    .to_string();
+
+  // Downstream services see who is acting.
+  let check_request = format!("POST /check-token HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
+  let check_response = run_test(check_request.as_bytes(), b"\"valid\":true");
+  assert!(check_response.contains(&format!("\"impersonator_id\":{}", admin_id)));
+  assert!(check_response.contains(&format!("\"user_id\":{}", person_id)));
+  assert!(check_response.contains("\"act\":{"));
+
+  // The admin's permissions do not carry over to the impersonation token.
+  let users_request = format!("GET /users HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
+  run_test(users_request.as_bytes(), b"Not allowed while impersonating");
+
+  // Nor can it list or end the person's own sessions.
+  let user_token = run_test(
+    format!(
+      "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"imp_user_{}\",\"password\":\"pass_imp_{}\"}}",
+      suffix, suffix
+    )
+    .as_bytes(),
+    b"\"token\"",
+  )
+  .split("\"token\":\"")
+  .nth(1)
+  .and_then(|segment| segment.split('"').next())
+  .expect("user token")
+  .to_string();
+  let sessions_request =
+    |token: &str| format!("GET /auth/sessions HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
+  let user_session_id = run_test(
+    sessions_request(&user_token).as_bytes(),
+    b"\"current\":true",
+  )
+  .split("\"session_id\":\"")
+  .nth(1)
+  .and_then(|segment| segment.split('"').next())
+  .expect("session id")
+  .to_string();
+  let imp_sessions_response = run_test(
+    sessions_request(&token).as_bytes(),
+    b"HTTP/1.1 403 Forbidden",
+  );
+  assert!(imp_sessions_response.contains("Not allowed while impersonating"));
+  let imp_revoke_request = format!(
+    "DELETE /auth/sessions/{} HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    user_session_id, token
+  );
+  let imp_revoke_response = run_test(imp_revoke_request.as_bytes(), b"HTTP/1.1 403 Forbidden");
+  assert!(imp_revoke_response.contains("Not allowed while impersonating"));
+  run_test(
+    format!(
+      "GET /auth/profile HTTP/1.1\r\ntoken: {}\r\n\r\n",
+      user_token
+    )
+    .as_bytes(),
+    b"\"payload\"",
+  );
+
+  let admin_stop_request = format!(
+    "POST /auth/impersonation/stop HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    admin_token
+  );
+  run_test(admin_stop_request.as_bytes(), b"Not an impersonation token");
+  let stop_request = format!(
+    "POST /auth/impersonation/stop HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    token
+  );
+  run_test(stop_request.as_bytes(), b"\"status\":\"stopped\"");
+  run_test(check_request.as_bytes(), b"Invalid token");
+
+  let audit_request = format!(
+    "GET /users/{}/impersonations HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    person_id, admin_token
+  );
+  let audit_response = run_test(audit_request.as_bytes(), b"\"event\":\"stop\"");
+  assert!(audit_response.contains("\"event\":\"start\""));
+  assert!(audit_response.contains("\"reason\":\"Ticket 42\""));
+  assert!(audit_response.contains(&format!("\"impersonator_id\":{}", admin_id)));
+}
+
+#[tokio::test]
+async fn test_impersonation_stop_recorded_when_token_ends() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let admin_token = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  )
+  .split("\"token\":\"")
+  .nth(1)
+  .and_then(|segment| segment.split('"').next())
+  .expect("token value")
+  .to_string();
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"imp_end_{suffix}\",\"password\":\"pass_end_{suffix}\",\"name\":\"Impersonated User\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"e{suffix}\"}}",
+    admin_token,
+    suffix = suffix
+  );
+  let person_id = run_test(create_request.as_bytes(), b"\"id\"")
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("user id")
+    .trim()
+    .to_string();
+  let impersonate = || {
+    let request = format!(
+      "POST /users/{}/impersonate HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"reason\":\"Ticket 43\"}}",
+      person_id, admin_token
+    );
+    let response = run_test(request.as_bytes(), b"HTTP/1.1 201 Created");
+    let value = |key: &str| {
+      response
+        .split(&format!("\"{}\":\"", key))
+        .nth(1)
+        .and_then(|segment| segment.split('"').next())
+        .expect("string value")
+        .to_string()
+    };
+    (value("token"), value("session_id"))
+  };
+  let audit_request = format!(
+    "GET /users/{}/impersonations HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    person_id, admin_token
+  );
+  let stopped_sessions = || {
+    let response = run_test(audit_request.as_bytes(), b"HTTP/1.1 200 OK");
+    let body = response.split("\r\n\r\n").nth(1).expect("response body");
+    let events: serde_json::Value = serde_json::from_str(body).expect("audit json");
+    events
+      .as_array()
+      .expect("audit events")
+      .iter()
+      .filter(|event| event["event"] == "stop")
+      .map(|event| event["session_id"].as_str().unwrap_or_default().to_string())
+      .collect::<Vec<_>>()
+  };
+
+  // A token that expires is recorded as stopped once it is found expired.
+  let (expired_token, expired_session) = impersonate();
+  backdate_activity(&expired_token).await;
+  let check_request = format!(
+    "POST /check-token HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    expired_token
+  );
+  run_test(check_request.as_bytes(), b"HTTP/1.1 401 Unauthorized");
+  assert_eq!(stopped_sessions(), vec![expired_session.clone()]);
+
+  // So is one revoked along with all of the person's sessions.
+  let (revoked_token, revoked_session) = impersonate();
+  let revoke_request = format!(
+    "DELETE /users/{}/sessions HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    person_id, admin_token
+  );
+  run_test(revoke_request.as_bytes(), b"HTTP/1.1 204 No Content");
+  let check_request = format!(
+    "POST /check-token HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    revoked_token
+  );
+  run_test(check_request.as_bytes(), b"Invalid token");
+  let mut stopped = stopped_sessions();
+  stopped.sort();
+  let mut expected = vec![expired_session, revoked_session];
+  expected.sort();
+  assert_eq!(stopped, expected);
+}
+
+#[tokio::test]
+async fn test_oauth_client_credentials() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
+    b"\"token\"",
+  );
+  let token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_service_request = format!(
+    "POST /services HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"OAuth Service {}\",\"description\":\"OAuth\"}}",
+    token, suffix
+  );
+  let service_response = run_test(create_service_request.as_bytes(), b"\"id\"");
+  let service_id = service_response
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("service id segment")
+    .trim()
+    .to_string();
+
+  let register_request = format!(
+    "POST /services/{}/oauth-clients HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"Reports\",\"scopes\":[\"reports.read\",\"reports.write\"]}}",
+    service_id, token
+  );
+  let register_response = run_test(register_request.as_bytes(), b"\"client_secret\"");
+  let client_id = register_response
+    .split("\"client_id\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("client id value")
+    .to_string();
+  let client_secret = register_response
+    .split("\"client_secret\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("client secret value")
+    .to_string();
 
-// Authentication
+  let list_request = format!(
+    "GET /services/{}/oauth-clients HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    service_id, token
+  );
+  let list_response = run_test(list_request.as_bytes(), client_id.as_bytes());
+  assert!(!list_response.contains(&client_secret));
 
-#[tokio::test]
-async fn test_login_success() {
-  setup_test_server(|| create_test_server()).await;
-  sleep(Duration::from_millis(100)).await;
+  // Credentials in the form body, narrowed to one scope.
+  let token_request = format!(
+    "POST /oauth/token HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\ngrant_type=client_credentials&client_id={}&client_secret={}&scope=reports.read",
+    client_id, client_secret
+  );
+  let token_response = run_test(token_request.as_bytes(), b"\"token_type\":\"Bearer\"");
+  assert!(token_response.contains("\"scope\":\"reports.read\""));
+  let access_token = token_response
+    .split("\"access_token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("access token value")
+    .to_string();
+  let check_request = format!(
+    "POST /check-token HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+    access_token
+  );
+  let check_response = run_test(check_request.as_bytes(), b"\"valid\":true");
+  assert!(check_response.contains(&format!("\"client_id\":\"{}\"", client_id)));
 
+  // HTTP Basic credentials; an omitted scope grants all registered scopes.
+  use base64::Engine;
+  let basic =
+    base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", client_id, client_secret));
+  let basic_request = format!(
+    "POST /oauth/token HTTP/1.1\r\nAuthorization: Basic {}\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\ngrant_type=client_credentials",
+    basic
+  );
   run_test(
-    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
-    b"\"token\"",
+    basic_request.as_bytes(),
+    b"\"scope\":\"reports.read reports.write\"",
   );
-}
-
-#[tokio::test]
-async fn test_login_invalid_password() {
-  setup_test_server(|| create_test_server()).await;
-  sleep(Duration::from_millis(100)).await;
 
+  let scope_request = format!(
+    "POST /oauth/token HTTP/1.1\r\nAuthorization: Basic {}\r\n\r\ngrant_type=client_credentials&scope=users.write",
+    basic
+  );
+  run_test(scope_request.as_bytes(), b"\"error\":\"invalid_scope\"");
+  let grant_request = format!(
+    "POST /oauth/token HTTP/1.1\r\nAuthorization: Basic {}\r\n\r\ngrant_type=password",
+    basic
+  );
   run_test(
-    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"wrong\"}",
-    b"Invalid credentials",
+    grant_request.as_bytes(),
+    b"\"error\":\"unsupported_grant_type\"",
+  );
+
+  let rotate_request = format!(
+    "POST /services/{}/oauth-clients/{}/rotate HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    service_id, client_id, token
   );
+  run_test(rotate_request.as_bytes(), b"\"client_secret\"");
+  run_test(basic_request.as_bytes(), b"\"error\":\"invalid_client\"");
+
+  let delete_request = format!(
+    "DELETE /services/{}/oauth-clients/{} HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    service_id, client_id, token
+  );
+  run_test(delete_request.as_bytes(), b"HTTP/1.1 204 No Content");
+  run_test(delete_request.as_bytes(), b"OAuth client not found");
 }
 
 #[tokio::test]
-async fn test_logout_success() {
+async fn test_oauth_authorization_code_pkce() {
   setup_test_server(|| create_test_server()).await;
   sleep(Duration::from_millis(100)).await;
 
@@ -49,29 +2171,275 @@ async fn test_logout_success() {
     .expect("token value")
     .to_string();
 
-  let logout_request = format!("POST /auth/logout HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
-  run_test(logout_request.as_bytes(), b"\"status\":\"logged_out\"");
-}
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_service_request = format!(
+    "POST /services HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"Partner App {}\",\"description\":\"PKCE\"}}",
+    token, suffix
+  );
+  let service_response = run_test(create_service_request.as_bytes(), b"\"id\"");
+  let service_id = service_response
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("service id segment")
+    .trim()
+    .to_string();
 
-#[tokio::test]
-async fn test_logout_missing_token() {
-  setup_test_server(|| create_test_server()).await;
-  sleep(Duration::from_millis(100)).await;
+  let redirect_request = format!(
+    "POST /services/{}/redirect-uris HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"redirect_uri\":\"https://app.example/callback\"}}",
+    service_id, token
+  );
+  run_test(redirect_request.as_bytes(), b"\"redirect_uri\"");
+  let register_request = format!(
+    "POST /services/{}/oauth-clients HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"Partner App\",\"scopes\":[\"profile\"],\"public\":true}}",
+    service_id, token
+  );
+  let register_response = run_test(register_request.as_bytes(), b"\"public\":true");
+  assert!(!register_response.contains("client_secret"));
+  let client_id = register_response
+    .split("\"client_id\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("client id value")
+    .to_string();
+
+  use base64::Engine;
+  use sha2::{Digest, Sha256};
+  let verifier = format!("verifier-{}-0123456789abcdefghijklmnopqrstuvwxyz", suffix);
+  let challenge =
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+  let redirect_uri = "https%3A%2F%2Fapp.example%2Fcallback";
 
+  let page_request = format!(
+    "GET /oauth/authorize?response_type=code&client_id={}&redirect_uri={}&scope=openid%20profile&nonce=n-123&state=xyz&code_challenge={}&code_challenge_method=S256 HTTP/1.1\r\n\r\n",
+    client_id, redirect_uri, challenge
+  );
+  let page_response = run_test(page_request.as_bytes(), b"<form method=\"post\"");
+  assert!(page_response.contains("Partner App"));
+  let unregistered_request = page_request.replace("app.example", "evil.example");
   run_test(
-    b"POST /auth/logout HTTP/1.1\r\n\r\n",
-    b"Missing token header",
+    unregistered_request.as_bytes(),
+    b"Redirect URI is not registered",
+  );
+
+  let consent_token = page_response
+    .split("name=\"consent_token\" value=\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("consent token value")
+    .to_string();
+
+  // The form is only accepted with the consent token of a page rendered for it.
+  let forged_body = format!(
+    "response_type=code&client_id={}&redirect_uri={}&scope=openid%20profile&nonce=n-123&state=xyz&code_challenge={}&code_challenge_method=S256&username=adm1&password=adm1-hash&decision=allow",
+    client_id, redirect_uri, challenge
+  );
+  let forged_request = format!(
+    "POST /oauth/authorize HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\n{}",
+    forged_body
+  );
+  run_test(forged_request.as_bytes(), b"HTTP/1.1 403 Forbidden");
+  let other_challenge =
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(b"another verifier"));
+  let mismatched_request = format!(
+    "{}&consent_token={}",
+    forged_request.replace(&challenge, &other_challenge),
+    consent_token
+  );
+  run_test(mismatched_request.as_bytes(), b"HTTP/1.1 403 Forbidden");
+
+  let authorize_body = format!(
+    "response_type=code&client_id={}&redirect_uri={}&scope=openid%20profile&nonce=n-123&state=xyz&code_challenge={}&code_challenge_method=S256&consent_token={}",
+    client_id, redirect_uri, challenge, consent_token
+  );
+  let deny_request = format!(
+    "POST /oauth/authorize HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\n{}&decision=deny",
+    authorize_body
+  );
+  run_test(deny_request.as_bytes(), b"error=access_denied");
+  let wrong_password_request = format!(
+    "POST /oauth/authorize HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\n{}&username=adm1&password=wrong&decision=allow",
+    authorize_body
+  );
+  run_test(wrong_password_request.as_bytes(), b"Invalid credentials");
+
+  let allow_request = format!(
+    "POST /oauth/authorize HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\n{}&username=adm1&password=adm1-hash&decision=allow",
+    authorize_body
+  );
+  let extract_code = |response: &str| {
+    response
+      .split("callback?code=")
+      .nth(1)
+      .and_then(|segment| segment.split(['&', '"']).next())
+      .expect("authorization code")
+      .to_string()
+  };
+  let allow_response = run_test(allow_request.as_bytes(), b"state=xyz");
+  let code = extract_code(&allow_response);
+
+  let exchange_request = format!(
+    "POST /oauth/token HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\ngrant_type=authorization_code&client_id={}&code={}&redirect_uri={}&code_verifier={}",
+    client_id, code, redirect_uri, verifier
+  );
+  let exchange_response = run_test(exchange_request.as_bytes(), b"\"refresh_token\"");
+  let id_token = exchange_response
+    .split("\"id_token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("id token value")
+    .to_string();
+  let claims = base64::engine::general_purpose::URL_SAFE_NO_PAD
+    .decode(id_token.split('.').nth(1).expect("id token claims"))
+    .expect("base64url claims");
+  let claims = String::from_utf8(claims).expect("utf-8 claims");
+  assert!(claims.contains("\"nonce\":\"n-123\""));
+  assert!(claims.contains(&format!("\"aud\":\"{}\"", client_id)));
+  let access_token = exchange_response
+    .split("\"access_token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("access token value")
+    .to_string();
+  let profile_request = format!(
+    "GET /auth/profile HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+    access_token
+  );
+  run_test(profile_request.as_bytes(), b"\"scope\":\"openid profile\"");
+
+  // A replayed code is refused and takes the tokens issued for it along.
+  run_test(
+    exchange_request.as_bytes(),
+    b"Authorization code reuse detected",
+  );
+  run_test(profile_request.as_bytes(), b"Invalid token");
+
+  let second_code = extract_code(&run_test(allow_request.as_bytes(), b"state=xyz"));
+  let wrong_verifier_request = format!(
+    "POST /oauth/token HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\ngrant_type=authorization_code&client_id={}&code={}&redirect_uri={}&code_verifier={}",
+    client_id,
+    second_code,
+    redirect_uri,
+    verifier.replace("verifier", "attacker")
+  );
+  run_test(
+    wrong_verifier_request.as_bytes(),
+    b"PKCE verification failed",
+  );
+
+  let client_credentials_request = format!(
+    "POST /oauth/token HTTP/1.1\r\n\r\ngrant_type=client_credentials&client_id={}",
+    client_id
+  );
+  run_test(
+    client_credentials_request.as_bytes(),
+    b"\"error\":\"unauthorized_client\"",
+  );
+
+  // The refresh token works on /oauth/token, and only for the client it was issued to.
+  let third_code = extract_code(&run_test(allow_request.as_bytes(), b"state=xyz"));
+  let third_exchange = exchange_request.replace(&code, &third_code);
+  let third_response = run_test(third_exchange.as_bytes(), b"\"refresh_token\"");
+  let refresh_token = third_response
+    .split("\"refresh_token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("refresh token value")
+    .to_string();
+  let client_access_token = third_response
+    .split("\"access_token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("access token value")
+    .to_string();
+
+  // A code-exchanged token acts for the person only within its scope.
+  let users_request = format!(
+    "GET /users HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+    client_access_token
+  );
+  run_test(users_request.as_bytes(), b"HTTP/1.1 403 Forbidden");
+  let password_request = format!(
+    "POST /auth/password HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\n\r\n{{\"current_password\":\"adm1-hash\",\"new_password\":\"Another-pass-{}\"}}",
+    client_access_token, suffix
+  );
+  run_test(
+    password_request.as_bytes(),
+    b"Not allowed with a client token",
+  );
+  // Signing in on the consent page never counts as a recent password check.
+  assert!(has_verification(&token).await);
+  assert!(!has_verification(&client_access_token).await);
+  let delete_request = format!(
+    "DELETE /users/1 HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+    client_access_token
+  );
+  run_test(delete_request.as_bytes(), b"HTTP/1.1 403 Forbidden");
+  let other_register_request = register_request.replace("Partner App", "Other App");
+  let other_client_id = run_test(other_register_request.as_bytes(), b"\"public\":true")
+    .split("\"client_id\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("other client id value")
+    .to_string();
+  let refresh_request = |client: &str, refresh_token: &str| {
+    format!(
+      "POST /oauth/token HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\ngrant_type=refresh_token&client_id={}&refresh_token={}",
+      client, refresh_token
+    )
+  };
+  run_test(
+    refresh_request(&other_client_id, &refresh_token).as_bytes(),
+    b"\"error\":\"invalid_grant\"",
+  );
+  let auth_refresh_request = format!(
+    "POST /auth/refresh HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"refresh_token\":\"{}\"}}",
+    refresh_token
+  );
+  run_test(auth_refresh_request.as_bytes(), b"Invalid refresh token");
+  run_test(
+    format!(
+      "{}&scope=openid%20email",
+      refresh_request(&client_id, &refresh_token)
+    )
+    .as_bytes(),
+    b"\"error\":\"invalid_scope\"",
+  );
+  let refreshed_response = run_test(
+    refresh_request(&client_id, &refresh_token).as_bytes(),
+    b"\"scope\":\"openid profile\"",
+  );
+  assert!(refreshed_response.contains("\"access_token\""));
+  let refreshed_access_token = refreshed_response
+    .split("\"access_token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("refreshed access token value")
+    .to_string();
+  assert!(!has_verification(&refreshed_access_token).await);
+  assert!(!refreshed_response.contains(&refresh_token));
+  run_test(
+    refresh_request(&client_id, &refresh_token).as_bytes(),
+    b"Refresh token reuse detected",
   );
 }
 
 #[tokio::test]
-async fn test_profile_success() {
+async fn test_oidc_id_token_and_key_rotation() {
   setup_test_server(|| create_test_server()).await;
   sleep(Duration::from_millis(100)).await;
 
+  run_test(
+    b"GET /.well-known/openid-configuration HTTP/1.1\r\n\r\n",
+    b"\"jwks_uri\"",
+  );
+
   let login_response = run_test(
     b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
-    b"\"token\"",
+    b"\"id_token\"",
   );
   let token = login_response
     .split("\"token\":\"")
@@ -79,50 +2447,313 @@ async fn test_profile_success() {
     .and_then(|segment| segment.split('"').next())
     .expect("token value")
     .to_string();
+  let id_token = login_response
+    .split("\"id_token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("id token value")
+    .to_string();
 
-  let profile_request = format!("GET /auth/profile HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
-  run_test(profile_request.as_bytes(), b"\"payload\"");
+  // The ID token verifies against the published key with the same kid.
+  use base64::Engine;
+  use rsa::pkcs1v15::{Signature, VerifyingKey};
+  use rsa::signature::Verifier;
+  let decode = |segment: &str| {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+      .decode(segment)
+      .expect("base64url segment")
+  };
+  let parts: Vec<&str> = id_token.split('.').collect();
+  assert_eq!(parts.len(), 3);
+  let header: serde_json::Value = serde_json::from_slice(&decode(parts[0])).unwrap();
+  let claims: serde_json::Value = serde_json::from_slice(&decode(parts[1])).unwrap();
+  assert_eq!(header["alg"], "RS256");
+  assert_eq!(claims["preferred_username"], "adm1");
+  let kid = header["kid"].as_str().expect("kid").to_string();
+
+  let jwks_response = run_test(b"GET /.well-known/jwks.json HTTP/1.1\r\n\r\n", b"\"keys\"");
+  let jwks: serde_json::Value =
+    serde_json::from_str(jwks_response.split("\r\n\r\n").nth(1).expect("jwks body")).unwrap();
+  let jwk = jwks["keys"]
+    .as_array()
+    .unwrap()
+    .iter()
+    .find(|key| key["kid"] == kid.as_str())
+    .expect("signing key in jwks");
+  let public_key = rsa::RsaPublicKey::new(
+    rsa::BigUint::from_bytes_be(&decode(jwk["n"].as_str().unwrap())),
+    rsa::BigUint::from_bytes_be(&decode(jwk["e"].as_str().unwrap())),
+  )
+  .unwrap();
+  let signature = Signature::try_from(decode(parts[2]).as_slice()).unwrap();
+  VerifyingKey::<sha2::Sha256>::new(public_key)
+    .verify(format!("{}.{}", parts[0], parts[1]).as_bytes(), &signature)
+    .expect("valid ID token signature");
+
+  let userinfo_request = format!("GET /userinfo HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
+  run_test(
+    userinfo_request.as_bytes(),
+    b"\"preferred_username\":\"adm1\"",
+  );
+
+  // After a rotation new ID tokens use the new key and the old one is still published.
+  let rotate_request = format!(
+    "POST /oidc/keys/rotate HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    token
+  );
+  let rotate_response = run_test(rotate_request.as_bytes(), b"\"kid\"");
+  let new_kid = rotate_response
+    .split("\"kid\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("new kid")
+    .to_string();
+  assert_ne!(new_kid, kid);
+  let jwks_response = run_test(b"GET /.well-known/jwks.json HTTP/1.1\r\n\r\n", b"\"keys\"");
+  assert!(jwks_response.contains(&kid));
+  assert!(jwks_response.contains(&new_kid));
+
+  // The private key is stored encrypted, not as a readable PKCS#1 DER.
+  use rsa::pkcs1::DecodeRsaPrivateKey;
+  let _ = dotenvy::dotenv();
+  let url = std::env::var("DATABASE_URL").expect("DATABASE_URL");
+  let pool = sqlx::PgPool::connect(&url)
+    .await
+    .expect("database connection");
+  let (private_key, nonce) = sqlx::query_as::<_, (Vec<u8>, Option<Vec<u8>>)>(
+    "SELECT private_key, private_key_nonce FROM auth.signing_keys WHERE kid = $1",
+  )
+  .bind(&new_kid)
+  .fetch_one(&pool)
+  .await
+  .expect("stored signing key");
+  assert_eq!(nonce.map(|nonce| nonce.len()), Some(12));
+  assert!(rsa::RsaPrivateKey::from_pkcs1_der(&private_key).is_err());
 }
 
 #[tokio::test]
-async fn test_profile_missing_token() {
+async fn test_userinfo_invalid_token() {
   setup_test_server(|| create_test_server()).await;
   sleep(Duration::from_millis(100)).await;
 
   run_test(
-    b"GET /auth/profile HTTP/1.1\r\n\r\n",
-    b"Missing token header",
+    b"GET /userinfo HTTP/1.1\r\nAuthorization: Bearer invalid\r\n\r\n",
+    b"Invalid token",
   );
 }
 
 #[tokio::test]
-async fn test_check_token_success() {
+async fn test_oauth_introspect_and_revoke() {
   setup_test_server(|| create_test_server()).await;
   sleep(Duration::from_millis(100)).await;
 
-  let login_response = run_test(
-    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}",
-    b"\"token\"",
+  let value_of = |response: &str, key: &str| {
+    response
+      .split(&format!("\"{}\":\"", key))
+      .nth(1)
+      .and_then(|segment| segment.split('"').next())
+      .expect("string value")
+      .to_string()
+  };
+  let login = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let token = value_of(&run_test(login, b"\"token\""), "token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_service_request = format!(
+    "POST /services HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"Gateway {}\",\"description\":\"Gateway\"}}",
+    token, suffix
   );
-  let token = login_response
-    .split("\"token\":\"")
+  let service_response = run_test(create_service_request.as_bytes(), b"\"id\"");
+  let service_id = service_response
+    .split("\"id\":")
     .nth(1)
-    .and_then(|segment| segment.split('"').next())
-    .expect("token value")
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("service id segment")
+    .trim()
+    .to_string();
+  let key_request = format!(
+    "POST /services/{}/api-keys HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    service_id, token
+  );
+  let api_key = value_of(&run_test(key_request.as_bytes(), b"\"api_key\""), "api_key");
+  let register_request = format!(
+    "POST /services/{}/oauth-clients HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"Gateway\",\"scopes\":[\"reports.read\"]}}",
+    service_id, token
+  );
+  let register_response = run_test(register_request.as_bytes(), b"\"client_secret\"");
+  let client_id = value_of(&register_response, "client_id");
+  let client_secret = value_of(&register_response, "client_secret");
+
+  // The caller must authenticate.
+  let anonymous_request = format!(
+    "POST /oauth/introspect HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\ntoken={}",
+    token
+  );
+  run_test(
+    anonymous_request.as_bytes(),
+    b"\"error\":\"invalid_client\"",
+  );
+
+  // A user token of a login for the service, introspected with its API key.
+  let service_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"adm1\",\"password\":\"adm1-hash\",\"service_id\":{}}}",
+    service_id
+  );
+  let session_response = run_test(service_login.as_bytes(), b"\"refresh_token\"");
+  let session_token = value_of(&session_response, "token");
+  let refresh_token = value_of(&session_response, "refresh_token");
+  let introspect = |token: &str| {
+    format!(
+      "POST /oauth/introspect HTTP/1.1\r\nx-api-key: {}\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\ntoken={}",
+      api_key, token
+    )
+  };
+  let introspect_response = run_test(introspect(&session_token).as_bytes(), b"\"active\":true");
+  assert!(introspect_response.contains("\"username\":\"adm1\""));
+  assert!(introspect_response.contains("\"sub\":\""));
+  assert!(introspect_response.contains("\"exp\":"));
+  assert!(introspect_response.contains("\"iat\":"));
+  run_test(introspect("unknown").as_bytes(), b"\"active\":false");
+  // Logins without a service are only disclosed to services the person has a role in.
+  run_test(introspect(&token).as_bytes(), b"\"active\":false");
+  let role_request = format!(
+    "POST /roles HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"gateway_role_{}\"}}",
+    token, suffix
+  );
+  let role_id = run_test(role_request.as_bytes(), b"\"id\"")
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("role id segment")
+    .trim()
+    .to_string();
+  let assign_service_request = format!(
+    "POST /service-roles HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":{},\"role_id\":{}}}",
+    token, service_id, role_id
+  );
+  run_test(assign_service_request.as_bytes(), b"\"status\":\"success\"");
+  let user_id = introspect_response
+    .split("\"user_id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("user id segment")
+    .trim()
     .to_string();
+  let assign_person_request = format!(
+    "POST /person-service-roles HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":{},\"service_id\":{},\"role_id\":{}}}",
+    token, user_id, service_id, role_id
+  );
+  run_test(assign_person_request.as_bytes(), b"\"status\":\"success\"");
+  run_test(introspect(&token).as_bytes(), b"\"active\":true");
 
-  let check_request = format!("POST /check-token HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
-  run_test(check_request.as_bytes(), b"\"valid\":true");
+  // A client_credentials token, introspected by the client itself.
+  let token_request = format!(
+    "POST /oauth/token HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\ngrant_type=client_credentials&client_id={}&client_secret={}",
+    client_id, client_secret
+  );
+  let access_token = value_of(
+    &run_test(token_request.as_bytes(), b"\"access_token\""),
+    "access_token",
+  );
+  let client_request = format!(
+    "POST /oauth/introspect HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\nclient_id={}&client_secret={}&token={}",
+    client_id, client_secret, access_token
+  );
+  let client_response = run_test(client_request.as_bytes(), b"\"active\":true");
+  assert!(client_response.contains(&format!("\"sub\":\"{}\"", client_id)));
+  assert!(client_response.contains("\"scope\":\"reports.read\""));
+
+  // Another service can neither see nor revoke them.
+  let other_service_request = create_service_request.replace("Gateway", "Other Gateway");
+  let other_service_id = run_test(other_service_request.as_bytes(), b"\"id\"")
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("other service id segment")
+    .trim()
+    .to_string();
+  let other_key_request = format!(
+    "POST /services/{}/api-keys HTTP/1.1\r\ntoken: {}\r\n\r\n",
+    other_service_id, token
+  );
+  let other_api_key = value_of(
+    &run_test(other_key_request.as_bytes(), b"\"api_key\""),
+    "api_key",
+  );
+  let other_introspect = introspect(&session_token).replace(&api_key, &other_api_key);
+  run_test(other_introspect.as_bytes(), b"\"active\":false");
+
+  // Revoking the access token leaves the refresh token usable.
+  let revoke = |token: &str| {
+    format!(
+      "POST /oauth/revoke HTTP/1.1\r\nx-api-key: {}\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\ntoken={}&token_type_hint=access_token",
+      api_key, token
+    )
+  };
+  for foreign in [&session_token, &refresh_token, &access_token] {
+    let foreign_revoke = revoke(foreign).replace(&api_key, &other_api_key);
+    run_test(foreign_revoke.as_bytes(), b"HTTP/1.1 200 OK");
+  }
+  run_test(introspect(&session_token).as_bytes(), b"\"active\":true");
+  run_test(client_request.as_bytes(), b"\"active\":true");
+  run_test(revoke(&session_token).as_bytes(), b"HTTP/1.1 200 OK");
+  run_test(introspect(&session_token).as_bytes(), b"\"active\":false");
+  run_test(revoke("unknown").as_bytes(), b"HTTP/1.1 200 OK");
+  let refresh_request = format!(
+    "POST /auth/refresh HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"refresh_token\":\"{}\"}}",
+    refresh_token
+  );
+  let refreshed_token = value_of(&run_test(refresh_request.as_bytes(), b"\"token\""), "token");
+  // `iat` is the issue time of the refreshed token, not the start of its session.
+  let _ = dotenvy::dotenv();
+  let url = std::env::var("DATABASE_URL").expect("DATABASE_URL");
+  let pool = sqlx::PgPool::connect(&url)
+    .await
+    .expect("database connection");
+  sqlx::query(
+    "UPDATE auth.tokens_cache SET created_at = created_at - 600
+     WHERE token_hash = encode(sha256(convert_to($1, 'UTF8')), 'hex')",
+  )
+  .bind(&refreshed_token)
+  .execute(&pool)
+  .await
+  .expect("backdate session start");
+  let refreshed_response = run_test(introspect(&refreshed_token).as_bytes(), b"\"active\":true");
+  let iat: i64 = refreshed_response
+    .split("\"iat\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .and_then(|value| value.trim().parse().ok())
+    .expect("iat value");
+  let now = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_secs() as i64;
+  assert!(now - iat < 60);
+
+  // Revoking a refresh token ends its whole session.
+  let session_response = run_test(service_login.as_bytes(), b"\"refresh_token\"");
+  let second_token = value_of(&session_response, "token");
+  let second_refresh = value_of(&session_response, "refresh_token");
+  run_test(revoke(&second_refresh).as_bytes(), b"HTTP/1.1 200 OK");
+  run_test(introspect(&second_token).as_bytes(), b"\"active\":false");
 }
 
 #[tokio::test]
-async fn test_check_token_invalid_token() {
+async fn test_oauth_token_invalid_request() {
   setup_test_server(|| create_test_server()).await;
   sleep(Duration::from_millis(100)).await;
 
   run_test(
-    b"POST /check-token HTTP/1.1\r\ntoken: invalid\r\n\r\n",
-    b"Invalid token",
+    b"POST /oauth/token HTTP/1.1\r\n\r\nclient_id=x&client_secret=y",
+    b"\"error\":\"invalid_request\"",
+  );
+  run_test(
+    b"POST /oauth/token HTTP/1.1\r\n\r\ngrant_type=client_credentials&client_id=unknown&client_secret=wrong",
+    b"\"error\":\"invalid_client\"",
   );
 }
 
@@ -156,6 +2787,36 @@ async fn test_users_list_missing_token() {
   run_test(b"GET /users HTTP/1.1\r\n\r\n", b"Missing token header");
 }
 
+#[tokio::test]
+async fn test_user_create_forbidden_without_permission() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr1\",\"password\":\"usr1-hash\"}",
+    b"\"token\"",
+  );
+  let token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\ntoken: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"nope\",\"password\":\"nope\",\"name\":\"Nope\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"nope\"}}",
+    token
+  );
+  let response = run_test(
+    create_request.as_bytes(),
+    b"Missing permission: users.write",
+  );
+  assert!(response.starts_with("HTTP/1.1 403"));
+
+  let list_request = format!("GET /users HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
+  run_test(list_request.as_bytes(), b"Missing permission: users.read");
+}
+
 #[tokio::test]
 async fn test_user_create_success() {
   setup_test_server(|| create_test_server()).await;
@@ -178,7 +2839,7 @@ async fn test_user_create_success() {
     .as_nanos();
   let username = format!("user_{}", suffix);
   let password = format!("pass_{}", suffix);
-  let document = format!("{}", suffix);
+  let document = format!("c{}", suffix);
   let create_body = format!(
     "{{\"username\":\"{uname}\",\"password_hash\":\"{pwd}\",\"name\":\"{name}\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"{doc}\"}}",
     uname = username,
@@ -849,6 +3510,29 @@ async fn test_role_create_success() {
   run_test(create_request.as_bytes(), expected.as_bytes());
 }
 
+#[tokio::test]
+async fn test_role_delete_forbidden_without_permission() {
+  setup_test_server(|| create_test_server()).await;
+  sleep(Duration::from_millis(100)).await;
+
+  let login_response = run_test(
+    b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr2\",\"password\":\"usr2-hash\"}",
+    b"\"token\"",
+  );
+  let token = login_response
+    .split("\"token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let delete_request = format!("DELETE /roles/1 HTTP/1.1\r\ntoken: {}\r\n\r\n", token);
+  run_test(
+    delete_request.as_bytes(),
+    b"Missing permission: roles.write",
+  );
+}
+
 #[tokio::test]
 async fn test_role_create_invalid_body() {
   setup_test_server(|| create_test_server()).await;